@@ -1,13 +1,36 @@
 #![feature(array_chunks)]
 #![feature(iter_intersperse)]
 
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::fs;
+use std::io::Write;
+use std::net::TcpListener;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::process::ExitCode;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use clap::{Args, Parser, Subcommand, ValueEnum};
+use fpt::debug_interface::expr::Condition;
 use fpt::debug_interface::{DebugCmd, DebugEvent};
+use fpt::debugger::symbols::SymbolTable;
+use fpt::disasm::flow::RomDisassembly;
+use fpt::disasm::{self, Pastraiser, Rgbds, Syntax};
+use fpt::gdb::GdbSession;
+use fpt::history::History;
+use fpt::memory::Buttons;
+use fpt::model::Model;
+use fpt::movie::{frame_hash, Boot, Movie};
+use fpt::ppu::tile::write_pgm_screenshot;
+use fpt::trace::{self, TraceFormat, Tracer};
 use fpt::Gameboy;
 use rustyline::error::ReadlineError;
 use rustyline::{DefaultEditor, Result};
+use script::Script;
+
+mod script;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -20,40 +43,157 @@ struct Cli {
 
 #[derive(Clone, Debug, Args)]
 struct GameboyConfig {
-    /// Apply known CPU and hardware register values of a well-known bootrom when it
+    /// Apply known CPU and hardware register values of the bootrom of this model when it
     /// hands off the execution to the cartridge's code. This skips emulating a bootrom.
+    #[arg(short, long, conflicts_with_all = ["model", "bootrom"])]
+    fake_bootrom: Option<ModelArg>,
+    /// Emulate this model instead of the one the cartridge header asks for
+    #[arg(short, long)]
+    model: Option<ModelArg>,
+    /// Run this bootrom of the model, 256 bytes or 2304 for a CGB, instead of the DMG one
     #[arg(short, long)]
-    fake_bootrom: Option<BootromToFake>,
+    bootrom: Option<String>,
 }
 
 impl GameboyConfig {
-    /// Build a `Gameboy` following this configuration. Consumes self.
-    pub fn build_gameboy(self) -> Gameboy {
+    /// Build a `Gameboy` with `rom` loaded following this configuration, or booting as
+    /// `movie` was recorded
+    pub fn build_gameboy(&self, rom: &[u8], movie: Option<&Movie>) -> Result<Gameboy> {
         let mut gameboy = Gameboy::new();
-        if let Some(BootromToFake::DMG0) = self.fake_bootrom {
-            gameboy.boot_fake();
-        } else {
-            gameboy.boot_real();
+        gameboy.load_rom(rom);
+        let model = movie.map_or(self.model(), |movie| movie.model);
+        if let Some(model) = model {
+            gameboy.set_model(model);
+        }
+        if let Some(path) = &self.bootrom {
+            gameboy
+                .set_bootrom(&fs::read(path)?)
+                .map_err(std::io::Error::other)?;
+        }
+        movie
+            .map_or(self.boot(), |movie| movie.boot)
+            .apply(&mut gameboy);
+        Ok(gameboy)
+    }
+
+    fn model(&self) -> Option<Model> {
+        self.fake_bootrom.or(self.model).map(Model::from)
+    }
+
+    fn boot(&self) -> Boot {
+        match self.fake_bootrom {
+            Some(_) => Boot::Fake,
+            None => Boot::Real,
         }
-        gameboy
     }
 }
 
-#[derive(ValueEnum, Debug, Clone, PartialEq)]
-enum BootromToFake {
-    DMG0,
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum ModelArg {
+    /// The first Game Boys, with an early bootrom
+    Dmg0,
+    /// Later Game Boys
+    DmgAbc,
+    /// Game Boy Pocket
+    Mgb,
+    /// Super Game Boy
+    Sgb,
+    /// Super Game Boy 2
+    Sgb2,
+    /// Game Boy Color
+    Cgb,
+}
+
+impl From<ModelArg> for Model {
+    fn from(model: ModelArg) -> Self {
+        match model {
+            ModelArg::Dmg0 => Model::Dmg0,
+            ModelArg::DmgAbc => Model::DmgAbc,
+            ModelArg::Mgb => Model::Mgb,
+            ModelArg::Sgb => Model::Sgb,
+            ModelArg::Sgb2 => Model::Sgb2,
+            ModelArg::Cgb => Model::Cgb,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+enum AsmSyntax {
+    /// RGBDS assembler syntax
+    #[default]
+    Rgbds,
+    /// Syntax of the pastraiser.com opcode table
+    Pastraiser,
+}
+
+impl AsmSyntax {
+    fn syntax(self) -> &'static dyn Syntax {
+        match self {
+            AsmSyntax::Rgbds => &Rgbds,
+            AsmSyntax::Pastraiser => &Pastraiser,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+enum TraceFormatArg {
+    /// Gameboy Doctor lines, to diff against its reference logs
+    #[default]
+    Doctor,
+    /// Gameboy Doctor lines plus IO registers, ROM bank and clock cycles
+    Full,
+}
+
+impl From<TraceFormatArg> for TraceFormat {
+    fn from(format: TraceFormatArg) -> Self {
+        match format {
+            TraceFormatArg::Doctor => TraceFormat::Doctor,
+            TraceFormatArg::Full => TraceFormat::Full,
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     Debug(Run),
+    Doctor(Doctor),
     Dump(Dump),
+    Gdbserver(Gdbserver),
     Run(Run),
 }
 
+/// Runs a ROM against a Gameboy Doctor log, stopping where the CPU state first differs
+#[derive(Debug, Args)]
+struct Doctor {
+    rom: String,
+    /// Log of a known-good emulator running the same ROM
+    reference_log: String,
+    /// Previous states to show when a line differs
+    #[arg(short, long, default_value_t = 10)]
+    context: usize,
+    /// Assembly syntax of the disassembly around PC
+    #[arg(short, long, value_enum, default_value_t)]
+    syntax: AsmSyntax,
+}
+
 #[derive(Debug, Args)]
 struct Dump {
     rom: String,
+    /// Write the RGBDS source to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<String>,
+    /// Name labels after this RGBDS symbol file
+    #[arg(short, long)]
+    symbols: Option<String>,
+}
+
+/// Lets gdb debug a ROM over the remote serial protocol
+#[derive(Debug, Args)]
+struct Gdbserver {
+    rom: String,
+    /// Local TCP port to wait for gdb on, as in `target remote :1234`
+    #[arg(short, long, default_value_t = 1234)]
+    port: u16,
 }
 
 #[derive(Debug, Args)]
@@ -61,12 +201,139 @@ struct Run {
     rom: String,
     #[arg(short, long)]
     debug: Option<bool>,
+    /// Assembly syntax of the instructions printed with --debug
+    #[arg(short, long, value_enum, default_value_t)]
+    syntax: AsmSyntax,
+    /// Log the registers before each instruction to this file
+    #[arg(short, long)]
+    trace: Option<String>,
+    #[arg(long, value_enum, default_value_t)]
+    trace_format: TraceFormatArg,
+    /// Start tracing once this debugger expression holds, like `pc == 0x150`
+    #[arg(long, value_parser = Condition::parse)]
+    trace_start: Option<Condition>,
+    /// Stop tracing, and running, once this debugger expression holds
+    #[arg(long, value_parser = Condition::parse)]
+    trace_stop: Option<Condition>,
+    /// Keep a snapshot of each of the last this many frames, for reverse step and reverse
+    /// continue in the debugger. They take a few hundred KB each.
+    #[arg(long)]
+    history: Option<usize>,
+    /// Lua script driving the game, see the `script` module for what it can call
+    #[arg(long)]
+    script: Option<String>,
+    /// Game Genie or GameShark code to apply along with the saved ones, can be repeated
+    #[arg(long)]
+    cheat: Vec<String>,
+    /// Record the buttons pressed at every frame to this movie file, written once the
+    /// run stops after --frames
+    #[arg(long, conflicts_with = "play", requires = "frames")]
+    record: Option<String>,
+    /// Play back a movie file, booting as it was recorded, and stop at its end or where
+    /// the picture first differs
+    #[arg(long)]
+    play: Option<String>,
+    /// Stop once this many frames ran since power on
+    #[arg(long)]
+    frames: Option<u32>,
+    /// Buttons to press at given frames, like `120:start,180:a` or `200-260:right+b`.
+    /// Without a range of frames, buttons are held for 6 frames.
+    #[arg(long, value_parser = InputScript::parse)]
+    input: Option<InputScript>,
+    /// Write the last frame to this file when the run stops, as a PGM image
+    #[arg(long)]
+    screenshot: Option<String>,
+    /// Print the hash of the last frame when the run stops
+    #[arg(long)]
+    frame_hash: bool,
+    /// Exit with an error unless the hash of the last frame is this one, as printed by
+    /// --frame-hash
+    #[arg(long, value_parser = parse_hash)]
+    expect_hash: Option<u64>,
+}
+
+/// Buttons held over ranges of frames
+#[derive(Clone, Debug)]
+struct InputScript(Vec<(RangeInclusive<u32>, String)>);
+
+impl InputScript {
+    /// Frames a button is held for when not given a range, as games tend to check the
+    /// joypad once per frame and some only see a press once it's released
+    const HOLD_FRAMES: u32 = 6;
+
+    fn parse(text: &str) -> std::result::Result<Self, String> {
+        let mut presses = Vec::new();
+        for press in text.split(',') {
+            let (frames, names) = press
+                .split_once(':')
+                .ok_or_else(|| format!("expected FRAME:BUTTONS, got {press}"))?;
+            let frame = |f: &str| {
+                f.trim()
+                    .parse::<u32>()
+                    .map_err(|_| format!("invalid frame {f}"))
+            };
+            let frames = match frames.split_once('-') {
+                Some((start, end)) => frame(start)?..=frame(end)?,
+                None => {
+                    let start = frame(frames)?;
+                    let end = start
+                        .checked_add(Self::HOLD_FRAMES - 1)
+                        .ok_or_else(|| format!("frame {start} is too large"))?;
+                    start..=end
+                }
+            };
+            if frames.is_empty() {
+                return Err(format!(
+                    "frames {}-{} end before they start",
+                    frames.start(),
+                    frames.end()
+                ));
+            }
+            for name in names.split('+').map(str::trim) {
+                Buttons::default().set(name, true)?;
+                presses.push((frames.clone(), name.to_string()));
+            }
+        }
+        Ok(InputScript(presses))
+    }
+
+    fn buttons(&self, frame: u32) -> Buttons {
+        let mut buttons = Buttons::default();
+        for (_, name) in self.0.iter().filter(|(frames, _)| frames.contains(&frame)) {
+            let _ = buttons.set(name, true);
+        }
+        buttons
+    }
+}
+
+fn parse_hash(text: &str) -> std::result::Result<u64, String> {
+    u64::from_str_radix(text.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("invalid hash {text}"))
+}
+
+/// Loads the cheats saved for the game, then adds the ones given on the command line
+fn load_cheats(gameboy: &mut Gameboy, codes: &[String]) {
+    if gameboy.bus().cheats_path().exists() {
+        match gameboy.bus_mut().load_cheats() {
+            Ok(count) => println!("Loaded {count} cheats"),
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+    for code in codes {
+        if let Err(e) = gameboy.bus().memory_mut().cheats.add(code, "") {
+            println!("Error: {e}");
+        }
+    }
 }
 
 fn debug(args: Run) -> Result<()> {
     let mut gameboy = Gameboy::new();
     let rom = fs::read(args.rom).unwrap();
     gameboy.load_rom(&rom);
+    load_cheats(&mut gameboy, &args.cheat);
+    if let Some(frames) = args.history {
+        gameboy.set_history(Some(History::frames(frames)));
+    }
 
     let mut rl = DefaultEditor::new()?;
     if rl.load_history(".fpt_debug_history").is_err() {
@@ -76,27 +343,44 @@ fn debug(args: Run) -> Result<()> {
         let readline = rl.readline(">> ");
         match readline {
             Ok(line) => {
-                rl.add_history_entry(&line)?;
-
-                let debug_cmd = DebugCmd::from_string(&line);
-                if debug_cmd.is_none() {
-                    println!("Error: cannot parse debug command");
+                if line.trim().is_empty() {
                     continue;
                 }
-                let debug_event = gameboy.debug_cmd(&debug_cmd.unwrap());
+                rl.add_history_entry(&line)?;
+
+                let debug_cmd = match DebugCmd::from_string(&line) {
+                    Ok(debug_cmd) => debug_cmd,
+                    Err(e) => {
+                        println!("Error: {e}");
+                        continue;
+                    }
+                };
+                let debug_event = gameboy.debug_cmd(&debug_cmd);
                 if debug_event.is_none() {
                     continue;
                 }
                 let debug_event = debug_event.unwrap();
                 print!("{}", debug_event);
 
-                if debug_event == DebugEvent::Continue {
+                if matches!(
+                    debug_event,
+                    DebugEvent::Continue
+                        | DebugEvent::Step
+                        | DebugEvent::Next
+                        | DebugEvent::Finish
+                        | DebugEvent::Until(_)
+                        | DebugEvent::ReverseStep
+                        | DebugEvent::ReverseContinue
+                ) {
                     loop {
                         if gameboy.paused() {
                             break;
                         }
                         gameboy.step();
                     }
+                    for event in gameboy.get_debug_events().drain(..) {
+                        print!("{}", event);
+                    }
                 }
             }
             Err(ReadlineError::Interrupted) => {
@@ -117,50 +401,283 @@ fn debug(args: Run) -> Result<()> {
     Ok(())
 }
 
+fn doctor(args: Doctor) -> Result<()> {
+    let rom = fs::read(&args.rom)?;
+    let reference = fs::read_to_string(&args.reference_log)?;
+    if !check_log(&args, &rom, &reference, &mut std::io::stdout())? {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Runs `rom` against the lines of `reference`, writing to `out` where they first differ
+/// or that they all match. Returns whether they did.
+fn check_log(args: &Doctor, rom: &[u8], reference: &str, out: &mut impl Write) -> Result<bool> {
+    let mut gameboy = Gameboy::new();
+    gameboy.load_rom(rom);
+    gameboy.boot_fake();
+    // Reference logs start from the registers the DMG boot ROM hands off with, and
+    // expect LY to always read 0x90 so that waiting for VBlank takes no time
+    let cpu = gameboy.cpu_mut();
+    cpu.set_af(0x01B0);
+    cpu.set_bc(0x0013);
+    cpu.set_de(0x00D8);
+    cpu.set_hl(0x014D);
+    gameboy.bus_mut().set_ly_stub(Some(0x90));
+
+    let syntax = args.syntax.syntax();
+    // Line number, pc and trace line of the last states that matched
+    let mut previous = VecDeque::with_capacity(args.context + 1);
+    let mut lines = 0;
+    for (i, expected) in reference.lines().map(str::trim).enumerate() {
+        while gameboy.cpu().halted() {
+            gameboy.instruction();
+        }
+        let cpu = gameboy.cpu();
+        let actual = trace::trace_line(TraceFormat::Doctor, cpu, gameboy.bus());
+        if actual != expected {
+            writeln!(out, "Line {} differs from {}:", i + 1, args.reference_log)?;
+            writeln!(out, "  expected: {expected}")?;
+            writeln!(out, "  actual:   {actual}")?;
+            for (name, expected, actual) in trace::diff_fields(expected, &actual) {
+                writeln!(out, "  {name}: expected {expected}, got {actual}")?;
+            }
+            writeln!(out)?;
+            writeln!(out, "Previous states:")?;
+            for (line, pc, state) in &previous {
+                let inst = cpu.disassemble(*pc);
+                writeln!(out, "  {line:>8}: {state}")?;
+                writeln!(out, "            {}", disasm::listing_line(syntax, &inst))?;
+            }
+            writeln!(out)?;
+            writeln!(out, "Disassembly from PC:")?;
+            let mut address = cpu.pc();
+            for _ in 0..5 {
+                let inst = cpu.disassemble(address);
+                writeln!(out, "  {}", disasm::listing_line(syntax, &inst))?;
+                address = address.wrapping_add(inst.size());
+            }
+            return Ok(false);
+        }
+        if previous.len() == args.context {
+            previous.pop_front();
+        }
+        if args.context > 0 {
+            previous.push_back((i + 1, cpu.pc(), actual));
+        }
+        lines += 1;
+        gameboy.instruction();
+    }
+    writeln!(out, "All {lines} lines match {}", args.reference_log)?;
+    Ok(true)
+}
+
+/// Disassembles the whole ROM by following its control flow, from the entry point and the
+/// RST and interrupt vectors. Whatever is never reached is written out as data.
 fn dump(args: Dump) -> Result<()> {
-    let mut gb = Gameboy::new();
-    let rom = fs::read(args.rom).unwrap();
-    gb.load_rom(&rom);
-    loop {
-        let inst = gb.cpu().decode();
-        let result: Vec<String> = (1..inst.size)
-            .map(|i| format!("{:#02X}", gb.cpu().mem8(gb.cpu().pc() + i as u16)))
-            .collect();
-        println!(
-            "{:#02X}: {:?} ({:#02X}{}{})",
-            gb.cpu().pc(),
-            inst,
-            inst.opcode,
-            if result.is_empty() { "" } else { " " },
-            result.join(" ")
-        );
-        // TODO: this is very, very stupid as it doesn't follow jumps, so it can
-        // read data as code. how do decompilers even work?
-        let next_pc = gb.cpu().pc() + inst.size as u16;
-        gb.cpu_mut().set_pc(next_pc);
+    let rom = fs::read(args.rom)?;
+    let mut disassembly = RomDisassembly::new(&rom);
+    if let Some(path) = args.symbols {
+        let symbols = SymbolTable::load(&path).map_err(std::io::Error::other)?;
+        disassembly.name_symbols(&symbols);
+    }
+    let asm = disassembly.to_rgbds();
+    match args.output {
+        Some(path) => fs::write(path, asm)?,
+        None => print!("{asm}"),
     }
+    Ok(())
 }
 
-fn run(gb_config: GameboyConfig, args: Run) -> Result<()> {
-    let mut gameboy = gb_config.build_gameboy();
+fn gdbserver(gb_config: GameboyConfig, args: Gdbserver) -> Result<()> {
+    let rom = fs::read(args.rom)?;
+    let mut gameboy = gb_config.build_gameboy(&rom, None)?;
 
+    let listener = TcpListener::bind(("127.0.0.1", args.port))?;
+    println!("Waiting for gdb on {}", listener.local_addr()?);
+    let (stream, peer) = listener.accept()?;
+    println!("Debugging for {peer}");
+    GdbSession::new(&mut gameboy, stream).serve()?;
+    Ok(())
+}
+
+fn run(gb_config: GameboyConfig, args: Run) -> Result<ExitCode> {
+    let movie = match &args.play {
+        Some(path) => Some(Movie::load(Path::new(path)).map_err(std::io::Error::other)?),
+        None => None,
+    };
     let rom = fs::read(args.rom)?;
-    gameboy.load_rom(&rom);
+    let mut gameboy = gb_config.build_gameboy(&rom, movie.as_ref())?;
+    load_cheats(&mut gameboy, &args.cheat);
+    if let Some(movie) = movie {
+        gameboy.play_movie(movie).map_err(std::io::Error::other)?;
+    } else if args.record.is_some() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        gameboy.record_movie(gb_config.boot(), now.as_secs());
+    }
+    if let Some(path) = args.trace {
+        let file = fs::File::create(path)?;
+        gameboy.set_tracer(Some(Tracer::new(
+            Box::new(std::io::BufWriter::new(file)),
+            args.trace_format.into(),
+            args.trace_start,
+            args.trace_stop,
+        )));
+    }
+    let gameboy = Rc::new(RefCell::new(gameboy));
+    let mut script = match args.script {
+        Some(path) => Some(Script::load(gameboy.clone(), &path)?),
+        None => None,
+    };
+    let mut pressed = None;
     loop {
-        if args.debug.unwrap_or(false) {
-            println!("{:#02X}: {:?}", gameboy.cpu().pc(), gameboy.cpu().decode());
+        let cycles = {
+            let mut gameboy = gameboy.borrow_mut();
+            if let Some(input) = &args.input {
+                let buttons = input.buttons(gameboy.ppu().frame_counter());
+                if pressed != Some(buttons) {
+                    gameboy.set_buttons(&buttons);
+                    pressed = Some(buttons);
+                }
+            }
+            if args.debug.unwrap_or(false) {
+                let inst = gameboy.cpu().disassemble(gameboy.cpu().pc());
+                println!("{}", disasm::listing_line(args.syntax.syntax(), &inst));
+            }
+            let cycles = gameboy.step();
+            if gameboy.tracer().is_some_and(Tracer::is_done) {
+                break;
+            }
+            if gameboy
+                .movie()
+                .is_some_and(|movie| movie.finished() || movie.desync().is_some())
+            {
+                break;
+            }
+            if args
+                .frames
+                .is_some_and(|frames| gameboy.ppu().frame_counter() >= frames)
+            {
+                break;
+            }
+            cycles
+        };
+        if let Some(script) = &mut script {
+            script.after_step(cycles as u32)?;
+            if script.stopped() {
+                break;
+            }
         }
-        gameboy.step();
     }
+    let mut gameboy = gameboy.borrow_mut();
+    if let Some(tracer) = gameboy.set_tracer(None) {
+        tracer.finish()?;
+    }
+    let mut status = ExitCode::SUCCESS;
+    if let Some(state) = gameboy.movie() {
+        match state.desync() {
+            Some(desync) => {
+                println!("Error: {desync}");
+                status = ExitCode::FAILURE;
+            }
+            None if !state.recording() => println!("Played {} frames", state.frame()),
+            None => {}
+        }
+    }
+    if let (Some(path), Some(movie)) = (args.record, gameboy.stop_movie()) {
+        movie.save(Path::new(&path))?;
+        println!("Recorded {} frames to {path}", movie.frames.len());
+    }
+
+    if let Some(path) = args.screenshot {
+        write_pgm_screenshot(gameboy.get_frame(), &path)
+            .map_err(|e| std::io::Error::other(format!("{path}: {e}")))?;
+    }
+    let hash = frame_hash(gameboy.get_frame());
+    if args.frame_hash {
+        println!("{hash:016x}");
+    }
+    if let Some(expected) = args.expect_hash.filter(|&expected| expected != hash) {
+        println!("Error: expected frame hash {expected:016x}, got {hash:016x}");
+        status = ExitCode::FAILURE;
+    }
+    Ok(status)
 }
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     let args = Cli::parse();
     let gb_config = args.gameboy_config;
 
     match args.command {
         Commands::Debug(args) => debug(args),
+        Commands::Doctor(args) => doctor(args),
         Commands::Dump(args) => dump(args),
-        Commands::Run(args) => run(gb_config, args),
+        Commands::Gdbserver(args) => gdbserver(gb_config, args),
+        Commands::Run(args) => return run(gb_config, args),
+    }?;
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_script() {
+        let input = InputScript::parse("120:start, 200-260:right+b").unwrap();
+        // Held for HOLD_FRAMES without a range
+        assert!(input.buttons(120).start);
+        assert!(input.buttons(125).start);
+        assert!(!input.buttons(126).start);
+        let buttons = input.buttons(260);
+        assert!(buttons.right && buttons.b && !buttons.start);
+        assert_eq!(input.buttons(261), Buttons::default());
+
+        assert!(InputScript::parse(&format!("{}:a", u32::MAX)).is_err());
+        assert!(InputScript::parse("10-5:a").is_err());
+        assert!(InputScript::parse("10:jump").is_err());
+        assert!(InputScript::parse("start").is_err());
+        assert!(InputScript::parse("x:a").is_err());
+    }
+
+    #[test]
+    fn test_record_needs_frames() {
+        let parse = |args: &[&str]| Cli::try_parse_from([&["main", "run"], args].concat());
+        assert!(parse(&["--record", "game.movie", "game.gb"]).is_err());
+        assert!(parse(&["--record", "game.movie", "--frames", "600", "game.gb"]).is_ok());
+    }
+
+    #[test]
+    fn test_doctor() {
+        let args = Doctor {
+            rom: "loop.gb".to_string(),
+            reference_log: "loop.log".to_string(),
+            context: 10,
+            syntax: AsmSyntax::default(),
+        };
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x103].copy_from_slice(&[0x3C, 0x18, 0xFE]); // inc a; jr @
+        let check = |reference: &str| {
+            let mut out = Vec::new();
+            let matched = check_log(&args, &rom, reference, &mut out).unwrap();
+            (matched, String::from_utf8(out).unwrap())
+        };
+
+        let reference = "\
+            A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:3C,18,FE,00
+            A:02 F:10 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:18,FE,00,00
+            A:02 F:10 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:18,FE,00,00";
+        assert_eq!(
+            check(reference),
+            (true, "All 3 lines match loop.log\n".to_string())
+        );
+
+        let (matched, report) = check(&reference.replacen("A:02", "A:03", 1));
+        assert!(!matched);
+        assert!(report.starts_with("Line 2 differs from loop.log:\n"));
+        assert!(report.contains("\n  A: expected 03, got 02\n"));
+        assert!(report.contains("\nPrevious states:\n         1: A:01 F:B0"));
     }
 }
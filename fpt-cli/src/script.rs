@@ -0,0 +1,311 @@
+//! Lua scripts that drive the emulator, for automated playthroughs and memory hacking.
+//!
+//! Scripts get a `gb` table:
+//!
+//! - `gb.reg(name)`, `gb.set_reg(name, value)`: registers by name, like `a` or `hl`
+//! - `gb.read(address)`, `gb.write(address, value)`: memory, as the CPU sees it
+//! - `gb.press(button)`, `gb.release(button)`: `a`, `b`, `start`, `select`, `up`, `down`,
+//!   `left` or `right`
+//! - `gb.screenshot(path)`: writes the last frame as a PGM image
+//! - `gb.frame()`: frames run so far
+//! - `gb.stop()`: stops running once the current callback returns
+//! - `gb.on_breakpoint(address, function)`: calls `function()` when PC gets to `address`
+//! - `gb.on_write(address, function)`: calls `function(value, old)` on writes to `address`
+//! - `gb.on_frame(function)`: calls `function()` after every frame
+//!
+//! Functions that fail return `nil` and an error message, so wrap them in `assert` to
+//! stop the script instead.
+
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::io;
+use std::rc::Rc;
+
+use fpt::debug_interface::expr::Register;
+use fpt::debug_interface::{DebugCmd, DebugEvent, WatchKind};
+use fpt::memory::Buttons;
+use fpt::ppu::tile::write_pgm_screenshot;
+use fpt::Gameboy;
+use hlua::{function0, function1, function2, Lua, LuaError};
+
+/// Keeps the Lua callbacks, which Rust only knows by debug point id
+const PRELUDE: &str = r#"
+local points, frame_callbacks = {}, {}
+
+function gb.on_breakpoint(address, f)
+    local id, err = gb._breakpoint(address)
+    if id then points[id] = f end
+    return id, err
+end
+
+function gb.on_write(address, f)
+    local id, err = gb._watch(address)
+    if id then points[id] = f end
+    return id, err
+end
+
+function gb.on_frame(f)
+    frame_callbacks[#frame_callbacks + 1] = f
+end
+
+function gb._hit(id, ...)
+    local f = points[id]
+    if f then f(...) end
+end
+
+function gb._frame()
+    for _, f in ipairs(frame_callbacks) do f() end
+end
+"#;
+
+pub struct Script {
+    lua: Lua<'static>,
+    gameboy: Rc<RefCell<Gameboy>>,
+    frames: Rc<Cell<u64>>,
+    stopped: Rc<Cell<bool>>,
+    cycles_since_last_frame: u32,
+}
+
+impl Script {
+    /// Runs the script at `path`, which sets up its callbacks
+    pub fn load(gameboy: Rc<RefCell<Gameboy>>, path: &str) -> io::Result<Self> {
+        Self::run(gameboy, &fs::read_to_string(path)?)
+    }
+
+    /// Runs `code`, which sets up its callbacks
+    fn run(gameboy: Rc<RefCell<Gameboy>>, code: &str) -> io::Result<Self> {
+        let mut script = Script {
+            lua: Lua::new(),
+            gameboy,
+            frames: Rc::new(Cell::new(0)),
+            stopped: Rc::new(Cell::new(false)),
+            cycles_since_last_frame: 0,
+        };
+        script.lua.openlibs();
+        script.expose_api();
+        script.execute(PRELUDE)?;
+        script.execute(code)?;
+        Ok(script)
+    }
+
+    /// Whether the script called `gb.stop()`
+    pub fn stopped(&self) -> bool {
+        self.stopped.get()
+    }
+
+    /// Calls the callbacks for what the last step did
+    pub fn after_step(&mut self, cycles: u32) -> io::Result<()> {
+        let hits: Vec<String> = {
+            let mut gameboy = self.gameboy.borrow_mut();
+            if !gameboy.paused() {
+                Vec::new()
+            } else {
+                let hits = gameboy
+                    .get_debug_events()
+                    .drain(..)
+                    .filter_map(|event| match event {
+                        DebugEvent::Breakpoint(id, _) => Some(format!("gb._hit({id})")),
+                        DebugEvent::Watchpoint(hit) => {
+                            Some(format!("gb._hit({}, {}, {})", hit.id, hit.new, hit.old))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                gameboy.debug_cmd(&DebugCmd::Continue);
+                hits
+            }
+        };
+        for hit in hits {
+            self.execute(&hit)?;
+        }
+
+        self.cycles_since_last_frame += cycles;
+        if self.cycles_since_last_frame >= self.gameboy.borrow().cycles_in_one_frame() {
+            self.cycles_since_last_frame = 0;
+            self.frames.set(self.frames.get() + 1);
+            self.execute("gb._frame()")?;
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, code: &str) -> io::Result<()> {
+        self.lua.execute::<()>(code).map_err(|e| match e {
+            LuaError::ReadError(e) => e,
+            e => io::Error::other(e.to_string()),
+        })
+    }
+
+    fn expose_api(&mut self) {
+        let mut gb = self.lua.empty_array("gb");
+
+        let gameboy = self.gameboy.clone();
+        gb.set(
+            "reg",
+            function1(move |name: String| -> Result<u32, String> {
+                let register = register(&name)?;
+                Ok(gameboy.borrow().cpu().register(register) as u32)
+            }),
+        );
+        let gameboy = self.gameboy.clone();
+        gb.set(
+            "set_reg",
+            function2(move |name: String, value: u32| -> Result<(), String> {
+                let register = register(&name)?;
+                gameboy
+                    .borrow_mut()
+                    .cpu_mut()
+                    .set_register(register, value as u16);
+                Ok(())
+            }),
+        );
+
+        let gameboy = self.gameboy.clone();
+        gb.set(
+            "read",
+            function1(move |address: u32| -> Result<u32, String> {
+                let address = address16(address)?;
+                Ok(gameboy.borrow().bus().read(address as usize) as u32)
+            }),
+        );
+        let gameboy = self.gameboy.clone();
+        gb.set(
+            "write",
+            function2(move |address: u32, value: u32| -> Result<(), String> {
+                let address = address16(address)?;
+                gameboy
+                    .borrow_mut()
+                    .bus_mut()
+                    .write(address as usize, value as u8);
+                Ok(())
+            }),
+        );
+
+        let gameboy = self.gameboy.clone();
+        gb.set(
+            "press",
+            function1(move |button: String| set_button(&gameboy, &button, true)),
+        );
+        let gameboy = self.gameboy.clone();
+        gb.set(
+            "release",
+            function1(move |button: String| set_button(&gameboy, &button, false)),
+        );
+
+        let gameboy = self.gameboy.clone();
+        gb.set(
+            "screenshot",
+            function1(move |path: String| -> Result<(), String> {
+                write_pgm_screenshot(gameboy.borrow().get_frame(), &path)
+                    .map_err(|e| format!("{path}: {e}"))
+            }),
+        );
+        let frames = self.frames.clone();
+        gb.set("frame", function0(move || frames.get() as u32));
+        let stopped = self.stopped.clone();
+        gb.set("stop", function0(move || stopped.set(true)));
+
+        let gameboy = self.gameboy.clone();
+        gb.set(
+            "_breakpoint",
+            function1(move |address: u32| -> Result<u32, String> {
+                let cmd = DebugCmd::Breakpoint(address16(address)?.into(), None);
+                point_id(gameboy.borrow_mut().debug_cmd(&cmd))
+            }),
+        );
+        let gameboy = self.gameboy.clone();
+        gb.set(
+            "_watch",
+            function1(move |address: u32| -> Result<u32, String> {
+                let cmd = DebugCmd::Watchpoint {
+                    kind: WatchKind::Write,
+                    start: address16(address)?.into(),
+                    end: None,
+                    condition: None,
+                };
+                point_id(gameboy.borrow_mut().debug_cmd(&cmd))
+            }),
+        );
+    }
+}
+
+fn register(name: &str) -> Result<Register, String> {
+    Register::from_name(&name.to_lowercase()).ok_or_else(|| format!("no register {name}"))
+}
+
+fn address16(address: u32) -> Result<u16, String> {
+    u16::try_from(address).map_err(|_| format!("address {address:#X} out of range"))
+}
+
+fn point_id(event: Option<DebugEvent>) -> Result<u32, String> {
+    match event {
+        Some(DebugEvent::RegisterBreakpoint(id, _) | DebugEvent::RegisterWatchpoint(id, _)) => {
+            Ok(id as u32)
+        }
+        Some(DebugEvent::Error(e)) => Err(e),
+        _ => Err("could not add the debug point".to_string()),
+    }
+}
+
+fn set_button(gameboy: &RefCell<Gameboy>, name: &str, pressed: bool) -> Result<(), String> {
+    let mut gameboy = gameboy.borrow_mut();
+    let mut buttons: Buttons = gameboy.bus().buttons();
+    buttons.set(name, pressed)?;
+    gameboy.set_buttons(&buttons);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Gameboy looping forever
+    fn gameboy() -> Rc<RefCell<Gameboy>> {
+        let mut rom = vec![0; 0x8000];
+        // .loop: jr .loop
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut gameboy = Gameboy::new();
+        gameboy.load_rom(&rom);
+        gameboy.boot_fake();
+        Rc::new(RefCell::new(gameboy))
+    }
+
+    #[test]
+    fn test_memory_and_registers() {
+        let gameboy = gameboy();
+        Script::run(
+            gameboy.clone(),
+            r#"
+            gb.write(0xC000, 0x42)
+            assert(gb.read(0xC000) == 0x42)
+            gb.set_reg("hl", 0x1234)
+            assert(gb.reg("h") == 0x12)
+            local value, err = gb.reg("q")
+            assert(value == nil and err == "no register q")
+            "#,
+        )
+        .unwrap();
+        let gameboy = gameboy.borrow();
+        assert_eq!(gameboy.bus().read(0xC000), 0x42);
+        assert_eq!(gameboy.cpu().hl(), 0x1234);
+    }
+
+    #[test]
+    fn test_on_frame() {
+        let gameboy = gameboy();
+        let mut script = Script::run(
+            gameboy.clone(),
+            r#"
+            gb.on_frame(function()
+                gb.write(0xC000, gb.frame())
+                if gb.frame() == 3 then gb.stop() end
+            end)
+            "#,
+        )
+        .unwrap();
+        while !script.stopped() {
+            let cycles = gameboy.borrow_mut().step();
+            script.after_step(cycles as u32).unwrap();
+        }
+        assert_eq!(gameboy.borrow().bus().read(0xC000), 3);
+    }
+}
@@ -12,7 +12,15 @@ use egui::{
     TextureHandle, TextureOptions, TopBottomPanel, Ui, Vec2, ViewportBuilder, ViewportCommand,
 };
 use fpt::debug_interface::DebugEvent;
+use fpt::debug_interface::WatchKind;
+use fpt::disasm::{self, DecodedInstruction, Pastraiser, Rgbds, Syntax};
+use fpt::history::History;
+use fpt::memory::search::{SearchFilter, SearchWidth};
 use fpt::memory::Buttons;
+use fpt::model::Model;
+use fpt::movie::Boot;
+#[cfg(not(target_arch = "wasm32"))]
+use fpt::movie::Movie;
 use fpt::ppu::tile::Tile;
 use fpt::{bw, DebugCmd, DebugInterface, Gameboy};
 use log::info;
@@ -27,6 +35,8 @@ const GREY: Color32 = Color32::from_rgb(120, 120, 120);
 
 const WIDTH: usize = fpt::ppu::WIDTH;
 const HEIGHT: usize = fpt::ppu::HEIGHT;
+const SGB_WIDTH: usize = fpt::sgb::SGB_WIDTH;
+const SGB_HEIGHT: usize = fpt::sgb::SGB_HEIGHT;
 
 const PALETTE: [Color32; 4] = [
     Color32::from_rgb(0, 63, 0),
@@ -53,6 +63,12 @@ const BMV_X_SIZE: usize = 256 + BMV_BORDER_SIZE * 2;
 const BMV_Y_SIZE: usize = 256 + BMV_BORDER_SIZE * 2;
 const BMV_TEXTURE_SCALE: f32 = 1.0;
 
+/// A line of the code panel: a label from the symbol file or a listed instruction
+enum CodeRow<'a> {
+    Label(&'a str),
+    Instruction(&'a DecodedInstruction),
+}
+
 #[cfg(target_arch = "wasm32")]
 #[allow(dead_code)]
 fn now() -> f64 {
@@ -83,17 +99,43 @@ struct DebugConsole {
     was_focused: bool,
 }
 
+#[derive(Default)]
+struct MemorySearchPanel {
+    width: SearchWidth,
+    /// Value for the "Equal to" filter
+    value: String,
+}
+
+/// The cheat being typed in
+#[derive(Default)]
+struct CheatsPanel {
+    code: String,
+    description: String,
+}
+
 pub struct FPT {
     gb: Gameboy,
     cycles_since_last_frame: u32,
     accum_time: f64,
     egui_frame_count: u64,
     gb_frame_count: u64,
-    bootrom: Option<BootromToFake>,
+    /// How to boot the ROMs loaded
+    boot: Boot,
+    /// The model given on the command line, if any
+    model: Option<Model>,
+    /// The bootrom given on the command line, if any
+    bootrom: Option<Vec<u8>>,
+    /// The ROM loaded, to power on again for movies
+    rom: Vec<u8>,
 
     slow_factor: f64,
+    rgbds_syntax: bool,
+    /// Whether history is recorded for reverse execution, which takes a lot of memory
+    reverse: bool,
     // Debug Console (DC)
     debug_console: DebugConsole,
+    memory_search: MemorySearchPanel,
+    cheats: CheatsPanel,
 
     image: ColorImage,
     texture: Option<TextureHandle>,
@@ -116,11 +158,18 @@ impl Default for FPT {
             accum_time: 0.0,
             egui_frame_count: 0,
             gb_frame_count: 0,
+            boot: Boot::Real,
+            model: None,
             bootrom: None,
+            rom: Vec::new(),
 
             slow_factor: 1.0,
+            rgbds_syntax: true,
+            reverse: false,
 
             debug_console: DebugConsole::default(),
+            memory_search: MemorySearchPanel::default(),
+            cheats: CheatsPanel::default(),
 
             image: ColorImage::new([WIDTH, HEIGHT], Color32::TRANSPARENT),
             texture: None,
@@ -139,26 +188,32 @@ impl Default for FPT {
 impl FPT {
     /// Called once before the first frame.
     #[allow(unused_variables)]
-    fn new(_cc: &eframe::CreationContext, bootrom: Option<BootromToFake>, rom_path: &str) -> Self {
+    fn new(_cc: &eframe::CreationContext, cli: Cli) -> Self {
+        let bootrom = cli
+            .bootrom
+            .map(|path| std::fs::read(&path).unwrap_or_else(|_| panic!("Unable to open {}", path)));
         let mut fpt = FPT {
-            bootrom: bootrom.clone(),
+            boot: match cli.fake_bootrom {
+                Some(_) => Boot::Fake,
+                None => Boot::Real,
+            },
+            model: cli.fake_bootrom.or(cli.model).map(Model::from),
+            bootrom,
             ..Default::default()
         };
+        let rom_path = cli.rom.unwrap_or("roms/Tetris_World_Rev_1.gb".to_string());
         if cfg!(target_arch = "wasm32") {
             fpt.gb.cpu_mut().set_paused(true);
         } else if std::env::var("CI").is_err() {
-            if let Ok(rom) = std::fs::read(rom_path) {
+            if let Ok(rom) = std::fs::read(&rom_path) {
                 fpt.gb.load_rom(&rom);
+                fpt.rom = rom;
+                fpt.load_saved_cheats();
             } else {
                 panic!("Unable to open {}", rom_path);
             }
         }
-        // XXX duplicated logic from fpt-cli main.rs
-        if let Some(BootromToFake::DMG0) = bootrom {
-            fpt.gb.boot_fake();
-        } else {
-            fpt.gb.boot_real();
-        }
+        fpt.boot(fpt.boot, fpt.model);
         fpt
     }
 
@@ -168,9 +223,14 @@ impl FPT {
         let delta_time = ui.input(|i| i.unstable_dt) as f64;
         self.accum_time += delta_time;
         // TODO: should limit to a 60fps frame, taking self.slow_factor into account (so 10 frames at 0.1, or 0.1 frames at 10)
-        let cycles_want = self.accum_time.div_euclid(T_CYCLE * self.slow_factor) as u32;
-        let mut cycles_ran = 0;
-        while cycles_ran < cycles_want && !self.gb.paused() {
+        // A t-cycle of the CPU is half a dot in double speed mode, which can start or end
+        // at any STOP, so time is counted as cycles run
+        let mut time_ran = 0.0;
+        while !self.gb.paused() {
+            let t_cycle = self.t_cycle() * self.slow_factor;
+            if time_ran + t_cycle > self.accum_time {
+                break;
+            }
             let cycles = self.gb.step() as u32;
             self.cycles_since_last_frame += cycles;
             if self.cycles_since_last_frame >= self.gb.cycles_in_one_frame() {
@@ -178,12 +238,21 @@ impl FPT {
                 self.gb_frame_count += 1;
                 self.cycles_since_last_frame = 0;
             }
-            cycles_ran += cycles;
+            time_ran += cycles as f64 * t_cycle;
         }
-        self.accum_time -= cycles_ran as f64 * T_CYCLE * self.slow_factor;
+        self.accum_time -= time_ran;
         frame
     }
 
+    /// How long a t-cycle of the CPU takes
+    fn t_cycle(&self) -> f64 {
+        if self.gb.double_speed() {
+            T_CYCLE / 2.0
+        } else {
+            T_CYCLE
+        }
+    }
+
     #[allow(dead_code)]
     fn sleep(&mut self, ctx: &Context, frame_start: f64, gb_frame_count_before: u64) {
         let mut _ccc = false;
@@ -253,6 +322,33 @@ impl FPT {
             {
                 self.gb.set_paused(!paused);
             }
+            ui.add_enabled_ui(paused, |ui| {
+                for (label, command) in [
+                    ("Step", DebugCmd::Step),
+                    ("Next", DebugCmd::Next),
+                    ("Finish", DebugCmd::Finish),
+                    ("Backtrace", DebugCmd::Backtrace),
+                ] {
+                    if ui.button(label).clicked() {
+                        self.run_debug_cmd(&command);
+                    }
+                }
+                ui.add_enabled_ui(self.reverse, |ui| {
+                    for (label, command) in [
+                        ("Reverse step", DebugCmd::ReverseStep),
+                        ("Reverse continue", DebugCmd::ReverseContinue),
+                    ] {
+                        if ui.button(label).clicked() {
+                            self.run_debug_cmd(&command);
+                        }
+                    }
+                });
+            });
+            if ui.checkbox(&mut self.reverse, "Reverse").changed() {
+                // Snapshots of the last ten seconds, which is about 150 MB
+                let history = self.reverse.then(History::default);
+                self.gb.set_history(history);
+            }
             ui.horizontal(|ui| {
                 ui.monospace("Slow factor:");
                 ui.radio_value(&mut self.slow_factor, 0.1f64, "0.1");
@@ -306,17 +402,43 @@ impl FPT {
         });
         // TODO: scroll into line of current pc (need to find index)
         // TODO: differentiate current pc
+        let mut run_to = None;
         ui.collapsing("Code", |ui| {
             ui.vertical(|ui| {
+                ui.checkbox(&mut self.rgbds_syntax, "RGBDS syntax");
+                let syntax: &dyn Syntax = if self.rgbds_syntax {
+                    &Rgbds
+                } else {
+                    &Pastraiser
+                };
                 let mem = self.gb.bus().memory();
-                let code_flat: Vec<&String> = mem.code_listing().iter().flatten().collect();
+                let symbols = self.gb.cpu().debugger().symbols();
+                let rom_bank = self.gb.bus().rom_bank();
+                let banked_symbols = symbols.with_rom_bank(rom_bank);
+                let code_flat: Vec<CodeRow> = mem
+                    .code_listing()
+                    .iter()
+                    .flatten()
+                    .flat_map(|inst| {
+                        let label = symbols
+                            .symbol_at(inst.address, rom_bank)
+                            .map(|symbol| CodeRow::Label(&symbol.name));
+                        label.into_iter().chain([CodeRow::Instruction(inst)])
+                    })
+                    .collect();
+                let format_row = |row: &CodeRow| match row {
+                    CodeRow::Label(name) => format!("{name}:"),
+                    CodeRow::Instruction(inst) => {
+                        disasm::listing_line_with_symbols(syntax, inst, &banked_symbols)
+                    }
+                };
                 if ui.button("Dump").clicked() {
                     println!(
                         "{}",
                         code_flat
                             .iter()
-                            .map(|s| s.as_str())
-                            .collect::<Vec<&str>>()
+                            .map(format_row)
+                            .collect::<Vec<String>>()
                             .join("\n")
                     );
                 }
@@ -326,12 +448,28 @@ impl FPT {
                     code_flat.len(),
                     |ui, row_range| {
                         for row in row_range {
-                            ui.label(RichText::new(code_flat[row].clone()).monospace());
+                            let label =
+                                ui.label(RichText::new(format_row(&code_flat[row])).monospace());
+                            if let CodeRow::Instruction(inst) = code_flat[row] {
+                                label.context_menu(|ui| {
+                                    if ui.button("Run to here").clicked() {
+                                        run_to = Some(inst.address);
+                                        ui.close_menu();
+                                    }
+                                });
+                            }
                         }
                     },
                 );
             });
         });
+        if let Some(address) = run_to {
+            self.run_debug_cmd(&DebugCmd::Until(address.into()));
+        }
+        ui.collapsing("Memory search", |ui| self.memory_search(ui));
+        ui.collapsing("Cheats", |ui| self.cheats(ui));
+        #[cfg(not(target_arch = "wasm32"))]
+        ui.collapsing("Movie", |ui| self.movie(ui));
         ui.collapsing("Console", |ui| {
             ScrollArea::vertical()
                 .auto_shrink(false)
@@ -381,15 +519,10 @@ impl FPT {
                     .push(format!("> {}", self.debug_console.command));
 
                 let command = DebugCmd::from_string(&self.debug_console.command);
-                let event = match command {
-                    Some(command) => self
-                        .gb
-                        .debug_cmd(&command)
-                        .map(|x| format!("{}", x))
-                        .unwrap_or("Unrecognized command".to_string()),
-                    None => "Cannot parse command".to_string(),
-                };
-                self.debug_console.console.push(event);
+                match command {
+                    Ok(command) => self.run_debug_cmd(&command),
+                    Err(e) => self.debug_console.console.push(format!("Error: {e}")),
+                }
 
                 self.debug_console
                     .last_command
@@ -399,6 +532,222 @@ impl FPT {
         });
     }
 
+    /// Sends `command` to the debugger and shows its answer in the console
+    fn run_debug_cmd(&mut self, command: &DebugCmd) {
+        let event = self
+            .gb
+            .debug_cmd(command)
+            .map(|x| format!("{}", x))
+            .unwrap_or("Unrecognized command".to_string());
+        self.debug_console.console.push(event);
+    }
+
+    /// Narrows down RAM addresses by how their value changes, to find a game's variables
+    fn memory_search(&mut self, ui: &mut Ui) {
+        const SHOWN: usize = 100;
+        let mut commands = Vec::new();
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.memory_search.width, SearchWidth::Byte, "8 bits");
+            ui.radio_value(&mut self.memory_search.width, SearchWidth::Word, "16 bits");
+            if ui.button("New").clicked() {
+                commands.push(DebugCmd::SearchStart(self.memory_search.width));
+            }
+        });
+        let search = self.gb.cpu().debugger().search();
+        ui.add_enabled_ui(search.is_some(), |ui| {
+            ui.horizontal(|ui| {
+                for (label, filter) in [
+                    ("Same", SearchFilter::Same),
+                    ("Changed", SearchFilter::Changed),
+                    ("Increased", SearchFilter::Increased),
+                    ("Decreased", SearchFilter::Decreased),
+                ] {
+                    if ui.button(label).clicked() {
+                        commands.push(DebugCmd::SearchFilter(filter));
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                let value = self.memory_search.value.trim();
+                let parsed = match value.strip_prefix("0x") {
+                    Some(hex) => u16::from_str_radix(hex, 16).ok(),
+                    None => value.parse().ok(),
+                };
+                ui.add_enabled_ui(parsed.is_some(), |ui| {
+                    if ui.button("Equal to").clicked() {
+                        commands.push(DebugCmd::SearchFilter(SearchFilter::Equal(parsed.unwrap())));
+                    }
+                });
+                ui.text_edit_singleline(&mut self.memory_search.value);
+            });
+        });
+        if let Some(search) = search {
+            let candidates = search.candidates();
+            ui.label(format!("{} candidates", candidates.len()));
+            let digits = match search.width() {
+                SearchWidth::Byte => 2,
+                SearchWidth::Word => 4,
+            };
+            Grid::new("memory_search").striped(true).show(ui, |ui| {
+                ui.label("Address");
+                ui.label("Previous");
+                ui.label("Searched");
+                ui.label("Now");
+                ui.end_row();
+                for candidate in candidates.iter().take(SHOWN) {
+                    let now = search.read(self.gb.bus(), candidate);
+                    let address = match candidate.bank {
+                        Some(bank) => format!("{:#06X} in bank {}", candidate.address, bank),
+                        None => format!("{:#06X}", candidate.address),
+                    };
+                    let address = ui.code(address);
+                    address.context_menu(|ui| {
+                        if ui.button("Watch writes").clicked() {
+                            commands.push(DebugCmd::Watchpoint {
+                                kind: WatchKind::Write,
+                                start: candidate.address.into(),
+                                end: None,
+                                condition: None,
+                            });
+                            ui.close_menu();
+                        }
+                    });
+                    ui.monospace(format!("{:0digits$X}", candidate.previous));
+                    ui.monospace(format!("{:0digits$X}", candidate.value));
+                    ui.monospace(format!("{:0digits$X}", now));
+                    ui.end_row();
+                }
+            });
+            if candidates.len() > SHOWN {
+                ui.label(format!("and {} more", candidates.len() - SHOWN));
+            }
+        }
+        for command in commands {
+            self.run_debug_cmd(&command);
+        }
+    }
+
+    fn cheats(&mut self, ui: &mut Ui) {
+        let mut commands = Vec::new();
+        Grid::new("cheats").striped(true).show(ui, |ui| {
+            let memory = self.gb.bus().memory();
+            for (i, cheat) in memory.cheats.list().iter().enumerate() {
+                let mut enabled = cheat.enabled;
+                if ui.checkbox(&mut enabled, &cheat.code).changed() {
+                    commands.push(DebugCmd::CheatEnable(i + 1, enabled));
+                }
+                ui.label(&cheat.description);
+                if ui.button("Delete").clicked() {
+                    commands.push(DebugCmd::CheatDelete(i + 1));
+                }
+                ui.end_row();
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(&mut self.cheats.code).hint_text("Code"));
+            ui.add(
+                egui::TextEdit::singleline(&mut self.cheats.description).hint_text("Description"),
+            );
+            if ui.button("Add").clicked() {
+                commands.push(DebugCmd::CheatAdd(
+                    self.cheats.code.clone(),
+                    self.cheats.description.clone(),
+                ));
+                self.cheats = CheatsPanel::default();
+            }
+        });
+        if ui.button("Save").clicked() {
+            commands.push(DebugCmd::CheatSave);
+        }
+        for command in commands {
+            self.run_debug_cmd(&command);
+        }
+    }
+
+    /// Boots the ROM loaded on `model`, if given, with the bootrom given, if any
+    fn boot(&mut self, boot: Boot, model: Option<Model>) {
+        if let Some(model) = model {
+            self.gb.set_model(model);
+        }
+        if let Some(bootrom) = &self.bootrom {
+            if let Err(e) = self.gb.set_bootrom(bootrom) {
+                self.debug_console.console.push(format!("Error: {e}"));
+            }
+        }
+        boot.apply(&mut self.gb);
+    }
+
+    /// Loads the cheats saved for the game, if any
+    fn load_saved_cheats(&mut self) {
+        if self.gb.bus().cheats_path().exists() {
+            self.run_debug_cmd(&DebugCmd::CheatLoad);
+        }
+    }
+
+    /// Records the buttons pressed from power on, or plays them back
+    #[cfg(not(target_arch = "wasm32"))]
+    fn movie(&mut self, ui: &mut Ui) {
+        let Some(state) = self.gb.movie() else {
+            if ui.button("Record from power on").clicked() {
+                self.power_on(self.boot, self.model);
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default();
+                self.gb.record_movie(self.boot, now.as_secs());
+            }
+            if ui.button("Play").clicked() {
+                if let Some(path) = rfd::FileDialog::new().pick_file() {
+                    let played = Movie::load(&path).and_then(|movie| {
+                        self.power_on(movie.boot, movie.model);
+                        self.gb.play_movie(movie)
+                    });
+                    if let Err(e) = played {
+                        self.debug_console.console.push(format!("Error: {e}"));
+                    }
+                }
+            }
+            return;
+        };
+
+        if state.recording() {
+            ui.label(format!("Recording frame {}", state.frame()));
+            if ui.button("Stop and save").clicked() {
+                let movie = self.gb.stop_movie().unwrap();
+                if let Some(path) = rfd::FileDialog::new().save_file() {
+                    if let Err(e) = movie.save(&path) {
+                        self.debug_console.console.push(format!("Error: {e}"));
+                    }
+                }
+            }
+        } else {
+            let frames = state.movie().frames.len();
+            ui.label(format!(
+                "Playing frame {} of {frames}",
+                state.frame().min(frames)
+            ));
+            match state.desync() {
+                Some(desync) => ui.colored_label(Color32::RED, desync.to_string()),
+                None if state.finished() => ui.label("Played without desync"),
+                None => ui.label("No desync so far"),
+            };
+            if ui.button("Stop").clicked() {
+                self.gb.stop_movie();
+            }
+        }
+    }
+
+    /// Starts the loaded ROM again from scratch, as movies do
+    #[cfg(not(target_arch = "wasm32"))]
+    fn power_on(&mut self, boot: Boot, model: Option<Model>) {
+        self.gb = Gameboy::new();
+        self.gb.load_rom(&self.rom);
+        self.boot(boot, model);
+        if self.reverse {
+            self.gb.set_history(Some(History::default()));
+        }
+        self.cycles_since_last_frame = 0;
+    }
+
     fn vram_registers(&mut self, ui: &mut Ui) {
         let bus = self.gb.bus();
         Grid::new("VRAM-registers-parent")
@@ -535,6 +884,18 @@ impl FPT {
         });
     }
 
+    /// Shows a picture in 0xRRGGBB colors, which is bigger on a SGB for the border
+    fn set_image(&mut self, picture: &[u32], size: [usize; 2]) {
+        let pixels = picture.iter().map(|rgb| {
+            let [_, r, g, b] = rgb.to_be_bytes();
+            Color32::from_rgb(r, g, b)
+        });
+        self.image = ColorImage {
+            size,
+            pixels: pixels.collect(),
+        };
+    }
+
     fn central_panel(&mut self, ctx: &Context, ui: &mut Ui) {
         if !self.gb.cpu().paused() {
             // TODO: only capture buttons if debug console is not focused
@@ -551,8 +912,17 @@ impl FPT {
             self.gb.set_buttons(&buttons);
             let frame = self.emulator(ui);
             if let Some(frame) = frame {
-                for (i, &gb_pixel) in frame.iter().enumerate() {
-                    self.image.pixels[i] = PALETTE[gb_pixel as usize];
+                if let Some(picture) = self.gb.get_sgb_frame() {
+                    self.set_image(&picture, [SGB_WIDTH, SGB_HEIGHT]);
+                } else if self.gb.bus().cgb() {
+                    let rgb_frame = *self.gb.get_rgb_frame();
+                    self.set_image(&rgb_frame, [WIDTH, HEIGHT]);
+                } else {
+                    let pixels = frame.iter().map(|&gb_pixel| PALETTE[gb_pixel as usize]);
+                    self.image = ColorImage {
+                        size: [WIDTH, HEIGHT],
+                        pixels: pixels.collect(),
+                    };
                 }
             }
         }
@@ -574,11 +944,8 @@ impl FPT {
     fn load_rom(&mut self, ui: &mut Ui) {
         if let Ok(text) = self.rom_channel.1.try_recv() {
             self.gb.load_rom(&text);
-            if let Some(BootromToFake::DMG0) = self.bootrom {
-                self.gb.boot_fake();
-            } else {
-                self.gb.boot_real();
-            }
+            self.rom = text;
+            self.boot(self.boot, self.model);
             self.gb.cpu_mut().set_paused(false);
         }
         if ui.button("Load rom").clicked() {
@@ -603,11 +970,9 @@ impl FPT {
             if let Some(file) = file {
                 let text: Box<[u8]> = std::fs::read(file).unwrap().into_boxed_slice();
                 self.gb.load_rom(&text);
-                if let Some(BootromToFake::DMG0) = self.bootrom {
-                    self.gb.boot_fake();
-                } else {
-                    self.gb.boot_real();
-                }
+                self.rom = text.into_vec();
+                self.load_saved_cheats();
+                self.boot(self.boot, self.model);
                 self.gb.cpu_mut().set_paused(false);
             }
         }
@@ -630,21 +995,51 @@ impl eframe::App for FPT {
     }
 }
 
-#[derive(Parser)]
+#[derive(Parser, Default)]
 #[command(version, about, long_about = None)]
 struct Cli {
-    /// Apply known CPU and hardware register values of a well-known bootrom when it
+    /// Apply known CPU and hardware register values of the bootrom of this model when it
     /// hands off the execution to the cartridge's code. This skips emulating a bootrom.
+    #[arg(short, long, conflicts_with_all = ["model", "bootrom"])]
+    fake_bootrom: Option<ModelArg>,
+    /// Emulate this model instead of the one the cartridge header asks for
+    #[arg(short, long)]
+    model: Option<ModelArg>,
+    /// Run this bootrom of the model, 256 bytes or 2304 for a CGB, instead of the DMG one
     #[arg(short, long)]
-    fake_bootrom: Option<BootromToFake>,
+    bootrom: Option<String>,
     /// ROM path
     rom: Option<String>,
 }
 
 // XXX duplicated struct from fpt-cli's main.rs
-#[derive(ValueEnum, Debug, Clone, PartialEq)]
-enum BootromToFake {
-    DMG0,
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum ModelArg {
+    /// The first Game Boys, with an early bootrom
+    Dmg0,
+    /// Later Game Boys
+    DmgAbc,
+    /// Game Boy Pocket
+    Mgb,
+    /// Super Game Boy
+    Sgb,
+    /// Super Game Boy 2
+    Sgb2,
+    /// Game Boy Color
+    Cgb,
+}
+
+impl From<ModelArg> for Model {
+    fn from(model: ModelArg) -> Self {
+        match model {
+            ModelArg::Dmg0 => Model::Dmg0,
+            ModelArg::DmgAbc => Model::DmgAbc,
+            ModelArg::Mgb => Model::Mgb,
+            ModelArg::Sgb => Model::Sgb,
+            ModelArg::Sgb2 => Model::Sgb2,
+            ModelArg::Cgb => Model::Cgb,
+        }
+    }
 }
 
 /// Desktop entry point
@@ -664,13 +1059,7 @@ fn main() -> eframe::Result<()> {
     eframe::run_native(
         "FPT",
         native_options,
-        Box::new(|cc| {
-            Box::new(FPT::new(
-                cc,
-                cli.fake_bootrom,
-                &cli.rom.unwrap_or("roms/Tetris_World_Rev_1.gb".to_string()),
-            ))
-        }),
+        Box::new(|cc| Box::new(FPT::new(cc, cli))),
     )
 }
 
@@ -685,7 +1074,7 @@ fn main() {
             .start(
                 "the_canvas_id",
                 web_options,
-                Box::new(|cc| Box::new(FPT::new(cc, None, ""))),
+                Box::new(|cc| Box::new(FPT::new(cc, Cli::default()))),
             )
             .await
             .expect("failed to start eframe");
@@ -1,8 +1,8 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
-use std::process::Command;
 
 use serde::{Deserialize, Serialize};
 
@@ -12,15 +12,44 @@ struct Suite {
     name: String,
 }
 
+/// Frames a screen test runs for when it doesn't say, about ten seconds
+const SCREEN_TEST_FRAMES: u32 = 600;
+
+/// Colors of the reference images of dmg-acid2 and Mealybug Tearoom, from the lightest
+/// shade to the darkest
+const GREYSCALE: [u32; 4] = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
+
 #[derive(Serialize, Deserialize)]
 struct Test {
     id: u32,
     path: String,
-    termination_address: String,
+    kind: Option<TestKind>,
+    termination_address: Option<String>,
+    /// Model a registers test runs on, as `Model::parse` takes it, when not the one the
+    /// ROM's header asks for
+    model: Option<String>,
+    /// PNG the screen of a screen test must look like
+    reference: Option<String>,
+    /// How long a screen test runs if it never gets to LD B,B
+    frames: Option<u32>,
+    /// Colors of the reference image, as `#RRGGBB`, from the lightest shade to the darkest
+    palette: Option<[String; 4]>,
     passing: Option<bool>,
     enabled: Option<bool>,
 }
 
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum TestKind {
+    /// Runs until `termination_address`, and passes if the registers hold the Fibonacci
+    /// numbers at every LD B,B, as Mooneye tests do
+    #[default]
+    Registers,
+    /// Runs until LD B,B or for `frames`, and passes if the screen looks like the
+    /// `reference` image
+    Screen,
+}
+
 fn generate_rom_tests() {
     println!("cargo:rerun-if-changed=tests");
     let out_dir = env::var("OUT_DIR").unwrap();
@@ -35,72 +64,75 @@ fn write_test(test_file: &mut File, directory: &str) {
     let source = std::fs::read_to_string(directory).unwrap();
     let suite: Suite = serde_json::from_str(&source).unwrap();
 
+    // How many tests each collection of test ROMs, like mooneye, has by the first directory
+    // of their paths
+    let mut collections: BTreeMap<String, usize> = BTreeMap::new();
     for test in suite.tests {
         if test.enabled.is_some() && !test.enabled.unwrap() {
             continue;
         }
         let test_name = format!("{}_{:04}", suite.name, test.id);
+        let collection = test.path.split('/').next().unwrap().to_string();
+        let passing = test.passing.unwrap_or(true);
 
+        let run = match test.kind.unwrap_or_default() {
+            TestKind::Registers => format!(
+                "rom_test({:?}, {}, {:?})",
+                test.path,
+                test.termination_address.unwrap(),
+                test.model.as_deref(),
+            ),
+            TestKind::Screen => format!(
+                "screen_test({:?}, {:?}, {:?}, {}, {})",
+                test_name,
+                test.path,
+                test.reference.unwrap(),
+                test.frames.unwrap_or(SCREEN_TEST_FRAMES),
+                palette(test.palette.as_ref()),
+            ),
+        };
         write!(
             test_file,
             include_str!("./tests/templates/test"),
             name = test_name,
-            path = test.path,
-            termination_address = test.termination_address,
-            passing = if test.passing.unwrap_or(true) {
-                "true"
-            } else {
-                "false"
-            },
+            collection = collection,
+            run = run,
+            passing = passing,
         )
         .unwrap();
+        *collections.entry(collection).or_default() += 1;
     }
-}
-
-fn write_header(test_file: &mut File) {
-    write!(test_file, include_str!("./tests/templates/header")).unwrap();
-}
 
-#[cfg(target_os = "windows")]
-fn get_sh() -> String {
-    std::env::var("ProgramFiles").unwrap_or(r#"C:\Program Files"#.to_string())
-        + r#"\Git\bin\sh.exe"#
+    let collections: Vec<String> = collections
+        .iter()
+        .map(|(collection, tests)| format!("({collection:?}, {tests})"))
+        .collect();
+    writeln!(
+        test_file,
+        "const COLLECTIONS: &[(&str, usize)] = &[{}];",
+        collections.join(", ")
+    )
+    .unwrap();
 }
 
-#[cfg(not(target_os = "windows"))]
-fn get_sh() -> &'static str {
-    "sh"
+/// The palette of a screen test as a Rust array
+fn palette(colors: Option<&[String; 4]>) -> String {
+    let colors = colors.map_or(GREYSCALE, |colors| {
+        colors.clone().map(|color| {
+            u32::from_str_radix(color.trim_start_matches('#'), 16)
+                .unwrap_or_else(|_| panic!("invalid color {color}, expected #RRGGBB"))
+        })
+    });
+    format!(
+        "[{:#08X}, {:#08X}, {:#08X}, {:#08X}]",
+        colors[0], colors[1], colors[2], colors[3]
+    )
 }
 
-fn run_cmd(cmd: &str) -> String {
-    let output = Command::new(get_sh())
-        .arg("-c")
-        .arg(cmd)
-        .output()
-        .unwrap_or_else(|e| {
-            panic!(
-                "failed to execute process: {:#?} under {:#?}: {:#?}",
-                cmd,
-                get_sh(),
-                e
-            )
-        });
-    if !output.status.success() {
-        panic!("Command {} failed with exit code: {}", cmd, output.status);
-    }
-    String::from_utf8(output.stdout).expect("Failed to convert output to string")
-}
-
-fn fetch_mooneye_test_roms() {
-    println!("cargo:rerun-if-changed=build.rs");
-    run_cmd("curl -L --create-dirs --output-dir ../target/tmp -O https://gekkio.fi/files/mooneye-test-suite/mts-20240127-1204-74ae166/mts-20240127-1204-74ae166.tar.xz");
-    run_cmd("mkdir -p ../target/test_roms");
-    run_cmd("tar -xvf ../target/tmp/mts-20240127-1204-74ae166.tar.xz -C ../target/test_roms");
-    run_cmd("rm -rf ../target/test_roms/mooneye");
-    run_cmd("mv ../target/test_roms/mts-20240127-1204-74ae166 ../target/test_roms/mooneye");
+fn write_header(test_file: &mut File) {
+    write!(test_file, include_str!("./tests/templates/header")).unwrap();
 }
 
 fn main() {
     generate_rom_tests();
-    fetch_mooneye_test_roms();
 }
@@ -0,0 +1,294 @@
+//! Game Genie and GameShark cheat codes.
+//!
+//! Game Genie codes patch ROM as the CPU reads it, and GameShark codes write to RAM at
+//! every VBlank. The cheats of a game are saved to a file named after its title.
+//!
+//! <https://gbdev.io/pandocs/Shark_Cheats.html>
+
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where cheats are saved, one file per game
+pub const CHEATS_DIR: &str = "cheats";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CheatCode {
+    /// Replaces the ROM byte at `address`, only while it is `compare` if given, so that
+    /// other banks are left alone
+    GameGenie {
+        address: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+    /// Writes `value` to `address` at every VBlank
+    GameShark { address: u16, value: u8 },
+}
+
+impl CheatCode {
+    /// Decodes `ABC-DEF` and `ABC-DEF-GHI` Game Genie codes, and `ttvvllhh` GameShark codes.
+    /// GameShark codes for a cartridge RAM bank write to whichever bank is switched in.
+    pub fn parse(code: &str) -> Result<CheatCode, String> {
+        let code = code.trim();
+        let digits: String = code.chars().filter(|&c| c != '-').collect();
+        let nibbles = digits
+            .chars()
+            .map(|c| c.to_digit(16).map(|d| d as u16))
+            .collect::<Option<Vec<u16>>>()
+            .ok_or_else(|| format!("{code} is not made of hex digits"))?;
+        match (code.contains('-'), nibbles.as_slice()) {
+            (true, [a, b, c, d, e, f, rest @ ..]) if rest.is_empty() || rest.len() == 3 => {
+                let compare = match rest {
+                    [g, _, i] => Some(((g << 4 | i) as u8).rotate_right(2) ^ 0xBA),
+                    _ => None,
+                };
+                Ok(CheatCode::GameGenie {
+                    address: (f << 12 | c << 8 | d << 4 | e) ^ 0xF000,
+                    value: (a << 4 | b) as u8,
+                    compare,
+                })
+            }
+            (false, [t1, t2, v1, v2, l1, l2, h1, h2]) => {
+                let kind = t1 << 4 | t2;
+                if kind > 0x01 && !(0x80..=0x8F).contains(&kind) {
+                    return Err(format!("unknown GameShark code type {kind:02X}"));
+                }
+                Ok(CheatCode::GameShark {
+                    address: h1 << 12 | h2 << 8 | l1 << 4 | l2,
+                    value: (v1 << 4 | v2) as u8,
+                })
+            }
+            _ => Err(format!(
+                "{code} is neither a Game Genie code (ABC-DEF or ABC-DEF-GHI) nor a GameShark one (01VVLLHH)"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Cheat {
+    /// As the user typed it
+    pub code: String,
+    pub decoded: CheatCode,
+    pub enabled: bool,
+    pub description: String,
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code)?;
+        if !self.enabled {
+            write!(f, " (disabled)")?;
+        }
+        if !self.description.is_empty() {
+            write!(f, " {}", self.description)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+    /// Adds an enabled cheat, and returns its number
+    pub fn add(&mut self, code: &str, description: &str) -> Result<usize, String> {
+        self.cheats.push(Cheat {
+            code: code.trim().to_uppercase(),
+            decoded: CheatCode::parse(code)?,
+            enabled: true,
+            description: description.trim().to_string(),
+        });
+        Ok(self.cheats.len())
+    }
+
+    /// Cheats are numbered from 1, in the order they were added
+    pub fn get_mut(&mut self, number: usize) -> Option<&mut Cheat> {
+        self.cheats.get_mut(number.checked_sub(1)?)
+    }
+
+    pub fn remove(&mut self, number: usize) -> Option<Cheat> {
+        let index = number.checked_sub(1)?;
+        (index < self.cheats.len()).then(|| self.cheats.remove(index))
+    }
+
+    pub fn list(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    fn enabled(&self) -> impl Iterator<Item = CheatCode> + '_ {
+        self.cheats
+            .iter()
+            .filter(|cheat| cheat.enabled)
+            .map(|cheat| cheat.decoded)
+    }
+
+    /// What the CPU reads from ROM at `address`, where the cartridge has `value`
+    pub fn patch_rom(&self, address: u16, value: u8) -> u8 {
+        self.enabled()
+            .find_map(|code| match code {
+                CheatCode::GameGenie {
+                    address: a,
+                    value: patched,
+                    compare,
+                } if a == address && compare.map_or(true, |c| c == value) => Some(patched),
+                _ => None,
+            })
+            .unwrap_or(value)
+    }
+
+    /// The RAM writes to do at every VBlank
+    pub fn ram_writes(&self) -> Vec<(u16, u8)> {
+        self.enabled()
+            .filter_map(|code| match code {
+                CheatCode::GameShark { address, value } => Some((address, value)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The file the cheats of the game titled `title` are saved to
+    pub fn path(title: &str) -> PathBuf {
+        let mut name: String = title
+            .trim_end_matches('\0')
+            .trim()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        if name.is_empty() {
+            name = "untitled".to_string();
+        }
+        Path::new(CHEATS_DIR).join(format!("{name}.cheats"))
+    }
+
+    /// Reads cheats saved by [`Cheats::save`], one per line: code, `on` or `off`, and the
+    /// description
+    pub fn load(path: &Path) -> Result<Cheats, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let mut cheats = Cheats::default();
+        for (i, line) in text
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !l.trim().is_empty())
+        {
+            let error = |e: String| format!("{}:{}: {e}", path.display(), i + 1);
+            let mut fields = line.splitn(3, ' ');
+            let code = fields.next().unwrap_or_default();
+            let enabled = match fields.next() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Err(error("expected on or off after the code".to_string())),
+            };
+            let number = cheats
+                .add(code, fields.next().unwrap_or_default())
+                .map_err(error)?;
+            cheats.get_mut(number).unwrap().enabled = enabled;
+        }
+        Ok(cheats)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut text = String::new();
+        for cheat in &self.cheats {
+            let state = if cheat.enabled { "on" } else { "off" };
+            let _ = writeln!(text, "{} {} {}", cheat.code, state, cheat.description);
+        }
+        fs::write(path, text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug_interface::DebugCmd;
+    use crate::Gameboy;
+
+    #[test]
+    fn test_parse() {
+        // Writes 0x00 at 0x4A17 where there is 0x3E
+        assert_eq!(
+            CheatCode::parse("00A-17B-1E2"),
+            Ok(CheatCode::GameGenie {
+                address: 0x4A17,
+                value: 0x00,
+                compare: Some(0x3E),
+            })
+        );
+        assert_eq!(
+            CheatCode::parse("3ED-A58"),
+            Ok(CheatCode::GameGenie {
+                address: 0x7DA5,
+                value: 0x3E,
+                compare: None,
+            })
+        );
+        assert_eq!(
+            CheatCode::parse("010238CD"),
+            Ok(CheatCode::GameShark {
+                address: 0xCD38,
+                value: 0x02,
+            })
+        );
+        assert!(CheatCode::parse("020238CD").is_err());
+        assert!(CheatCode::parse("00A-17B-1E").is_err());
+        assert!(CheatCode::parse("XYZ").is_err());
+    }
+
+    #[test]
+    fn test_cheats() {
+        let mut cheats = Cheats::default();
+        assert_eq!(cheats.add("00A-17B-1E2", "no damage"), Ok(1));
+        assert_eq!(cheats.add("01FF38CD", "lives"), Ok(2));
+        assert_eq!(cheats.patch_rom(0x4A17, 0x3E), 0x00);
+        // Another bank, with another byte at the same address
+        assert_eq!(cheats.patch_rom(0x4A17, 0x12), 0x12);
+        assert_eq!(cheats.ram_writes(), [(0xCD38, 0xFF)]);
+
+        cheats.get_mut(1).unwrap().enabled = false;
+        assert_eq!(cheats.patch_rom(0x4A17, 0x3E), 0x3E);
+
+        let path = std::env::temp_dir().join(format!("fpt-{}.cheats", std::process::id()));
+        cheats.save(&path).unwrap();
+        assert_eq!(Cheats::load(&path), Ok(cheats.clone()));
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(cheats.remove(1).unwrap().code, "00A-17B-1E2");
+        assert!(cheats.remove(2).is_none());
+        assert_eq!(cheats.ram_writes(), [(0xCD38, 0xFF)]);
+    }
+
+    #[test]
+    fn test_cheat_commands() {
+        let mut gb = Gameboy::new();
+        let mut run = |command: &str| match DebugCmd::from_string(command) {
+            Ok(command) => gb.debug_cmd(&command).unwrap().to_string(),
+            Err(e) => e,
+        };
+
+        assert_eq!(
+            run("cheat add 01FF10C0 infinite lives"),
+            "Added cheat 1: 01FF10C0 infinite lives\n"
+        );
+        assert_eq!(run("cheat disable 1"), "Disabled cheat 1\n");
+        assert_eq!(run("cheat list"), "1: 01FF10C0 (disabled) infinite lives\n");
+        assert_eq!(run("cheat delete 2"), "Error: no cheat 2\n");
+        assert_eq!(run("cheat delete 1"), "Deleted cheat 1\n");
+        assert_eq!(run("cheat frob"), "unknown cheat command frob");
+        assert_eq!(run("cheat enable"), "missing cheat number");
+    }
+
+    #[test]
+    fn test_path() {
+        assert_eq!(
+            Cheats::path("POKEMON RED\0\0\0\0\0"),
+            Path::new("cheats/POKEMON_RED.cheats")
+        );
+        assert_eq!(Cheats::path("\0\0"), Path::new("cheats/untitled.cheats"));
+    }
+}
@@ -1,41 +1,407 @@
+pub mod expr;
+
 use std::collections::VecDeque;
 use std::fmt;
+use std::ops::RangeInclusive;
 
+use expr::{Condition, Register};
 use num_traits::Num;
 use regex::Regex;
 
-#[derive(Clone, Copy, PartialEq, Debug)]
+use crate::cheats::Cheat;
+use crate::disasm::{self, DecodedInstruction};
+use crate::lr35902::call_stack::{Desync, Frame};
+use crate::memory::search::{Candidate, SearchFilter, SearchWidth};
+
+/// Candidates that `search list` shows at most
+const SEARCH_LIST_MAX: usize = 32;
+
+/// The CPU registers, as seen by the debugger
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Registers {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+/// What breakpoints, watchpoints and instrpoints have in common
+#[derive(Clone, PartialEq, Debug)]
+pub struct DebugPoint {
+    /// Shared by all kinds of debug points, so that `delete 3` is never ambiguous
+    pub id: usize,
+    pub enabled: bool,
+    /// Times the point was reached, whether its condition held or not
+    pub hits: u32,
+    /// Times to let the point go by before stopping
+    pub ignore_count: u32,
+}
+
+impl DebugPoint {
+    pub fn new(id: usize) -> Self {
+        Self {
+            id,
+            enabled: true,
+            hits: 0,
+            ignore_count: 0,
+        }
+    }
+
+    /// Whether to stop at a hit whose condition evaluated to `condition`, using up the
+    /// ignore count first
+    pub fn stops(&mut self, condition: bool) -> bool {
+        if !condition {
+            false
+        } else if self.ignore_count > 0 {
+            self.ignore_count -= 1;
+            false
+        } else {
+            true
+        }
+    }
+}
+
+impl fmt::Display for DebugPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "hits: {}", self.hits)?;
+        if !self.enabled {
+            write!(f, ", disabled")?;
+        }
+        if self.ignore_count > 0 {
+            write!(f, ", ignoring the next {}", self.ignore_count)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct Breakpoint {
+    pub point: DebugPoint,
     pub pc: u16,
-    pub triggered: bool,
+    /// Only break while this ROM bank is switched in
+    pub bank: Option<u16>,
+    /// Only break when this is true
+    pub condition: Option<Condition>,
 }
 
 impl Breakpoint {
-    pub fn new(pc: u16, triggered: bool) -> Self {
-        Self { pc, triggered }
+    pub fn new(id: usize, pc: u16) -> Self {
+        Self {
+            point: DebugPoint::new(id),
+            pc,
+            bank: None,
+            condition: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// Which accesses a watchpoint fires on
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    pub fn matches(self, access: AccessKind) -> bool {
+        matches!(
+            (self, access),
+            (WatchKind::ReadWrite, _)
+                | (WatchKind::Read, AccessKind::Read)
+                | (WatchKind::Write, AccessKind::Write)
+        )
+    }
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::ReadWrite => "read/write",
+        })
     }
 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct Watchpoint {
-    pub addr: u16,
+    pub point: DebugPoint,
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+    pub condition: Option<Condition>,
 }
 
 impl Watchpoint {
-    pub fn new(addr: u16) -> Self {
-        Self { addr }
+    /// Watches writes to `addr`
+    pub fn new(id: usize, addr: u16) -> Self {
+        Self {
+            point: DebugPoint::new(id),
+            range: addr..=addr,
+            kind: WatchKind::Write,
+            condition: None,
+        }
+    }
+
+    pub fn watches(&self, address: u16, access: AccessKind) -> bool {
+        self.point.enabled && self.range.contains(&address) && self.kind.matches(access)
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Debug)]
+/// A memory access that fired a watchpoint
+#[derive(Clone, PartialEq, Debug)]
+pub struct WatchHit {
+    pub id: usize,
+    pub kind: AccessKind,
+    pub address: SymbolicAddress,
+    /// Value before the access. Reads leave it unchanged.
+    pub old: u8,
+    pub new: u8,
+    /// The instruction that accessed memory, or started the OAM DMA
+    pub pc: SymbolicAddress,
+    pub dma: bool,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            AccessKind::Read => write!(f, "read of {}: {:#04X}", self.address, self.new)?,
+            AccessKind::Write => write!(
+                f,
+                "write to {}: {:#04X} -> {:#04X}",
+                self.address, self.old, self.new
+            )?,
+        }
+        if self.dma {
+            write!(f, ", by OAM DMA started at pc={}", self.pc)
+        } else {
+            write!(f, ", at pc={}", self.pc)
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct Instrpoint {
+    pub point: DebugPoint,
     pub opcode: u16,
-    pub triggered: bool,
 }
 
 impl Instrpoint {
-    pub fn new(opcode: u16, triggered: bool) -> Self {
-        Self { opcode, triggered }
+    pub fn new(id: usize, opcode: u16) -> Self {
+        Self {
+            point: DebugPoint::new(id),
+            opcode,
+        }
+    }
+}
+
+/// An address given to a debugger command, either as a number or as a label of the
+/// loaded symbol file
+#[derive(Debug, PartialEq, Clone)]
+pub enum Location {
+    Address(u16),
+    Symbol(String),
+}
+
+impl From<u16> for Location {
+    fn from(address: u16) -> Self {
+        Location::Address(address)
+    }
+}
+
+/// An address along with the closest symbol at or before it, if any
+#[derive(Debug, PartialEq, Clone)]
+pub struct SymbolicAddress {
+    pub address: u16,
+    pub symbol: Option<(String, u16)>,
+}
+
+impl fmt::Display for SymbolicAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#06X}", self.address)?;
+        match &self.symbol {
+            Some((name, 0)) => write!(f, " <{name}>"),
+            Some((name, offset)) => write!(f, " <{name}+{offset}>"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A call stack frame, with its addresses symbolized
+#[derive(Debug, PartialEq, Clone)]
+pub struct BacktraceFrame {
+    pub frame: Frame,
+    pub target: SymbolicAddress,
+    pub return_address: SymbolicAddress,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Backtrace {
+    pub pc: SymbolicAddress,
+    /// From the innermost frame to the outermost one
+    pub frames: Vec<BacktraceFrame>,
+    /// Why the frames may be wrong
+    pub desync: Option<Desync>,
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "#0  {}", self.pc)?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            write!(
+                f,
+                "#{:<2} {} in bank {}, after {} to {}",
+                i + 1,
+                frame.return_address,
+                frame.frame.bank,
+                frame.frame.kind,
+                frame.target
+            )?;
+            match frame.frame.via {
+                Some(via) => writeln!(f, " through JP (HL) at {:#06X}", via)?,
+                None => writeln!(f)?,
+            }
+        }
+        if let Some(desync) = self.desync {
+            writeln!(f, "warning: the call stack may be out of sync: {}", desync)?;
+        }
+        Ok(())
+    }
+}
+
+/// How `x` shows memory
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum ExamineFormat {
+    #[default]
+    Hex,
+    Decimal,
+    /// 16 bit little endian words, in hex
+    Word,
+    Char,
+    Instruction,
+}
+
+impl ExamineFormat {
+    fn from_letter(letter: char) -> Option<Self> {
+        match letter {
+            'x' => Some(ExamineFormat::Hex),
+            'd' => Some(ExamineFormat::Decimal),
+            'w' => Some(ExamineFormat::Word),
+            'c' => Some(ExamineFormat::Char),
+            'i' => Some(ExamineFormat::Instruction),
+            _ => None,
+        }
+    }
+
+    /// Bytes making up each value
+    pub fn unit(self) -> usize {
+        match self {
+            ExamineFormat::Word => 2,
+            _ => 1,
+        }
+    }
+
+    fn per_line(self) -> usize {
+        match self {
+            ExamineFormat::Hex | ExamineFormat::Char => 16,
+            _ => 8,
+        }
+    }
+}
+
+/// Memory shown by `x`
+#[derive(Debug, PartialEq, Clone)]
+pub enum Examined {
+    Memory {
+        start: u16,
+        format: ExamineFormat,
+        bytes: Vec<u8>,
+    },
+    Instructions(Vec<DecodedInstruction>),
+}
+
+impl fmt::Display for Examined {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (start, format, bytes) = match self {
+            Examined::Memory {
+                start,
+                format,
+                bytes,
+            } => (*start, *format, bytes),
+            Examined::Instructions(instructions) => {
+                for inst in instructions {
+                    writeln!(f, "{}", disasm::listing_line(&disasm::Rgbds, inst))?;
+                }
+                return Ok(());
+            }
+        };
+        let line_bytes = format.per_line() * format.unit();
+        for (i, line) in bytes.chunks(line_bytes).enumerate() {
+            let address = start.wrapping_add((i * line_bytes) as u16);
+            write!(f, "{:#06X}:", address)?;
+            match format {
+                ExamineFormat::Char => {
+                    let chars: String = line
+                        .iter()
+                        .map(|&b| {
+                            if b.is_ascii_graphic() || b == b' ' {
+                                b as char
+                            } else {
+                                '.'
+                            }
+                        })
+                        .collect();
+                    write!(f, " {}", chars)?;
+                }
+                ExamineFormat::Word => {
+                    for word in line.chunks(2) {
+                        match word {
+                            [low, high] => write!(f, " {:04X}", u16::from_le_bytes([*low, *high]))?,
+                            _ => write!(f, " {:02X}", word[0])?,
+                        }
+                    }
+                }
+                ExamineFormat::Decimal => {
+                    for b in line {
+                        write!(f, " {:>3}", b)?;
+                    }
+                }
+                _ => {
+                    for b in line {
+                        write!(f, " {:02X}", b)?;
+                    }
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags: String = [(7, 'Z'), (6, 'N'), (5, 'H'), (4, 'C')]
+            .iter()
+            .map(|&(bit, name)| if self.af >> bit & 1 == 1 { name } else { '-' })
+            .collect();
+        writeln!(
+            f,
+            "af: {:#06X}  bc: {:#06X}  de: {:#06X}  hl: {:#06X}",
+            self.af, self.bc, self.de, self.hl
+        )?;
+        writeln!(
+            f,
+            "sp: {:#06X}  pc: {:#06X}  flags: {}",
+            self.sp, self.pc, flags
+        )
     }
 }
 
@@ -43,30 +409,110 @@ impl Instrpoint {
 pub enum DebugCmd {
     Pause,
     Continue,
-    Breakpoint(u16),
-    Watchpoint(u16),
+    Breakpoint(Location, Option<Condition>),
+    Watchpoint {
+        kind: WatchKind,
+        start: Location,
+        /// End of the watched range, exclusive. Only `start` is watched without it.
+        end: Option<Location>,
+        condition: Option<Condition>,
+    },
+    /// Whether watchpoints see the accesses of OAM DMA transfers
+    WatchDma(bool),
     Instrpoint(u16),
     Load(String),
+    Symbols(String),
     ListBreakpoints,
     ListWatchpoints,
-    Print(u16),
+    ListInstrpoints,
+    Delete(usize),
+    Enable(usize),
+    Disable(usize),
+    /// Let a debug point go by this many times
+    Ignore(usize, u32),
+    Print(Location),
+    /// Show `count` values from `start`, or the values from `start` up to `end`, exclusive
+    Examine {
+        format: ExamineFormat,
+        count: usize,
+        start: Location,
+        end: Option<Location>,
+    },
+    Registers,
+    SetRegister(Register, u16),
+    SetMemory(Location, Vec<u8>),
+    /// Start a memory search with all of RAM as candidates
+    SearchStart(SearchWidth),
+    SearchFilter(SearchFilter),
+    SearchList,
+    /// Add a Game Genie or GameShark code, with a description
+    CheatAdd(String, String),
+    CheatList,
+    CheatEnable(usize, bool),
+    CheatDelete(usize),
+    /// Save the cheats to the file of the loaded game
+    CheatSave,
+    CheatLoad,
     Step,
+    /// Step over calls and interrupts
+    Next,
+    /// Run until the current call returns
+    Finish,
+    Until(Location),
+    Backtrace,
+    /// Go back to the previous instruction
+    ReverseStep,
+    /// Go back to the previous breakpoint or watchpoint hit
+    ReverseContinue,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum DebugEvent {
     Pause,
     Continue,
-    RegisterBreakpoint(u16),
-    RegisterWatchpoint(u16),
-    RegisterInstrpoint(u16),
+    RegisterBreakpoint(usize, SymbolicAddress),
+    RegisterWatchpoint(usize, SymbolicAddress),
+    RegisterInstrpoint(usize, u16),
     ListBreakpoints(Vec<Breakpoint>),
     ListWatchpoints(Vec<Watchpoint>),
-    Breakpoint(u16),
-    Watchpoint(u16, u16),
-    Instrpoint(u16),
+    ListInstrpoints(Vec<Instrpoint>),
+    Delete(usize),
+    Enable(usize),
+    Disable(usize),
+    Ignore(usize, u32),
+    Breakpoint(usize, SymbolicAddress),
+    Watchpoint(WatchHit),
+    WatchDma(bool),
+    Instrpoint(usize, u16),
     Print(u8),
+    Examine(Examined),
+    Registers(Registers),
+    SetRegister(Register, u16),
+    /// How many bytes were written, from where
+    SetMemory(SymbolicAddress, usize),
+    SearchCandidates(usize),
+    SearchList(SearchWidth, Vec<Candidate>),
+    CheatAdd(usize, Cheat),
+    CheatList(Vec<Cheat>),
+    CheatEnable(usize, bool),
+    CheatDelete(usize),
+    CheatSave(String),
+    /// How many cheats were loaded, from where
+    CheatLoad(usize, String),
     Step,
+    Next,
+    Finish,
+    Until(SymbolicAddress),
+    /// A step, next, finish or until is done
+    Stop(SymbolicAddress),
+    Backtrace(Backtrace),
+    ReverseStep,
+    ReverseContinue,
+    /// Went back as far as the recorded history goes
+    HistoryStart(SymbolicAddress),
+    LoadRom(String),
+    LoadSymbols(usize),
+    Error(String),
 }
 
 impl fmt::Display for DebugEvent {
@@ -74,104 +520,406 @@ impl fmt::Display for DebugEvent {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             DebugEvent::Continue => writeln!(f, "continue"),
-            DebugEvent::RegisterBreakpoint(pc) => {
-                writeln!(f, "Registered breakpoint at pc={:#04X}", pc)
+            DebugEvent::RegisterBreakpoint(id, pc) => {
+                writeln!(f, "Registered breakpoint {} at pc={}", id, pc)
             }
-            DebugEvent::RegisterWatchpoint(addr) => {
-                writeln!(f, "Registered watchpoint at address {:#04X}", addr)
+            DebugEvent::RegisterWatchpoint(id, addr) => {
+                writeln!(f, "Registered watchpoint {} at address {}", id, addr)
             }
-            DebugEvent::RegisterInstrpoint(opcode) => {
-                writeln!(f, "Registered instrpoint with opcode {:#04X}", opcode)
+            DebugEvent::RegisterInstrpoint(id, opcode) => {
+                writeln!(
+                    f,
+                    "Registered instrpoint {} with opcode {:#04X}",
+                    id, opcode
+                )
             }
             DebugEvent::ListBreakpoints(breakpoints) => {
                 writeln!(f, "breakpoints:")?;
-                for (i, breakpoint) in breakpoints.iter().enumerate() {
-                    writeln!(f, "\t{i}: {:#06X}", breakpoint.pc)?;
+                for breakpoint in breakpoints {
+                    write!(f, "\t{}: {:#06X}", breakpoint.point.id, breakpoint.pc)?;
+                    if let Some(condition) = &breakpoint.condition {
+                        write!(f, " if {condition}")?;
+                    }
+                    writeln!(f, " ({})", breakpoint.point)?;
                 }
                 Ok(())
             }
             DebugEvent::ListWatchpoints(watchpoints) => {
                 writeln!(f, "watchpoints:")?;
-                for (i, watchpoint) in watchpoints.iter().enumerate() {
-                    writeln!(f, "\t{i}: {:#06X}", watchpoint.addr)?;
+                for watchpoint in watchpoints {
+                    let (start, end) = watchpoint.range.clone().into_inner();
+                    let id = watchpoint.point.id;
+                    write!(f, "\t{id}: {} {:#06X}", watchpoint.kind, start)?;
+                    if end != start {
+                        write!(f, "..={:#06X}", end)?;
+                    }
+                    if let Some(condition) = &watchpoint.condition {
+                        write!(f, " if {condition}")?;
+                    }
+                    writeln!(f, " ({})", watchpoint.point)?;
                 }
                 Ok(())
             }
-            DebugEvent::Breakpoint(pc) => {
-                writeln!(f, "Hit breakpoint at {:#06X}", pc)
+            DebugEvent::ListInstrpoints(instrpoints) => {
+                writeln!(f, "instrpoints:")?;
+                for instrpoint in instrpoints {
+                    writeln!(
+                        f,
+                        "\t{}: {:#04X} ({})",
+                        instrpoint.point.id, instrpoint.opcode, instrpoint.point
+                    )?;
+                }
+                Ok(())
             }
-            DebugEvent::Watchpoint(address, value) => {
-                writeln!(
-                    f,
-                    "Hit watchpoint at {:#06X} with value: {:#06X}",
-                    address, value
-                )
+            DebugEvent::Delete(id) => writeln!(f, "Deleted {}", id),
+            DebugEvent::Enable(id) => writeln!(f, "Enabled {}", id),
+            DebugEvent::Disable(id) => writeln!(f, "Disabled {}", id),
+            DebugEvent::Ignore(id, count) => {
+                writeln!(f, "Will ignore the next {} hits of {}", count, id)
             }
-            DebugEvent::Instrpoint(opcode) => {
-                writeln!(f, "Hit instrpoint at {:#06X}", opcode)
+            DebugEvent::Breakpoint(id, pc) => {
+                writeln!(f, "Hit breakpoint {} at {}", id, pc)
+            }
+            DebugEvent::Watchpoint(hit) => {
+                writeln!(f, "Hit watchpoint {} on {}", hit.id, hit)
+            }
+            DebugEvent::WatchDma(true) => writeln!(f, "Watching OAM DMA accesses"),
+            DebugEvent::WatchDma(false) => writeln!(f, "Not watching OAM DMA accesses"),
+            DebugEvent::Instrpoint(id, opcode) => {
+                writeln!(f, "Hit instrpoint {} at {:#06X}", id, opcode)
             }
             DebugEvent::Print(value) => {
                 writeln!(f, "{:#04X}", value)
             }
+            DebugEvent::Examine(examined) => write!(f, "{}", examined),
+            DebugEvent::Registers(registers) => write!(f, "{}", registers),
+            DebugEvent::SetRegister(register, value) => {
+                writeln!(f, "{} = {:#X}", register, value)
+            }
+            DebugEvent::SetMemory(address, count) => {
+                writeln!(f, "Wrote {} bytes at {}", count, address)
+            }
+            DebugEvent::SearchCandidates(count) => writeln!(f, "{} candidates", count),
+            DebugEvent::SearchList(width, candidates) => {
+                for candidate in candidates.iter().take(SEARCH_LIST_MAX) {
+                    let (previous, value) = match width {
+                        SearchWidth::Byte => (
+                            format!("{:#04X}", candidate.previous),
+                            format!("{:#04X}", candidate.value),
+                        ),
+                        SearchWidth::Word => (
+                            format!("{:#06X}", candidate.previous),
+                            format!("{:#06X}", candidate.value),
+                        ),
+                    };
+                    write!(f, "{:#06X}", candidate.address)?;
+                    if let Some(bank) = candidate.bank {
+                        write!(f, " in bank {}", bank)?;
+                    }
+                    writeln!(f, ": {} (was {})", value, previous)?;
+                }
+                if candidates.len() > SEARCH_LIST_MAX {
+                    writeln!(f, "and {} more", candidates.len() - SEARCH_LIST_MAX)?;
+                }
+                Ok(())
+            }
+            DebugEvent::CheatAdd(number, cheat) => writeln!(f, "Added cheat {}: {}", number, cheat),
+            DebugEvent::CheatList(cheats) => {
+                for (i, cheat) in cheats.iter().enumerate() {
+                    writeln!(f, "{}: {}", i + 1, cheat)?;
+                }
+                Ok(())
+            }
+            DebugEvent::CheatEnable(number, true) => writeln!(f, "Enabled cheat {}", number),
+            DebugEvent::CheatEnable(number, false) => writeln!(f, "Disabled cheat {}", number),
+            DebugEvent::CheatDelete(number) => writeln!(f, "Deleted cheat {}", number),
+            DebugEvent::CheatSave(path) => writeln!(f, "Saved cheats to {}", path),
+            DebugEvent::CheatLoad(count, path) => {
+                writeln!(f, "Loaded {} cheats from {}", count, path)
+            }
             DebugEvent::Pause => writeln!(f, "pause"),
             DebugEvent::Step => writeln!(f, "step"),
+            DebugEvent::Next => writeln!(f, "next"),
+            DebugEvent::Finish => writeln!(f, "finish"),
+            DebugEvent::Until(address) => writeln!(f, "until {}", address),
+            DebugEvent::Stop(pc) => writeln!(f, "Stopped at {}", pc),
+            DebugEvent::Backtrace(backtrace) => write!(f, "{}", backtrace),
+            DebugEvent::ReverseStep => writeln!(f, "reverse step"),
+            DebugEvent::ReverseContinue => writeln!(f, "reverse continue"),
+            DebugEvent::HistoryStart(pc) => {
+                writeln!(f, "Reached the start of the recorded history at {}", pc)
+            }
+            DebugEvent::LoadRom(title) => writeln!(f, "Loaded rom {}", title),
+            DebugEvent::LoadSymbols(count) => writeln!(f, "Loaded {} symbols", count),
+            DebugEvent::Error(message) => writeln!(f, "Error: {}", message),
+        }
+    }
+}
+
+fn next_arg<'a>(args: &mut impl Iterator<Item = &'a str>, what: &str) -> Result<&'a str, String> {
+    args.next().ok_or_else(|| format!("missing {what}"))
+}
+
+fn breakpoint_cmd<'a, Args>(args: Args) -> Result<DebugCmd, String>
+where
+    Args: IntoIterator<Item = &'a str>,
+{
+    let mut args = args.into_iter();
+    let location = location(next_arg(&mut args, "address")?)?;
+    Ok(DebugCmd::Breakpoint(location, condition(args)?))
+}
+
+fn watchpoint_cmd<'a, Args>(kind: WatchKind, args: Args) -> Result<DebugCmd, String>
+where
+    Args: IntoIterator<Item = &'a str>,
+{
+    let mut args = args.into_iter();
+    let (start, end) = range(next_arg(&mut args, "address")?)?;
+    Ok(DebugCmd::Watchpoint {
+        kind,
+        start,
+        end,
+        condition: condition(args)?,
+    })
+}
+
+/// Parses an optional `if <expression>` after a debug point's location
+fn condition<'a, Args>(args: Args) -> Result<Option<Condition>, String>
+where
+    Args: IntoIterator<Item = &'a str>,
+{
+    let mut args = args.into_iter();
+    match args.next() {
+        None => Ok(None),
+        Some("if") => {
+            let source: String = args.intersperse(" ").collect();
+            Condition::parse(&source)
+                .map(Some)
+                .map_err(|e| format!("invalid condition: {e}"))
         }
+        Some(other) => Err(format!("expected `if <condition>`, got {other}")),
     }
 }
 
-fn breakpoint_cmd<'a, Args>(args: Args) -> Option<DebugCmd>
+fn print_cmd<'a, Args>(args: Args) -> Result<DebugCmd, String>
 where
     Args: IntoIterator<Item = &'a str>,
 {
-    Some(DebugCmd::Breakpoint(parse::<u16>(
-        args.into_iter().next()?,
-    )?))
+    let mut args = args.into_iter();
+    Ok(DebugCmd::Print(location(next_arg(&mut args, "address")?)?))
 }
 
-fn watchpoint_cmd<'a, Args>(args: Args) -> Option<DebugCmd>
+/// Parses `x/<count><format> <address>` and `x/<format> <start>..<end>`
+fn examine_cmd<'a, Args>(spec: Option<&str>, args: Args) -> Result<DebugCmd, String>
 where
     Args: IntoIterator<Item = &'a str>,
 {
-    Some(DebugCmd::Watchpoint(parse::<u16>(
-        args.into_iter().next()?,
-    )?))
+    let spec = spec.unwrap_or_default();
+    let digits = spec
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(spec.len());
+    let (count, format) = spec.split_at(digits);
+    let count = if count.is_empty() {
+        1
+    } else {
+        parse::<usize>(count)?
+    };
+    let mut letters = format.chars();
+    let format = match (letters.next(), letters.next()) {
+        (None, _) => ExamineFormat::default(),
+        (Some(letter), None) => ExamineFormat::from_letter(letter)
+            .ok_or_else(|| format!("unknown format {letter}, expected one of x, d, w, c, i"))?,
+        _ => return Err(format!("invalid format {format}")),
+    };
+    let mut args = args.into_iter();
+    let (start, end) = range(next_arg(&mut args, "address")?)?;
+    Ok(DebugCmd::Examine {
+        format,
+        count,
+        start,
+        end,
+    })
 }
 
-fn print_cmd<'a, Args>(args: Args) -> Option<DebugCmd>
+fn search_cmd<'a, Args>(args: Args) -> Result<DebugCmd, String>
 where
     Args: IntoIterator<Item = &'a str>,
 {
-    Some(DebugCmd::Print(parse::<u16>(args.into_iter().next()?)?))
+    let mut args = args.into_iter();
+    let filter = match next_arg(&mut args, "new, list or what the value did")? {
+        "new" => {
+            let width = match args.next() {
+                None | Some("8") => SearchWidth::Byte,
+                Some("16") => SearchWidth::Word,
+                Some(other) => return Err(format!("can search 8 or 16 bit values, not {other}")),
+            };
+            return Ok(DebugCmd::SearchStart(width));
+        }
+        "list" => return Ok(DebugCmd::SearchList),
+        "same" => SearchFilter::Same,
+        "changed" => SearchFilter::Changed,
+        "inc" => SearchFilter::Increased,
+        "dec" => SearchFilter::Decreased,
+        value => SearchFilter::Equal(parse::<u16>(value)?),
+    };
+    Ok(DebugCmd::SearchFilter(filter))
 }
 
-fn parse<T>(value: &str) -> Option<T>
+fn cheat_cmd<'a, Args>(args: Args) -> Result<DebugCmd, String>
 where
-    T: Num + std::str::FromStr,
+    Args: IntoIterator<Item = &'a str>,
+{
+    let mut args = args.into_iter();
+    match next_arg(
+        &mut args,
+        "add, list, enable, disable, delete, save or load",
+    )? {
+        "add" => {
+            let code = next_arg(&mut args, "code")?.to_string();
+            Ok(DebugCmd::CheatAdd(code, args.intersperse(" ").collect()))
+        }
+        "list" => Ok(DebugCmd::CheatList),
+        "enable" => Ok(DebugCmd::CheatEnable(
+            parse::<usize>(next_arg(&mut args, "cheat number")?)?,
+            true,
+        )),
+        "disable" => Ok(DebugCmd::CheatEnable(
+            parse::<usize>(next_arg(&mut args, "cheat number")?)?,
+            false,
+        )),
+        "delete" => Ok(DebugCmd::CheatDelete(parse::<usize>(next_arg(
+            &mut args,
+            "cheat number",
+        )?)?)),
+        "save" => Ok(DebugCmd::CheatSave),
+        "load" => Ok(DebugCmd::CheatLoad),
+        other => Err(format!("unknown cheat command {other}")),
+    }
+}
+
+fn set_cmd<'a, Args>(args: Args) -> Result<DebugCmd, String>
+where
+    Args: IntoIterator<Item = &'a str>,
 {
+    let mut args = args.into_iter();
+    match next_arg(&mut args, "reg or mem")? {
+        "reg" => {
+            let name = next_arg(&mut args, "register")?;
+            let register = Register::from_name(&name.to_lowercase())
+                .ok_or_else(|| format!("unknown register {name}"))?;
+            let value = parse::<u16>(next_arg(&mut args, "value")?)?;
+            Ok(DebugCmd::SetRegister(register, value))
+        }
+        "mem" => {
+            let start = location(next_arg(&mut args, "address")?)?;
+            let bytes = args.map(parse::<u8>).collect::<Result<Vec<u8>, String>>()?;
+            if bytes.is_empty() {
+                return Err("missing bytes".to_string());
+            }
+            Ok(DebugCmd::SetMemory(start, bytes))
+        }
+        other => Err(format!("can only set reg or mem, not {other}")),
+    }
+}
+
+/// Parses `<start>..<end>`, or a single location
+fn range(value: &str) -> Result<(Location, Option<Location>), String> {
+    match value.split_once("..") {
+        Some((start, end)) => Ok((location(start)?, Some(location(end)?))),
+        None => Ok((location(value)?, None)),
+    }
+}
+
+/// Parses an address, written as expressions write numbers, like `0xC000`, `$C000` or
+/// `49152`, or a label name like the ones RGBDS accepts
+fn location(value: &str) -> Result<Location, String> {
     let value = value.trim();
-    if value.starts_with("0x") {
-        Some(<T>::from_str_radix(value.strip_prefix("0x").unwrap(), 16).ok()?)
+    let is_label = value.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.@#$".contains(c));
+    if is_label {
+        Ok(Location::Symbol(value.to_string()))
     } else {
-        Some(value.parse::<T>().ok()?)
+        expr::parse_number(value)
+            .and_then(|address| u16::try_from(address).ok())
+            .map(Location::Address)
+            .ok_or_else(|| format!("invalid address {value}"))
     }
 }
 
+fn parse<T>(value: &str) -> Result<T, String>
+where
+    T: Num + std::str::FromStr,
+{
+    let value = value.trim();
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => <T>::from_str_radix(hex, 16).ok(),
+        None => value.parse::<T>().ok(),
+    };
+    parsed.ok_or_else(|| format!("invalid number {value}"))
+}
+
 impl DebugCmd {
-    pub fn from_string(cmd: &str) -> Option<DebugCmd> {
+    pub fn from_string(cmd: &str) -> Result<DebugCmd, String> {
         let re = Regex::new(r#"[^\s"']+|"([^"]*)"|'([^']*)'"#).unwrap();
         let tokens = re.find_iter(cmd).map(|m| m.as_str()).collect::<Vec<&str>>();
-        let mut args = tokens.iter().skip(1).copied();
-        match tokens[0] {
-            "c" | "continue" => Some(DebugCmd::Continue),
+        let Some((&name, args)) = tokens.split_first() else {
+            return Err("empty command".to_string());
+        };
+        let mut args = args.iter().copied();
+        // Only `x` takes a format, as in `x/16x`
+        let (name, spec) = match name.split_once('/') {
+            Some(("x", spec)) => ("x", Some(spec)),
+            _ => (name, None),
+        };
+        match name {
+            "c" | "continue" => Ok(DebugCmd::Continue),
             "b" | "break" | "breakpoint" => breakpoint_cmd(args),
-            "w" | "watch" | "watchpoint" => watchpoint_cmd(args),
-            "lb" | "list_breakpoints" => Some(DebugCmd::ListBreakpoints),
-            "lw" | "list_watchpoints" => Some(DebugCmd::ListWatchpoints),
-            "load" => Some(DebugCmd::Load(args.next().unwrap().to_string())),
+            "w" | "watch" | "watchpoint" => watchpoint_cmd(WatchKind::Write, args),
+            "rw" | "rwatch" => watchpoint_cmd(WatchKind::Read, args),
+            "aw" | "awatch" => watchpoint_cmd(WatchKind::ReadWrite, args),
+            "watch_dma" => match next_arg(&mut args, "on or off")? {
+                "on" => Ok(DebugCmd::WatchDma(true)),
+                "off" => Ok(DebugCmd::WatchDma(false)),
+                other => Err(format!("expected on or off, got {other}")),
+            },
+            "lb" | "list_breakpoints" => Ok(DebugCmd::ListBreakpoints),
+            "lw" | "list_watchpoints" => Ok(DebugCmd::ListWatchpoints),
+            "i" | "instrpoint" => Ok(DebugCmd::Instrpoint(parse::<u16>(next_arg(
+                &mut args, "opcode",
+            )?)?)),
+            "li" | "list_instrpoints" => Ok(DebugCmd::ListInstrpoints),
+            "d" | "delete" => Ok(DebugCmd::Delete(parse::<usize>(next_arg(
+                &mut args, "id",
+            )?)?)),
+            "enable" => Ok(DebugCmd::Enable(parse::<usize>(next_arg(
+                &mut args, "id",
+            )?)?)),
+            "disable" => Ok(DebugCmd::Disable(parse::<usize>(next_arg(
+                &mut args, "id",
+            )?)?)),
+            "ignore" => Ok(DebugCmd::Ignore(
+                parse::<usize>(next_arg(&mut args, "id")?)?,
+                parse::<u32>(next_arg(&mut args, "count")?)?,
+            )),
+            "load" => Ok(DebugCmd::Load(next_arg(&mut args, "path")?.to_string())),
+            "symbols" => Ok(DebugCmd::Symbols(next_arg(&mut args, "path")?.to_string())),
             "p" | "print" => print_cmd(args),
-            "s" | "step" => Some(DebugCmd::Step),
-            "pause" => Some(DebugCmd::Pause),
-            _ => None,
+            "x" => examine_cmd(spec, args),
+            "regs" | "registers" => Ok(DebugCmd::Registers),
+            "set" => set_cmd(args),
+            "search" => search_cmd(args),
+            "cheat" => cheat_cmd(args),
+            "s" | "step" => Ok(DebugCmd::Step),
+            "n" | "next" => Ok(DebugCmd::Next),
+            "f" | "finish" => Ok(DebugCmd::Finish),
+            "u" | "until" => Ok(DebugCmd::Until(location(next_arg(&mut args, "address")?)?)),
+            "bt" | "backtrace" => Ok(DebugCmd::Backtrace),
+            "rs" | "rstep" => Ok(DebugCmd::ReverseStep),
+            "rc" | "rcontinue" => Ok(DebugCmd::ReverseContinue),
+            "pause" => Ok(DebugCmd::Pause),
+            _ => Err(format!("unknown command {name}")),
         }
     }
 }
@@ -182,3 +930,29 @@ pub trait DebugInterface {
     fn set_paused(&mut self, paused: bool);
     fn get_debug_events(&mut self) -> &mut VecDeque<DebugEvent>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location() {
+        assert_eq!(location("0xC000"), Ok(Location::Address(0xC000)));
+        assert_eq!(location("$C000"), Ok(Location::Address(0xC000)));
+        assert_eq!(location("0150"), Ok(Location::Address(150)));
+        assert_eq!(location("100"), Ok(Location::Address(100)));
+        // Hex needs a prefix, so names that happen to be hex are labels
+        assert_eq!(location("c000"), Ok(Location::Symbol("c000".to_string())));
+        assert_eq!(location("Fade"), Ok(Location::Symbol("Fade".to_string())));
+        assert_eq!(
+            location("wCount"),
+            Ok(Location::Symbol("wCount".to_string()))
+        );
+        assert_eq!(
+            location("Main.loop"),
+            Ok(Location::Symbol("Main.loop".to_string()))
+        );
+        assert!(location("12g").is_err());
+        assert!(location("0x10000").is_err());
+    }
+}
@@ -0,0 +1,477 @@
+//! Expressions for conditional breakpoints and watchpoints, like
+//! `a == 0x12 && [hl] > 3`.
+//!
+//! Operators follow C precedence: `||`, `&&`, `|`, `^`, `&`, `==` `!=`,
+//! `<` `<=` `>` `>=`, `+` `-` and the unary `!`, `~`, `-`. `[addr]` reads a byte of memory.
+//! Numbers can be decimal, `0x`/`$` hex or `0b`/`%` binary. Names are registers
+//! (`a`..`l`, `af`..`pc`), flags (`zf`, `nf`, `hf`, `cf`), the switched ROM `bank`, the
+//! `hits` of the debug point, the `value` a watchpoint saw and the `old` one it replaced,
+//! or symbols of the symbol file.
+
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Register {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Flag {
+    Z,
+    N,
+    H,
+    C,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Var {
+    Register(Register),
+    Flag(Flag),
+    Bank,
+    Hits,
+    Value,
+    Old,
+    Symbol(String),
+}
+
+impl Register {
+    pub fn from_name(name: &str) -> Option<Register> {
+        Some(match name {
+            "a" => Register::A,
+            "f" => Register::F,
+            "b" => Register::B,
+            "c" => Register::C,
+            "d" => Register::D,
+            "e" => Register::E,
+            "h" => Register::H,
+            "l" => Register::L,
+            "af" => Register::AF,
+            "bc" => Register::BC,
+            "de" => Register::DE,
+            "hl" => Register::HL,
+            "sp" => Register::SP,
+            "pc" => Register::PC,
+            _ => return None,
+        })
+    }
+
+    /// Whether this is one of the 8 bit halves of a register pair
+    pub fn is_8bit(self) -> bool {
+        matches!(
+            self,
+            Register::A
+                | Register::F
+                | Register::B
+                | Register::C
+                | Register::D
+                | Register::E
+                | Register::H
+                | Register::L
+        )
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Register::A => "a",
+            Register::F => "f",
+            Register::B => "b",
+            Register::C => "c",
+            Register::D => "d",
+            Register::E => "e",
+            Register::H => "h",
+            Register::L => "l",
+            Register::AF => "af",
+            Register::BC => "bc",
+            Register::DE => "de",
+            Register::HL => "hl",
+            Register::SP => "sp",
+            Register::PC => "pc",
+        })
+    }
+}
+
+impl Var {
+    fn from_name(name: &str) -> Var {
+        if let Some(register) = Register::from_name(name) {
+            return Var::Register(register);
+        }
+        match name {
+            "zf" => Var::Flag(Flag::Z),
+            "nf" => Var::Flag(Flag::N),
+            "hf" => Var::Flag(Flag::H),
+            "cf" => Var::Flag(Flag::C),
+            "bank" => Var::Bank,
+            "hits" => Var::Hits,
+            "value" => Var::Value,
+            "old" => Var::Old,
+            _ => Var::Symbol(name.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum UnaryOp {
+    Not,
+    Complement,
+    Negate,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BinaryOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+}
+
+/// Binary operators by precedence, lowest first
+const PRECEDENCE: [&[(&str, BinaryOp)]; 8] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[("|", BinaryOp::BitOr)],
+    &[("^", BinaryOp::BitXor)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("==", BinaryOp::Eq), ("!=", BinaryOp::Ne)],
+    &[
+        ("<=", BinaryOp::Le),
+        (">=", BinaryOp::Ge),
+        ("<", BinaryOp::Lt),
+        (">", BinaryOp::Gt),
+    ],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+];
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Expr {
+    Number(i64),
+    Var(Var),
+    Deref(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+/// What an expression can look at when it is evaluated
+pub trait Context {
+    fn var(&self, var: &Var) -> Result<i64, String>;
+    fn read(&self, address: u16) -> u8;
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, String> {
+        let mut parser = Parser { source, pos: 0 };
+        let expr = parser.binary(0)?;
+        parser.skip_whitespace();
+        if parser.pos < source.len() {
+            return Err(parser.error("unexpected"));
+        }
+        Ok(expr)
+    }
+
+    pub fn eval(&self, context: &dyn Context) -> Result<i64, String> {
+        Ok(match self {
+            Expr::Number(value) => *value,
+            Expr::Var(var) => context.var(var)?,
+            Expr::Deref(address) => context.read(address.eval(context)? as u16) as i64,
+            Expr::Unary(op, expr) => {
+                let value = expr.eval(context)?;
+                match op {
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::Complement => !value,
+                    UnaryOp::Negate => value.wrapping_neg(),
+                }
+            }
+            // Short-circuit, so that `bank == 2 && [hl] == 0` only reads when it matters
+            Expr::Binary(BinaryOp::Or, lhs, rhs) => {
+                (lhs.eval(context)? != 0 || rhs.eval(context)? != 0) as i64
+            }
+            Expr::Binary(BinaryOp::And, lhs, rhs) => {
+                (lhs.eval(context)? != 0 && rhs.eval(context)? != 0) as i64
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(context)?;
+                let rhs = rhs.eval(context)?;
+                match op {
+                    BinaryOp::Or | BinaryOp::And => unreachable!(),
+                    BinaryOp::BitOr => lhs | rhs,
+                    BinaryOp::BitXor => lhs ^ rhs,
+                    BinaryOp::BitAnd => lhs & rhs,
+                    BinaryOp::Eq => (lhs == rhs) as i64,
+                    BinaryOp::Ne => (lhs != rhs) as i64,
+                    BinaryOp::Lt => (lhs < rhs) as i64,
+                    BinaryOp::Le => (lhs <= rhs) as i64,
+                    BinaryOp::Gt => (lhs > rhs) as i64,
+                    BinaryOp::Ge => (lhs >= rhs) as i64,
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                }
+            }
+        })
+    }
+
+    /// Every variable this expression uses
+    pub fn vars(&self) -> Vec<&Var> {
+        match self {
+            Expr::Number(_) => Vec::new(),
+            Expr::Var(var) => vec![var],
+            Expr::Deref(expr) | Expr::Unary(_, expr) => expr.vars(),
+            Expr::Binary(_, lhs, rhs) => {
+                let mut vars = lhs.vars();
+                vars.extend(rhs.vars());
+                vars
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    fn error(&self, what: &str) -> String {
+        match self.rest().chars().next() {
+            Some(c) => format!("{what} '{c}' at column {}", self.pos + 1),
+            None => format!("{what} end of expression"),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.pos = self.source.len() - self.rest().trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        // `|` and `&` must not eat the first half of `||` and `&&`
+        let doubled = token.len() == 1
+            && "|&".contains(token)
+            && self
+                .rest()
+                .get(1..)
+                .is_some_and(|rest| rest.starts_with(token));
+        if self.rest().starts_with(token) && !doubled {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        'operators: loop {
+            for (token, op) in PRECEDENCE[level] {
+                if self.eat(token) {
+                    let rhs = self.binary(level + 1)?;
+                    lhs = Expr::Binary(*op, Box::new(lhs), Box::new(rhs));
+                    continue 'operators;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let op = if self.eat("!") {
+            UnaryOp::Not
+        } else if self.eat("~") {
+            UnaryOp::Complement
+        } else if self.eat("-") {
+            UnaryOp::Negate
+        } else {
+            return self.primary();
+        };
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        if self.eat("(") {
+            let expr = self.binary(0)?;
+            return if self.eat(")") {
+                Ok(expr)
+            } else {
+                Err(self.error("expected ')' instead of"))
+            };
+        }
+        if self.eat("[") {
+            let expr = self.binary(0)?;
+            return if self.eat("]") {
+                Ok(Expr::Deref(Box::new(expr)))
+            } else {
+                Err(self.error("expected ']' instead of"))
+            };
+        }
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || "_.@#$%".contains(c)))
+            .unwrap_or(rest.len());
+        let word = &rest[..len];
+        let first = word.chars().next();
+        let expr = if first.is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.') {
+            Expr::Var(Var::from_name(word))
+        } else {
+            Expr::Number(parse_number(word).ok_or_else(|| self.error("unexpected"))?)
+        };
+        self.pos += len;
+        Ok(expr)
+    }
+}
+
+pub(crate) fn parse_number(word: &str) -> Option<i64> {
+    let (digits, radix) = if let Some(hex) = word.strip_prefix("0x").or(word.strip_prefix('$')) {
+        (hex, 16)
+    } else if let Some(bin) = word.strip_prefix("0b").or(word.strip_prefix('%')) {
+        (bin, 2)
+    } else {
+        (word, 10)
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
+
+/// A parsed expression that remembers how it was written
+#[derive(Clone, PartialEq, Debug)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Condition, String> {
+        Ok(Condition {
+            source: source.trim().to_string(),
+            expr: Expr::parse(source)?,
+        })
+    }
+
+    pub fn expr(&self) -> &Expr {
+        &self.expr
+    }
+
+    pub fn is_true(&self, context: &dyn Context) -> Result<bool, String> {
+        Ok(self.expr.eval(context)? != 0)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestContext;
+
+    impl Context for TestContext {
+        fn var(&self, var: &Var) -> Result<i64, String> {
+            match var {
+                Var::Register(Register::A) => Ok(0x12),
+                Var::Register(Register::HL) => Ok(0xC000),
+                Var::Flag(Flag::Z) => Ok(1),
+                Var::Hits => Ok(3),
+                Var::Symbol(name) if name == "wCount" => Ok(0xC001),
+                _ => Err(format!("{var:?} is not defined")),
+            }
+        }
+
+        fn read(&self, address: u16) -> u8 {
+            (address & 0xFF) as u8 + 4
+        }
+    }
+
+    fn eval(source: &str) -> Result<i64, String> {
+        Expr::parse(source)?.eval(&TestContext)
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(
+            Expr::parse("a == 1 || b < 2 && c").unwrap(),
+            Expr::Binary(
+                BinaryOp::Or,
+                Box::new(Expr::Binary(
+                    BinaryOp::Eq,
+                    Box::new(Expr::Var(Var::Register(Register::A))),
+                    Box::new(Expr::Number(1)),
+                )),
+                Box::new(Expr::Binary(
+                    BinaryOp::And,
+                    Box::new(Expr::Binary(
+                        BinaryOp::Lt,
+                        Box::new(Expr::Var(Var::Register(Register::B))),
+                        Box::new(Expr::Number(2)),
+                    )),
+                    Box::new(Expr::Var(Var::Register(Register::C))),
+                )),
+            )
+        );
+        assert_eq!(eval("1 + 2 == 3"), Ok(1));
+        assert_eq!(eval("0x10 | 0x01 & 0x03"), Ok(0x11));
+        assert_eq!(eval("5 - 3 - 1"), Ok(1));
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(eval("a == 0x12 && [hl] > 3"), Ok(1));
+        assert_eq!(eval("a == $12 && [hl + 1] == 5"), Ok(1));
+        assert_eq!(eval("[wCount] == %101"), Ok(1));
+        assert_eq!(eval("!zf || hits >= 10"), Ok(0));
+        assert_eq!(eval("-(a) & ~0"), Ok(-0x12));
+        // The right hand side of `||` is never evaluated
+        assert_eq!(eval("zf || sp"), Ok(1));
+        assert!(eval("sp == 0").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Expr::parse("a == "),
+            Err("unexpected end of expression".to_string())
+        );
+        assert_eq!(
+            Expr::parse("[hl"),
+            Err("expected ']' instead of end of expression".to_string())
+        );
+        assert_eq!(
+            Expr::parse("a == 1 )"),
+            Err("unexpected ')' at column 8".to_string())
+        );
+        assert_eq!(
+            Expr::parse("a == 0xZZ"),
+            Err("unexpected '0' at column 6".to_string())
+        );
+    }
+}
@@ -1,16 +1,64 @@
+pub mod symbols;
+
+use std::cell::RefCell;
 use std::collections::VecDeque;
 
-use crate::debug_interface::{Breakpoint, DebugCmd, DebugEvent, Instrpoint, Watchpoint};
-use crate::memory::Bus;
+use symbols::SymbolTable;
+
+use crate::debug_interface::expr::{Condition, Context, Flag, Register, Var};
+use crate::debug_interface::{
+    AccessKind, Backtrace, BacktraceFrame, Breakpoint, DebugCmd, DebugEvent, DebugPoint,
+    ExamineFormat, Examined, Instrpoint, Location, Registers, SymbolicAddress, WatchHit,
+    Watchpoint,
+};
+use crate::disasm;
+use crate::lr35902::call_stack::CallStack;
+use crate::memory::search::MemorySearch;
+use crate::memory::{map, Bus};
+
+/// A memory access to a watched address, waiting for its instruction to end
+#[derive(Clone, PartialEq, Debug)]
+struct Access {
+    kind: AccessKind,
+    address: u16,
+    old: u8,
+    new: u8,
+    dma: bool,
+}
+
+/// How far to run before pausing again
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Stepping {
+    Instruction,
+    /// Until back at this call depth or above, running through calls and interrupts
+    Over(usize),
+    /// Until returned from this call depth
+    Out(usize),
+    Until(u16),
+}
 
 #[derive(Clone, PartialEq)]
 pub struct Debugger {
     breakpoints: Vec<Breakpoint>,
     watchpoints: Vec<Watchpoint>,
+    /// Recorded from `&self` because the CPU reads memory through shared references
+    accesses: RefCell<Vec<Access>>,
+    watch_dma: bool,
     instrpoints: Vec<Instrpoint>,
+    next_id: usize,
     pub paused: bool,
-    pub step: bool,
+    /// Set when resuming, so that the debug points we stopped at don't fire again
+    resuming: bool,
+    /// Ids of the breakpoints and instrpoints checked before the instruction it stopped
+    /// at, which aren't checked again when resuming there
+    checked: Vec<usize>,
+    /// Set while replaying history, which must not stop or step
+    muted: bool,
+    stepping: Option<Stepping>,
     dbg_events: VecDeque<DebugEvent>,
+    symbols: SymbolTable,
+    /// The memory search going on, if any
+    search: Option<MemorySearch>,
     bus: Bus,
 }
 
@@ -19,41 +67,106 @@ impl Debugger {
         Self {
             breakpoints: Vec::new(),
             watchpoints: Vec::new(),
+            accesses: RefCell::new(Vec::new()),
+            watch_dma: false,
             instrpoints: Vec::new(),
+            next_id: 1,
             paused: false,
-            step: false,
+            resuming: false,
+            checked: Vec::new(),
+            muted: false,
+            stepping: None,
             dbg_events: VecDeque::new(),
+            symbols: SymbolTable::default(),
+            search: None,
             bus,
         }
     }
 
-    pub fn receive_command(&mut self, cmd: &DebugCmd) -> Option<DebugEvent> {
+    pub fn receive_command(
+        &mut self,
+        cmd: &DebugCmd,
+        registers: &Registers,
+        call_stack: &CallStack,
+    ) -> Option<DebugEvent> {
         match cmd {
             DebugCmd::Pause => {
                 self.paused = true;
+                self.stepping = None;
                 Some(DebugEvent::Pause)
             }
             DebugCmd::Continue => {
-                self.paused = false;
+                self.set_paused(false);
+                self.stepping = None;
                 Some(DebugEvent::Continue)
             }
-            DebugCmd::Breakpoint(pc) => {
+            DebugCmd::Breakpoint(location, condition) => {
+                let (pc, bank) = match self.resolve(location) {
+                    Ok(resolved) => resolved,
+                    Err(error) => return Some(error),
+                };
+                if let Err(error) = self.check_condition(condition, false) {
+                    return Some(error);
+                }
+                let point = self.new_point();
+                let id = point.id;
                 self.breakpoints.push(Breakpoint {
-                    pc: *pc,
-                    triggered: false,
+                    point,
+                    pc,
+                    bank,
+                    condition: condition.clone(),
                 });
-                Some(DebugEvent::RegisterBreakpoint(*pc))
+                Some(DebugEvent::RegisterBreakpoint(
+                    id,
+                    self.symbolic_address(pc, bank),
+                ))
             }
-            DebugCmd::Watchpoint(addr) => {
-                self.watchpoints.push(Watchpoint { addr: *addr });
-                Some(DebugEvent::RegisterWatchpoint(*addr))
+            DebugCmd::Watchpoint {
+                kind,
+                start,
+                end,
+                condition,
+            } => {
+                let start = match self.resolve(start) {
+                    Ok((start, _)) => start,
+                    Err(error) => return Some(error),
+                };
+                let last = match end.as_ref().map(|end| self.resolve(end)) {
+                    None => start,
+                    Some(Ok((end, _))) if end > start => end - 1,
+                    Some(Ok(_)) => {
+                        return Some(DebugEvent::Error("empty watchpoint range".to_string()))
+                    }
+                    Some(Err(error)) => return Some(error),
+                };
+                if let Err(error) = self.check_condition(condition, true) {
+                    return Some(error);
+                }
+                let point = self.new_point();
+                let id = point.id;
+                self.watchpoints.push(Watchpoint {
+                    point,
+                    range: start..=last,
+                    kind: *kind,
+                    condition: condition.clone(),
+                });
+                Some(DebugEvent::RegisterWatchpoint(
+                    id,
+                    self.symbolic_address(start, None),
+                ))
+            }
+            DebugCmd::WatchDma(watch_dma) => {
+                self.watch_dma = *watch_dma;
+                Some(DebugEvent::WatchDma(*watch_dma))
             }
             DebugCmd::Instrpoint(instruction) => {
+                let point = self.new_point();
+                let id = point.id;
                 self.instrpoints.push(Instrpoint {
+                    point,
                     opcode: *instruction,
-                    triggered: false,
                 });
-                Some(DebugEvent::RegisterInstrpoint(*instruction))
+                Some(DebugEvent::RegisterInstrpoint(id, *instruction))
             }
             DebugCmd::ListBreakpoints => {
                 Some(DebugEvent::ListBreakpoints(self.breakpoints.clone()))
@@ -61,58 +174,548 @@ impl Debugger {
             DebugCmd::ListWatchpoints => {
                 Some(DebugEvent::ListWatchpoints(self.watchpoints.clone()))
             }
-            DebugCmd::Load(_) => None,
-            DebugCmd::Print(addr) => Some(DebugEvent::Print(self.bus.read(*addr as usize))),
+            DebugCmd::ListInstrpoints => {
+                Some(DebugEvent::ListInstrpoints(self.instrpoints.clone()))
+            }
+            DebugCmd::Delete(id) => {
+                let count =
+                    self.breakpoints.len() + self.watchpoints.len() + self.instrpoints.len();
+                self.breakpoints.retain(|b| b.point.id != *id);
+                self.watchpoints.retain(|w| w.point.id != *id);
+                self.instrpoints.retain(|i| i.point.id != *id);
+                let deleted = count
+                    != self.breakpoints.len() + self.watchpoints.len() + self.instrpoints.len();
+                Some(if deleted {
+                    DebugEvent::Delete(*id)
+                } else {
+                    no_point(*id)
+                })
+            }
+            DebugCmd::Enable(id) => match self.point_mut(*id) {
+                Some(point) => {
+                    point.enabled = true;
+                    Some(DebugEvent::Enable(*id))
+                }
+                None => Some(no_point(*id)),
+            },
+            DebugCmd::Disable(id) => match self.point_mut(*id) {
+                Some(point) => {
+                    point.enabled = false;
+                    Some(DebugEvent::Disable(*id))
+                }
+                None => Some(no_point(*id)),
+            },
+            DebugCmd::Ignore(id, count) => match self.point_mut(*id) {
+                Some(point) => {
+                    point.ignore_count = *count;
+                    Some(DebugEvent::Ignore(*id, *count))
+                }
+                None => Some(no_point(*id)),
+            },
+            DebugCmd::Load(path) => match std::fs::read(path) {
+                Ok(rom) => {
+                    self.bus.load_rom(&rom);
+                    Some(DebugEvent::LoadRom(path.clone()))
+                }
+                Err(e) => Some(DebugEvent::Error(format!("{path}: {e}"))),
+            },
+            DebugCmd::Symbols(path) => match SymbolTable::load(path) {
+                Ok(symbols) => {
+                    self.symbols = symbols;
+                    Some(DebugEvent::LoadSymbols(self.symbols.len()))
+                }
+                Err(e) => Some(DebugEvent::Error(e)),
+            },
+            DebugCmd::Print(location) => match self.resolve(location) {
+                Ok((addr, _)) => Some(DebugEvent::Print(self.bus.read(addr as usize))),
+                Err(error) => Some(error),
+            },
+            DebugCmd::Examine {
+                format,
+                count,
+                start,
+                end,
+            } => Some(
+                self.examine(*format, *count, start, end.as_ref())
+                    .unwrap_or_else(|error| error),
+            ),
+            DebugCmd::Registers => Some(DebugEvent::Registers(*registers)),
+            // Handled by the CPU, which owns the registers
+            DebugCmd::SetRegister(..) => Some(DebugEvent::Error("no registers to set".to_string())),
+            DebugCmd::SetMemory(location, bytes) => match self.resolve(location) {
+                Ok((address, bank)) => {
+                    if address as usize + bytes.len() > 0x10000 {
+                        return Some(DebugEvent::Error(
+                            "writing past the end of memory".to_string(),
+                        ));
+                    }
+                    for (i, byte) in bytes.iter().enumerate() {
+                        self.bus.write(address as usize + i, *byte);
+                    }
+                    Some(DebugEvent::SetMemory(
+                        self.symbolic_address(address, bank),
+                        bytes.len(),
+                    ))
+                }
+                Err(error) => Some(error),
+            },
+            DebugCmd::SearchStart(width) => {
+                let search = self.bus.memsearch(*width);
+                let count = search.candidates().len();
+                self.search = Some(search);
+                Some(DebugEvent::SearchCandidates(count))
+            }
+            DebugCmd::SearchFilter(filter) => match &mut self.search {
+                Some(search) => {
+                    search.filter(&self.bus, *filter);
+                    Some(DebugEvent::SearchCandidates(search.candidates().len()))
+                }
+                None => Some(no_search()),
+            },
+            DebugCmd::SearchList => match &self.search {
+                Some(search) => Some(DebugEvent::SearchList(
+                    search.width(),
+                    search.candidates().to_vec(),
+                )),
+                None => Some(no_search()),
+            },
+            DebugCmd::CheatAdd(code, description) => {
+                let mut memory = self.bus.memory_mut();
+                match memory.cheats.add(code, description) {
+                    Ok(number) => Some(DebugEvent::CheatAdd(
+                        number,
+                        memory.cheats.list()[number - 1].clone(),
+                    )),
+                    Err(e) => Some(DebugEvent::Error(e)),
+                }
+            }
+            DebugCmd::CheatList => Some(DebugEvent::CheatList(
+                self.bus.memory().cheats.list().to_vec(),
+            )),
+            DebugCmd::CheatEnable(number, enabled) => {
+                match self.bus.memory_mut().cheats.get_mut(*number) {
+                    Some(cheat) => {
+                        cheat.enabled = *enabled;
+                        Some(DebugEvent::CheatEnable(*number, *enabled))
+                    }
+                    None => Some(no_cheat(*number)),
+                }
+            }
+            DebugCmd::CheatDelete(number) => match self.bus.memory_mut().cheats.remove(*number) {
+                Some(_) => Some(DebugEvent::CheatDelete(*number)),
+                None => Some(no_cheat(*number)),
+            },
+            DebugCmd::CheatSave => match self.bus.save_cheats() {
+                Ok(path) => Some(DebugEvent::CheatSave(path.display().to_string())),
+                Err(e) => Some(DebugEvent::Error(e.to_string())),
+            },
+            DebugCmd::CheatLoad => match self.bus.load_cheats() {
+                Ok(count) => Some(DebugEvent::CheatLoad(
+                    count,
+                    self.bus.cheats_path().display().to_string(),
+                )),
+                Err(e) => Some(DebugEvent::Error(e)),
+            },
             DebugCmd::Step => {
-                self.paused = false;
-                self.step = true;
+                self.resume(Stepping::Instruction);
                 Some(DebugEvent::Step)
             }
+            DebugCmd::Next => {
+                self.resume(Stepping::Over(call_stack.depth()));
+                Some(DebugEvent::Next)
+            }
+            DebugCmd::Finish => {
+                if call_stack.depth() == 0 {
+                    return Some(DebugEvent::Error("not in a call".to_string()));
+                }
+                self.resume(Stepping::Out(call_stack.depth()));
+                Some(DebugEvent::Finish)
+            }
+            DebugCmd::Until(location) => match self.resolve(location) {
+                Ok((address, bank)) => {
+                    self.resume(Stepping::Until(address));
+                    Some(DebugEvent::Until(self.symbolic_address(address, bank)))
+                }
+                Err(error) => Some(error),
+            },
+            DebugCmd::Backtrace => Some(DebugEvent::Backtrace(
+                self.backtrace(registers.pc, call_stack),
+            )),
+            // Handled by `Gameboy`, which has the history
+            DebugCmd::ReverseStep | DebugCmd::ReverseContinue => Some(DebugEvent::Error(
+                "no history recorded to go back in".to_string(),
+            )),
         }
     }
 
-    pub fn match_instrpoint(&mut self, opcode: u16) -> bool {
-        let instrpoint = self.instrpoints.iter_mut().find(|i| i.opcode == opcode);
-        let is_instrpoint = instrpoint.is_some();
+    /// Reads `count` values from `start` for `x`, or the values up to `end`
+    fn examine(
+        &self,
+        format: ExamineFormat,
+        count: usize,
+        start: &Location,
+        end: Option<&Location>,
+    ) -> Result<DebugEvent, DebugEvent> {
+        let (start, _) = self.resolve(start)?;
+        let end = match end {
+            Some(end) => {
+                let (end, _) = self.resolve(end)?;
+                if end <= start {
+                    return Err(DebugEvent::Error(format!(
+                        "{end:#06X} is not after {start:#06X}"
+                    )));
+                }
+                Some(end as usize)
+            }
+            None => None,
+        };
 
-        let triggered = false;
+        if format == ExamineFormat::Instruction {
+            let mut instructions = Vec::new();
+            let mut address = start as usize;
+            while address < end.unwrap_or(0x10000) && (end.is_some() || instructions.len() < count)
+            {
+                let inst = disasm::decode(address as u16, |a| self.bus.read(a as usize));
+                address += inst.size() as usize;
+                instructions.push(inst);
+            }
+            return Ok(DebugEvent::Examine(Examined::Instructions(instructions)));
+        }
 
-        if instrpoint.is_some() {
-            let instrpoint = instrpoint.unwrap();
-            if instrpoint.triggered {
-                instrpoint.triggered = false;
-            } else {
-                instrpoint.triggered = true;
-                self.paused = true;
+        let end = end
+            .unwrap_or_else(|| (start as usize).saturating_add(count.saturating_mul(format.unit())))
+            .min(0x10000);
+        Ok(DebugEvent::Examine(Examined::Memory {
+            start,
+            format,
+            bytes: (start as usize..end).map(|a| self.bus.read(a)).collect(),
+        }))
+    }
+
+    pub fn backtrace(&self, pc: u16, call_stack: &CallStack) -> Backtrace {
+        let frames = call_stack
+            .frames()
+            .iter()
+            .rev()
+            .map(|frame| BacktraceFrame {
+                frame: *frame,
+                target: self.symbolic_address(frame.target, Some(frame.bank)),
+                return_address: self.symbolic_address(frame.return_address, Some(frame.bank)),
+            })
+            .collect();
+        Backtrace {
+            pc: self.symbolic_address(pc, None),
+            frames,
+            desync: call_stack.desync(),
+        }
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        if self.paused && !paused {
+            self.resuming = true;
+        }
+        self.paused = paused;
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    /// Forgets how the emulator was running, after going back in its history
+    pub fn rewind(&mut self) {
+        self.paused = false;
+        self.resuming = false;
+        self.checked.clear();
+        self.stepping = None;
+        self.accesses.take();
+        self.dbg_events.clear();
+    }
+
+    fn resume(&mut self, stepping: Stepping) {
+        self.set_paused(false);
+        self.stepping = Some(stepping);
+    }
+
+    fn new_point(&mut self) -> DebugPoint {
+        self.next_id += 1;
+        DebugPoint::new(self.next_id - 1)
+    }
+
+    fn point_mut(&mut self, id: usize) -> Option<&mut DebugPoint> {
+        self.breakpoints
+            .iter_mut()
+            .map(|b| &mut b.point)
+            .chain(self.watchpoints.iter_mut().map(|w| &mut w.point))
+            .chain(self.instrpoints.iter_mut().map(|i| &mut i.point))
+            .find(|point| point.id == id)
+    }
+
+    /// Pauses if a step, next, finish or until is done, now that the CPU is at `pc` with
+    /// `depth` frames in its call stack
+    pub fn end_instruction(&mut self, pc: u16, depth: usize) {
+        if self.muted {
+            return;
+        }
+        let done = match self.stepping {
+            None => return,
+            Some(Stepping::Instruction) => true,
+            Some(Stepping::Over(start)) => depth <= start,
+            Some(Stepping::Out(start)) => depth < start,
+            Some(Stepping::Until(address)) => pc == address,
+        };
+        if done {
+            self.stepping = None;
+            self.paused = true;
+            self.dbg_events
+                .push_back(DebugEvent::Stop(self.symbolic_address(pc, None)));
+        }
+    }
+
+    /// The address `location` refers to, and the ROM bank it lives in if it is a label in
+    /// switchable ROM
+    fn resolve(&self, location: &Location) -> Result<(u16, Option<u16>), DebugEvent> {
+        match location {
+            Location::Address(address) => Ok((*address, None)),
+            Location::Symbol(name) => {
+                let symbol = self
+                    .symbols
+                    .get(name)
+                    .ok_or_else(|| DebugEvent::Error(format!("unknown symbol {name}")))?;
+                let bank = map::ROM_BANK1
+                    .contains(&(symbol.address as usize))
+                    .then_some(symbol.bank);
+                Ok((symbol.address, bank))
             }
         }
+    }
 
-        if self.paused {
-            self.dbg_events.push_back(DebugEvent::Instrpoint(opcode));
+    /// Rejects conditions that could never be evaluated
+    fn check_condition(
+        &self,
+        condition: &Option<Condition>,
+        is_watchpoint: bool,
+    ) -> Result<(), DebugEvent> {
+        let Some(condition) = condition else {
+            return Ok(());
+        };
+        for var in condition.expr().vars() {
+            match var {
+                Var::Symbol(name) if self.symbols.get(name).is_none() => {
+                    return Err(DebugEvent::Error(format!("unknown symbol {name}")));
+                }
+                Var::Value | Var::Old if !is_watchpoint => {
+                    return Err(DebugEvent::Error(
+                        "only watchpoints have a value".to_string(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Names `address` after the closest symbol before it, looked up in `bank` or in
+    /// the ROM bank currently switched in
+    pub fn symbolic_address(&self, address: u16, bank: Option<u16>) -> SymbolicAddress {
+        let bank = bank.unwrap_or_else(|| self.bus.rom_bank());
+        SymbolicAddress {
+            address,
+            symbol: self
+                .symbols
+                .symbolize(address, bank)
+                .map(|(symbol, offset)| (symbol.name.clone(), offset)),
         }
+    }
 
-        is_instrpoint && !triggered
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
     }
 
-    pub fn match_breakpoint(&mut self, pc: u16) -> bool {
-        let breakpoint = self.breakpoints.iter_mut().find(|b| b.pc == pc);
+    pub fn search(&self) -> Option<&MemorySearch> {
+        self.search.as_ref()
+    }
 
-        let mut pc = 0;
-        if breakpoint.is_some() {
-            let breakpoint = breakpoint.unwrap();
-            pc = breakpoint.pc;
-            if breakpoint.triggered {
-                breakpoint.triggered = false;
-            } else {
-                breakpoint.triggered = true;
+    /// Evaluates `condition` outside of any debug point
+    pub fn evaluate(&self, condition: &Condition, registers: &Registers) -> Result<bool, String> {
+        condition.is_true(&PointContext {
+            registers,
+            bus: &self.bus,
+            symbols: &self.symbols,
+            hits: 0,
+            value: None,
+            old: None,
+        })
+    }
+
+    /// Checks the breakpoints and instrpoints before running the instruction at
+    /// `registers.pc`. Returns whether to stay paused instead.
+    pub fn match_points(&mut self, registers: &Registers, opcode: u16) -> bool {
+        if self.muted {
+            return false;
+        }
+        if self.paused {
+            return true;
+        }
+        let skip = if std::mem::take(&mut self.resuming) {
+            std::mem::take(&mut self.checked)
+        } else {
+            Vec::new()
+        };
+        self.checked.clear();
+        let paused =
+            self.match_breakpoints(registers, &skip) || self.match_instrpoints(opcode, &skip);
+        if !paused {
+            self.checked.clear();
+        }
+        paused
+    }
+
+    /// Checks every enabled instrpoint on `opcode` but the ones in `skip`
+    fn match_instrpoints(&mut self, opcode: u16, skip: &[usize]) -> bool {
+        for instrpoint in self.instrpoints.iter_mut() {
+            let point = &mut instrpoint.point;
+            if !point.enabled || instrpoint.opcode != opcode || skip.contains(&point.id) {
+                continue;
+            }
+            point.hits += 1;
+            self.checked.push(point.id);
+            if point.stops(true) {
                 self.paused = true;
+                self.stepping = None;
+                self.dbg_events
+                    .push_back(DebugEvent::Instrpoint(point.id, opcode));
             }
         }
+        self.paused
+    }
 
-        if self.paused {
-            self.dbg_events.push_back(DebugEvent::Breakpoint(pc));
+    /// Checks every enabled breakpoint at `registers.pc` but the ones in `skip`
+    fn match_breakpoints(&mut self, registers: &Registers, skip: &[usize]) -> bool {
+        let rom_bank = self.bus.rom_bank();
+        let mut stopped = Vec::new();
+        for breakpoint in self.breakpoints.iter_mut() {
+            let matches = breakpoint.point.enabled
+                && breakpoint.pc == registers.pc
+                && breakpoint.bank.map_or(true, |bank| bank == rom_bank);
+            if !matches || skip.contains(&breakpoint.point.id) {
+                continue;
+            }
+
+            breakpoint.point.hits += 1;
+            self.checked.push(breakpoint.point.id);
+            let context = PointContext {
+                registers,
+                bus: &self.bus,
+                symbols: &self.symbols,
+                hits: breakpoint.point.hits,
+                value: None,
+                old: None,
+            };
+            let condition = match &breakpoint.condition {
+                None => true,
+                Some(condition) => condition.is_true(&context).unwrap_or_else(|e| {
+                    // Better to stop than to silently run past it
+                    self.dbg_events.push_back(DebugEvent::Error(e));
+                    true
+                }),
+            };
+            if breakpoint.point.stops(condition) {
+                stopped.push(breakpoint.point.id);
+            }
+        }
+        for id in stopped {
+            self.paused = true;
+            self.stepping = None;
+            self.dbg_events.push_back(DebugEvent::Breakpoint(
+                id,
+                self.symbolic_address(registers.pc, None),
+            ));
+        }
+        self.paused
+    }
+
+    pub fn is_watched(&self, address: u16, kind: AccessKind) -> bool {
+        !self.muted && self.watchpoints.iter().any(|w| w.watches(address, kind))
+    }
+
+    pub fn watches_dma(&self) -> bool {
+        self.watch_dma && !self.watchpoints.is_empty()
+    }
+
+    /// Records a read of `address` for [`Debugger::match_watchpoints`]
+    pub fn watch_read(&self, address: u16, value: u8, dma: bool) {
+        if self.is_watched(address, AccessKind::Read) {
+            self.accesses.borrow_mut().push(Access {
+                kind: AccessKind::Read,
+                address,
+                old: value,
+                new: value,
+                dma,
+            });
+        }
+    }
+
+    /// Records a write to `address` for [`Debugger::match_watchpoints`]
+    pub fn watch_write(&self, address: u16, old: u8, new: u8, dma: bool) {
+        if self.is_watched(address, AccessKind::Write) {
+            self.accesses.borrow_mut().push(Access {
+                kind: AccessKind::Write,
+                address,
+                old,
+                new,
+                dma,
+            });
+        }
+    }
+
+    /// Checks the accesses recorded while running the instruction at `pc` against the
+    /// watchpoints, pausing if any fired. Runs once the instruction is done, so that
+    /// execution stops at an instruction boundary.
+    pub fn match_watchpoints(&mut self, registers: &Registers, pc: u16) -> bool {
+        let accesses = self.accesses.take();
+        let mut hits = Vec::new();
+        for access in accesses {
+            for watchpoint in self
+                .watchpoints
+                .iter_mut()
+                .filter(|w| w.watches(access.address, access.kind))
+            {
+                watchpoint.point.hits += 1;
+                let context = PointContext {
+                    registers,
+                    bus: &self.bus,
+                    symbols: &self.symbols,
+                    hits: watchpoint.point.hits,
+                    value: Some(access.new),
+                    old: Some(access.old),
+                };
+                let condition = match &watchpoint.condition {
+                    None => true,
+                    Some(condition) => condition.is_true(&context).unwrap_or_else(|e| {
+                        self.dbg_events.push_back(DebugEvent::Error(e));
+                        true
+                    }),
+                };
+                if watchpoint.point.stops(condition) {
+                    hits.push((watchpoint.point.id, access.clone()));
+                }
+            }
         }
 
+        for (id, access) in hits {
+            self.paused = true;
+            self.stepping = None;
+            let hit = WatchHit {
+                id,
+                kind: access.kind,
+                address: self.symbolic_address(access.address, None),
+                old: access.old,
+                new: access.new,
+                pc: self.symbolic_address(pc, None),
+                dma: access.dma,
+            };
+            self.dbg_events.push_back(DebugEvent::Watchpoint(hit));
+        }
         self.paused
     }
 
@@ -120,3 +723,76 @@ impl Debugger {
         &mut self.dbg_events
     }
 }
+
+fn no_point(id: usize) -> DebugEvent {
+    DebugEvent::Error(format!("no debug point {id}"))
+}
+
+fn no_cheat(number: usize) -> DebugEvent {
+    DebugEvent::Error(format!("no cheat {number}"))
+}
+
+fn no_search() -> DebugEvent {
+    DebugEvent::Error("no search going on, start one with `search new`".to_string())
+}
+
+/// What the condition of a debug point can look at
+struct PointContext<'a> {
+    registers: &'a Registers,
+    bus: &'a Bus,
+    symbols: &'a SymbolTable,
+    hits: u32,
+    /// The value accessed by a watchpoint, and the one it overwrote
+    value: Option<u8>,
+    old: Option<u8>,
+}
+
+impl Context for PointContext<'_> {
+    fn var(&self, var: &Var) -> Result<i64, String> {
+        let r = self.registers;
+        let value = match var {
+            Var::Register(register) => {
+                (match register {
+                    Register::A => r.af >> 8,
+                    Register::F => r.af & 0xFF,
+                    Register::B => r.bc >> 8,
+                    Register::C => r.bc & 0xFF,
+                    Register::D => r.de >> 8,
+                    Register::E => r.de & 0xFF,
+                    Register::H => r.hl >> 8,
+                    Register::L => r.hl & 0xFF,
+                    Register::AF => r.af,
+                    Register::BC => r.bc,
+                    Register::DE => r.de,
+                    Register::HL => r.hl,
+                    Register::SP => r.sp,
+                    Register::PC => r.pc,
+                }) as i64
+            }
+            Var::Flag(flag) => {
+                let bit = match flag {
+                    Flag::Z => 7,
+                    Flag::N => 6,
+                    Flag::H => 5,
+                    Flag::C => 4,
+                };
+                (r.af >> bit & 1) as i64
+            }
+            Var::Bank => self.bus.rom_bank() as i64,
+            Var::Hits => self.hits as i64,
+            Var::Value => self.value.ok_or("only watchpoints have a value")? as i64,
+            Var::Old => self.old.ok_or("only watchpoints have a value")? as i64,
+            Var::Symbol(name) => {
+                self.symbols
+                    .get(name)
+                    .ok_or_else(|| format!("unknown symbol {name}"))?
+                    .address as i64
+            }
+        };
+        Ok(value)
+    }
+
+    fn read(&self, address: u16) -> u8 {
+        self.bus.read(address as usize)
+    }
+}
@@ -0,0 +1,187 @@
+//! Symbol files as written by `rgblink -n`.
+//!
+//! Each line maps a `bank:address` pair, both in hex, to a label name:
+//! ```text
+//! ; File generated by rgblink
+//! 00:0150 Main
+//! 00:0156 Main.loop
+//! 02:4000 LoadTiles
+//! 00:c0a0 wScore
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::disasm::Symbols;
+use crate::memory::map;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Symbol {
+    pub bank: u16,
+    pub address: u16,
+    pub name: String,
+}
+
+impl Symbol {
+    /// Whether this symbol is visible at `address` with `rom_bank` switched into
+    /// 0x4000-0x7FFF. Banks are only told apart in switchable ROM.
+    fn is_mapped(&self, rom_bank: u16) -> bool {
+        !map::ROM_BANK1.contains(&(self.address as usize)) || self.bank == rom_bank
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct SymbolTable {
+    by_address: BTreeMap<u16, Vec<Symbol>>,
+    by_name: HashMap<String, Symbol>,
+}
+
+impl SymbolTable {
+    pub fn parse(source: &str) -> Result<SymbolTable, String> {
+        let mut table = SymbolTable::default();
+        for (i, line) in source.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let symbol = parse_line(line).ok_or_else(|| format!("line {}: {line}", i + 1))?;
+            table.insert(symbol);
+        }
+        Ok(table)
+    }
+
+    pub fn load(path: &str) -> Result<SymbolTable, String> {
+        let source = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        SymbolTable::parse(&source).map_err(|e| format!("{path}: {e}"))
+    }
+
+    pub fn insert(&mut self, symbol: Symbol) {
+        self.by_name.insert(symbol.name.clone(), symbol.clone());
+        self.by_address
+            .entry(symbol.address)
+            .or_default()
+            .push(symbol);
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.by_address.values().flatten()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.by_name.get(name)
+    }
+
+    /// The first symbol defined exactly at `address`
+    pub fn symbol_at(&self, address: u16, rom_bank: u16) -> Option<&Symbol> {
+        self.by_address
+            .get(&address)?
+            .iter()
+            .find(|symbol| symbol.is_mapped(rom_bank))
+    }
+
+    /// The closest symbol at or before `address` in the same memory region, along with
+    /// how far `address` is from it
+    pub fn symbolize(&self, address: u16, rom_bank: u16) -> Option<(&Symbol, u16)> {
+        let region_start = region_start(address);
+        self.by_address
+            .range(region_start..=address)
+            .rev()
+            .find_map(|(_, symbols)| symbols.iter().find(|symbol| symbol.is_mapped(rom_bank)))
+            .map(|symbol| (symbol, address - symbol.address))
+    }
+
+    /// These symbols as seen with `rom_bank` switched in, for disassembling
+    pub fn with_rom_bank(&self, rom_bank: u16) -> impl Symbols + '_ {
+        move |address: u16| {
+            self.symbol_at(address, rom_bank)
+                .map(|symbol| symbol.name.clone())
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<Symbol> {
+    let (location, name) = line.split_once(char::is_whitespace)?;
+    let (bank, address) = location.split_once(':')?;
+    Some(Symbol {
+        bank: u16::from_str_radix(bank, 16).ok()?,
+        address: u16::from_str_radix(address, 16).ok()?,
+        name: name.trim().to_string(),
+    })
+}
+
+/// Start of the memory map region `address` is in, so that symbols don't run over into
+/// unrelated memory
+fn region_start(address: u16) -> u16 {
+    [
+        map::ROM_BANK0,
+        map::ROM_BANK1,
+        map::VRAM,
+        map::EXT_WRAM,
+        map::WRAM,
+        map::NOT_USABLE1,
+        map::OAM,
+        map::NOT_USABLE2,
+        map::IO_REGISTERS,
+        map::HRAM,
+    ]
+    .into_iter()
+    .find(|region| region.contains(&(address as usize)))
+    .map_or(address, |region| region.start as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "; File generated by rgblink
+00:0150 Main
+00:0156 Main.loop
+01:4000 Tiles
+02:4000 Music
+00:c000 wScore ; the score
+";
+
+    #[test]
+    fn test_parse() {
+        let symbols = SymbolTable::parse(SYM).unwrap();
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(
+            symbols.get("Main.loop"),
+            Some(&Symbol {
+                bank: 0,
+                address: 0x156,
+                name: "Main.loop".to_string()
+            })
+        );
+        assert_eq!(symbols.get("wScore").unwrap().address, 0xC000);
+        assert_eq!(
+            SymbolTable::parse("00:0150 Main\n0150 Oops"),
+            Err("line 2: 0150 Oops".to_string())
+        );
+    }
+
+    #[test]
+    fn test_symbolize() {
+        let symbols = SymbolTable::parse(SYM).unwrap();
+        let name = |address, bank| {
+            symbols
+                .symbolize(address, bank)
+                .map(|(symbol, offset)| (symbol.name.as_str(), offset))
+        };
+        assert_eq!(name(0x0150, 1), Some(("Main", 0)));
+        assert_eq!(name(0x015A, 1), Some(("Main.loop", 4)));
+        assert_eq!(name(0x4010, 1), Some(("Tiles", 0x10)));
+        assert_eq!(name(0x4010, 2), Some(("Music", 0x10)));
+        assert_eq!(name(0x4010, 3), None);
+        assert_eq!(name(0xC001, 3), Some(("wScore", 1)));
+        // Symbols in ROM don't spill over into RAM
+        assert_eq!(name(0x8000, 1), None);
+    }
+}
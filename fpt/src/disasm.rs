@@ -0,0 +1,644 @@
+//! Structured disassembler for the LR35902.
+//!
+//! [`decode`] turns the bytes at an address into a [`DecodedInstruction`] with typed operands:
+//! immediates are read from memory, relative jumps are resolved to their target and
+//! `(a8)`/`(a16)` references carry the address they point to. Turning that into text is
+//! the job of a [`Syntax`], of which there are two: [`Rgbds`] and [`Pastraiser`].
+
+use std::fmt;
+
+use crate::bw;
+use crate::lr35902::instructions::{Instruction, INSTRUCTIONS};
+
+pub mod flow;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mnemonic {
+    Adc,
+    Add,
+    And,
+    Bit,
+    Call,
+    Ccf,
+    Cp,
+    Cpl,
+    Daa,
+    Dec,
+    Di,
+    Ei,
+    Halt,
+    Inc,
+    Jp,
+    Jr,
+    Ld,
+    Ldh,
+    Nop,
+    Or,
+    Pop,
+    Push,
+    Res,
+    Ret,
+    Reti,
+    Rl,
+    Rla,
+    Rlc,
+    Rlca,
+    Rr,
+    Rra,
+    Rrc,
+    Rrca,
+    Rst,
+    Sbc,
+    Scf,
+    Set,
+    Sla,
+    Sra,
+    Srl,
+    Stop,
+    Sub,
+    Swap,
+    Xor,
+    /// Not an instruction: an illegal opcode, shown as a data byte
+    Db,
+}
+
+impl Mnemonic {
+    fn from_table(name: &str) -> Mnemonic {
+        match name {
+            "ADC" => Mnemonic::Adc,
+            "ADD" => Mnemonic::Add,
+            "AND" => Mnemonic::And,
+            "BIT" => Mnemonic::Bit,
+            "CALL" => Mnemonic::Call,
+            "CCF" => Mnemonic::Ccf,
+            "CP" => Mnemonic::Cp,
+            "CPL" => Mnemonic::Cpl,
+            "DAA" => Mnemonic::Daa,
+            "DEC" => Mnemonic::Dec,
+            "DI" => Mnemonic::Di,
+            "EI" => Mnemonic::Ei,
+            "HALT" => Mnemonic::Halt,
+            "INC" => Mnemonic::Inc,
+            "JP" => Mnemonic::Jp,
+            "JR" => Mnemonic::Jr,
+            "LD" => Mnemonic::Ld,
+            "LDH" => Mnemonic::Ldh,
+            "NOP" => Mnemonic::Nop,
+            "OR" => Mnemonic::Or,
+            "POP" => Mnemonic::Pop,
+            "PUSH" => Mnemonic::Push,
+            "RES" => Mnemonic::Res,
+            "RET" => Mnemonic::Ret,
+            "RETI" => Mnemonic::Reti,
+            "RL" => Mnemonic::Rl,
+            "RLA" => Mnemonic::Rla,
+            "RLC" => Mnemonic::Rlc,
+            "RLCA" => Mnemonic::Rlca,
+            "RR" => Mnemonic::Rr,
+            "RRA" => Mnemonic::Rra,
+            "RRC" => Mnemonic::Rrc,
+            "RRCA" => Mnemonic::Rrca,
+            "RST" => Mnemonic::Rst,
+            "SBC" => Mnemonic::Sbc,
+            "SCF" => Mnemonic::Scf,
+            "SET" => Mnemonic::Set,
+            "SLA" => Mnemonic::Sla,
+            "SRA" => Mnemonic::Sra,
+            "SRL" => Mnemonic::Srl,
+            "STOP" => Mnemonic::Stop,
+            "SUB" => Mnemonic::Sub,
+            "SWAP" => Mnemonic::Swap,
+            "XOR" => Mnemonic::Xor,
+            _ => Mnemonic::Db,
+        }
+    }
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&format!("{:?}", self).to_uppercase())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Reg8 {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Reg16 {
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Condition {
+    NZ,
+    Z,
+    NC,
+    C,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Operand {
+    /// A, B, C, D, E, H or L
+    Reg8(Reg8),
+    /// AF, BC, DE, HL or SP
+    Reg16(Reg16),
+    /// Memory pointed to by a register pair: (BC), (DE) or (HL)
+    Indirect(Reg16),
+    /// (HL+): memory pointed to by HL, which is incremented afterwards
+    HlInc,
+    /// (HL-): memory pointed to by HL, which is decremented afterwards
+    HlDec,
+    /// (C): memory at 0xFF00 + C
+    HighC,
+    /// d8
+    Imm8(u8),
+    /// d16
+    Imm16(u16),
+    /// r8 of ADD SP,r8
+    SignedImm8(i8),
+    /// SP+r8 of LD HL,SP+r8
+    SpOffset(i8),
+    /// (a16): memory at an absolute address
+    Mem(u16),
+    /// (a8): memory at 0xFF00 + a8
+    HighMem(u8),
+    /// a16 of JP and CALL
+    Target(u16),
+    /// r8 of JR, already resolved to the address it jumps to
+    Relative {
+        offset: i8,
+        target: u16,
+    },
+    Cond(Condition),
+    /// Bit index of BIT, RES and SET
+    Bit(u8),
+    /// RST vector
+    Vector(u8),
+}
+
+impl Operand {
+    /// The address this operand refers to, if it is a memory reference or a jump target
+    pub fn address(&self) -> Option<u16> {
+        match self {
+            Operand::Mem(address) | Operand::Target(address) => Some(*address),
+            Operand::HighMem(offset) => Some(0xFF00 | *offset as u16),
+            Operand::Relative { target, .. } => Some(*target),
+            Operand::Vector(vector) => Some(*vector as u16),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct DecodedInstruction {
+    pub address: u16,
+    /// Raw bytes, including the 0xCB prefix and any immediates
+    pub bytes: Vec<u8>,
+    pub mnemonic: Mnemonic,
+    pub operands: Vec<Operand>,
+    /// The entry in the opcode table this was decoded from
+    pub instruction: Instruction,
+}
+
+impl DecodedInstruction {
+    pub fn size(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.size())
+    }
+
+    /// Where JP, JR, CALL and RST (may) transfer control to, if known statically
+    pub fn target(&self) -> Option<u16> {
+        match self.mnemonic {
+            Mnemonic::Jp | Mnemonic::Jr | Mnemonic::Call | Mnemonic::Rst => self
+                .operands
+                .iter()
+                .find(|o| {
+                    matches!(
+                        o,
+                        Operand::Target(_) | Operand::Relative { .. } | Operand::Vector(_)
+                    )
+                })
+                .and_then(Operand::address),
+            _ => None,
+        }
+    }
+
+    pub fn is_conditional(&self) -> bool {
+        self.operands.iter().any(|o| matches!(o, Operand::Cond(_)))
+    }
+}
+
+/// Decodes the instruction at `address`, reading memory through `read`
+pub fn decode(address: u16, read: impl Fn(u16) -> u8) -> DecodedInstruction {
+    let first = read(address);
+    let (instruction, mut size) = if first == 0xCB {
+        let opcode = 0x100 + read(address.wrapping_add(1)) as usize;
+        (INSTRUCTIONS[opcode], 2)
+    } else {
+        let instruction = INSTRUCTIONS[first as usize];
+        (instruction, instruction.size as u16)
+    };
+    if instruction.mnemonic == "NI" {
+        size = 1;
+    }
+    let bytes: Vec<u8> = (0..size).map(|i| read(address.wrapping_add(i))).collect();
+
+    let mut tokens = instruction.mnemonic.split(' ');
+    let mnemonic = Mnemonic::from_table(tokens.next().unwrap_or_default());
+    let operands = if mnemonic == Mnemonic::Db {
+        vec![Operand::Imm8(first)]
+    } else {
+        tokens
+            .next()
+            .map(|operands| {
+                operands
+                    .split(',')
+                    .enumerate()
+                    .filter_map(|(i, token)| parse_operand(mnemonic, i, token, address, &bytes))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    DecodedInstruction {
+        address,
+        bytes,
+        mnemonic,
+        operands,
+        instruction,
+    }
+}
+
+/// Decodes `count` instructions laid out one after the other, starting at `address`
+pub fn decode_range(
+    address: u16,
+    count: usize,
+    read: impl Fn(u16) -> u8,
+) -> Vec<DecodedInstruction> {
+    let mut decoded = Vec::with_capacity(count);
+    let mut address = address;
+    for _ in 0..count {
+        let inst = decode(address, &read);
+        address = inst.next_address();
+        decoded.push(inst);
+    }
+    decoded
+}
+
+fn parse_operand(
+    mnemonic: Mnemonic,
+    position: usize,
+    token: &str,
+    address: u16,
+    bytes: &[u8],
+) -> Option<Operand> {
+    let d8 = || bytes[1];
+    let d16 = || bw::word16(bytes[2], bytes[1]);
+    let is_branch = matches!(
+        mnemonic,
+        Mnemonic::Jp | Mnemonic::Jr | Mnemonic::Call | Mnemonic::Ret
+    );
+    let operand = match token {
+        "NZ" if is_branch => Operand::Cond(Condition::NZ),
+        "Z" if is_branch => Operand::Cond(Condition::Z),
+        "NC" if is_branch => Operand::Cond(Condition::NC),
+        "C" if is_branch => Operand::Cond(Condition::C),
+        "A" => Operand::Reg8(Reg8::A),
+        "B" => Operand::Reg8(Reg8::B),
+        "C" => Operand::Reg8(Reg8::C),
+        "D" => Operand::Reg8(Reg8::D),
+        "E" => Operand::Reg8(Reg8::E),
+        "H" => Operand::Reg8(Reg8::H),
+        "L" => Operand::Reg8(Reg8::L),
+        "AF" => Operand::Reg16(Reg16::AF),
+        "BC" => Operand::Reg16(Reg16::BC),
+        "DE" => Operand::Reg16(Reg16::DE),
+        "HL" => Operand::Reg16(Reg16::HL),
+        "SP" => Operand::Reg16(Reg16::SP),
+        // JP (HL) jumps to the address in HL, it doesn't read memory
+        "(HL)" if mnemonic == Mnemonic::Jp => Operand::Reg16(Reg16::HL),
+        "(BC)" => Operand::Indirect(Reg16::BC),
+        "(DE)" => Operand::Indirect(Reg16::DE),
+        "(HL)" => Operand::Indirect(Reg16::HL),
+        "(HL+)" => Operand::HlInc,
+        "(HL-)" => Operand::HlDec,
+        "(C)" => Operand::HighC,
+        "d8" => Operand::Imm8(d8()),
+        "d16" => Operand::Imm16(d16()),
+        "a16" => Operand::Target(d16()),
+        "(a16)" => Operand::Mem(d16()),
+        "(a8)" => Operand::HighMem(d8()),
+        "SP+r8" => Operand::SpOffset(d8() as i8),
+        "r8" if mnemonic == Mnemonic::Jr => {
+            let offset = d8() as i8;
+            Operand::Relative {
+                offset,
+                target: address.wrapping_add(2).wrapping_add_signed(offset as i16),
+            }
+        }
+        "r8" => Operand::SignedImm8(d8() as i8),
+        // STOP is encoded as 0x10 0x00, assemblers take no operand
+        "0" if mnemonic == Mnemonic::Stop => return None,
+        bit if position == 0
+            && matches!(mnemonic, Mnemonic::Bit | Mnemonic::Res | Mnemonic::Set) =>
+        {
+            Operand::Bit(bit.parse().ok()?)
+        }
+        vector if mnemonic == Mnemonic::Rst => {
+            Operand::Vector(u8::from_str_radix(vector.strip_suffix('H')?, 16).ok()?)
+        }
+        _ => return None,
+    };
+    Some(operand)
+}
+
+/// Names for addresses, like the labels of a disassembly or the ones in a symbol file
+pub trait Symbols {
+    fn symbol(&self, address: u16) -> Option<String>;
+}
+
+impl<F: Fn(u16) -> Option<String>> Symbols for F {
+    fn symbol(&self, address: u16) -> Option<String> {
+        self(address)
+    }
+}
+
+/// Turns a [`DecodedInstruction`] into assembly text
+pub trait Syntax {
+    fn mnemonic(&self, mnemonic: Mnemonic) -> String;
+
+    /// Formats `operand`, naming jump targets and memory references after `symbols` if possible
+    fn operand(
+        &self,
+        inst: &DecodedInstruction,
+        operand: &Operand,
+        symbols: &dyn Symbols,
+    ) -> String;
+
+    fn operand_separator(&self) -> &'static str;
+
+    fn format(&self, inst: &DecodedInstruction) -> String {
+        self.format_with_symbols(inst, &|_: u16| None)
+    }
+
+    fn format_with_symbols(&self, inst: &DecodedInstruction, symbols: &dyn Symbols) -> String {
+        let mnemonic = self.mnemonic(inst.mnemonic);
+        if inst.operands.is_empty() {
+            return mnemonic;
+        }
+        let operands = inst
+            .operands
+            .iter()
+            .map(|operand| self.operand(inst, operand, symbols))
+            .collect::<Vec<String>>()
+            .join(self.operand_separator());
+        format!("{mnemonic} {operands}")
+    }
+}
+
+/// Syntax accepted by RGBASM, as in <https://rgbds.gbdev.io/docs/v0.7.0/gbz80.7>
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Rgbds;
+
+impl Syntax for Rgbds {
+    fn mnemonic(&self, mnemonic: Mnemonic) -> String {
+        mnemonic.to_string().to_lowercase()
+    }
+
+    fn operand(
+        &self,
+        _inst: &DecodedInstruction,
+        operand: &Operand,
+        symbols: &dyn Symbols,
+    ) -> String {
+        if let Some(symbol) = operand
+            .address()
+            .and_then(|address| symbols.symbol(address))
+        {
+            match operand {
+                Operand::Mem(_) | Operand::HighMem(_) => return format!("[{symbol}]"),
+                Operand::Target(_) | Operand::Relative { .. } => return symbol,
+                _ => {}
+            }
+        }
+        match operand {
+            Operand::Reg8(r) => format!("{:?}", r).to_lowercase(),
+            Operand::Reg16(r) => format!("{:?}", r).to_lowercase(),
+            Operand::Indirect(r) => format!("[{}]", format!("{:?}", r).to_lowercase()),
+            Operand::HlInc => "[hl+]".to_string(),
+            Operand::HlDec => "[hl-]".to_string(),
+            Operand::HighC => "[$ff00+c]".to_string(),
+            Operand::Imm8(n) => format!("${:02X}", n),
+            Operand::Imm16(n) => format!("${:04X}", n),
+            Operand::SignedImm8(n) => signed_rgbds(*n),
+            Operand::SpOffset(n) if *n < 0 => format!("sp-${:02X}", n.unsigned_abs()),
+            Operand::SpOffset(n) => format!("sp+${:02X}", n),
+            Operand::Mem(address) => format!("[${:04X}]", address),
+            Operand::HighMem(offset) => format!("[${:04X}]", 0xFF00 | *offset as u16),
+            Operand::Target(address)
+            | Operand::Relative {
+                target: address, ..
+            } => {
+                format!("${:04X}", address)
+            }
+            Operand::Cond(c) => format!("{:?}", c).to_lowercase(),
+            Operand::Bit(bit) => bit.to_string(),
+            Operand::Vector(vector) => format!("${:02X}", vector),
+        }
+    }
+
+    fn operand_separator(&self) -> &'static str {
+        ", "
+    }
+}
+
+fn signed_rgbds(n: i8) -> String {
+    if n < 0 {
+        format!("-${:02X}", n.unsigned_abs())
+    } else {
+        format!("${:02X}", n)
+    }
+}
+
+/// The syntax of the opcode table at
+/// <https://www.pastraiser.com/cpu/gameboy/gameboy_opcodes.html>, with immediates filled in
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Pastraiser;
+
+impl Syntax for Pastraiser {
+    fn mnemonic(&self, mnemonic: Mnemonic) -> String {
+        mnemonic.to_string()
+    }
+
+    fn operand(
+        &self,
+        inst: &DecodedInstruction,
+        operand: &Operand,
+        symbols: &dyn Symbols,
+    ) -> String {
+        if let Some(symbol) = operand
+            .address()
+            .and_then(|address| symbols.symbol(address))
+        {
+            match operand {
+                Operand::Mem(_) | Operand::HighMem(_) => return format!("({symbol})"),
+                Operand::Target(_) | Operand::Relative { .. } => return symbol,
+                _ => {}
+            }
+        }
+        match operand {
+            Operand::Reg8(r) => format!("{:?}", r),
+            Operand::Reg16(Reg16::HL) if inst.mnemonic == Mnemonic::Jp => "(HL)".to_string(),
+            Operand::Reg16(r) => format!("{:?}", r),
+            Operand::Indirect(r) => format!("({:?})", r),
+            Operand::HlInc => "(HL+)".to_string(),
+            Operand::HlDec => "(HL-)".to_string(),
+            Operand::HighC => "(C)".to_string(),
+            Operand::Imm8(n) => format!("{:#04X}", n),
+            Operand::Imm16(n) => format!("{:#06X}", n),
+            Operand::SignedImm8(n) => n.to_string(),
+            Operand::SpOffset(n) => format!("SP{:+}", n),
+            Operand::Mem(address) => format!("({:#06X})", address),
+            Operand::HighMem(offset) => format!("({:#06X})", 0xFF00 | *offset as u16),
+            Operand::Target(address)
+            | Operand::Relative {
+                target: address, ..
+            } => {
+                format!("{:#06X}", address)
+            }
+            Operand::Cond(c) => format!("{:?}", c),
+            Operand::Bit(bit) => bit.to_string(),
+            Operand::Vector(vector) => format!("{:02X}H", vector),
+        }
+    }
+
+    fn operand_separator(&self) -> &'static str {
+        ","
+    }
+}
+
+/// One line of a code listing: address, assembly and raw bytes
+pub fn listing_line(syntax: &dyn Syntax, inst: &DecodedInstruction) -> String {
+    listing_line_with_symbols(syntax, inst, &|_: u16| None)
+}
+
+/// Like [`listing_line`], naming jump targets and memory references after `symbols`
+pub fn listing_line_with_symbols(
+    syntax: &dyn Syntax,
+    inst: &DecodedInstruction,
+    symbols: &dyn Symbols,
+) -> String {
+    let bytes = inst
+        .bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(" ");
+    format!(
+        "{:#06X}: {:<20} ; {}",
+        inst.address,
+        syntax.format_with_symbols(inst, symbols),
+        bytes
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bytes(bytes: &[u8], address: u16) -> DecodedInstruction {
+        decode(address, |a| bytes[(a - address) as usize])
+    }
+
+    #[test]
+    fn test_immediates() {
+        let inst = decode_bytes(&[0x01, 0x34, 0x12], 0x100);
+        assert_eq!(inst.mnemonic, Mnemonic::Ld);
+        assert_eq!(
+            inst.operands,
+            vec![Operand::Reg16(Reg16::BC), Operand::Imm16(0x1234)]
+        );
+        assert_eq!(Rgbds.format(&inst), "ld bc, $1234");
+        assert_eq!(Pastraiser.format(&inst), "LD BC,0x1234");
+    }
+
+    #[test]
+    fn test_relative_jump() {
+        // JR NZ,-3 jumps back to the start of the previous (1-byte) instruction
+        let inst = decode_bytes(&[0x20, 0xFD], 0x150);
+        assert_eq!(
+            inst.operands,
+            vec![
+                Operand::Cond(Condition::NZ),
+                Operand::Relative {
+                    offset: -3,
+                    target: 0x14F
+                }
+            ]
+        );
+        assert_eq!(inst.target(), Some(0x14F));
+        assert!(inst.is_conditional());
+        assert_eq!(Rgbds.format(&inst), "jr nz, $014F");
+    }
+
+    #[test]
+    fn test_memory_references() {
+        let ldh = decode_bytes(&[0xE0, 0x40], 0);
+        assert_eq!(ldh.operands[0].address(), Some(0xFF40));
+        assert_eq!(Rgbds.format(&ldh), "ldh [$FF40], a");
+        assert_eq!(Pastraiser.format(&ldh), "LDH (0xFF40),A");
+
+        let ld = decode_bytes(&[0xFA, 0x00, 0xC0], 0);
+        assert_eq!(Rgbds.format(&ld), "ld a, [$C000]");
+        assert_eq!(Pastraiser.format(&ld), "LD A,(0xC000)");
+    }
+
+    #[test]
+    fn test_prefix_cb() {
+        let inst = decode_bytes(&[0xCB, 0x7E], 0);
+        assert_eq!(inst.size(), 2);
+        assert_eq!(inst.mnemonic, Mnemonic::Bit);
+        assert_eq!(Rgbds.format(&inst), "bit 7, [hl]");
+        assert_eq!(Pastraiser.format(&inst), "BIT 7,(HL)");
+    }
+
+    #[test]
+    fn test_special_cases() {
+        assert_eq!(Rgbds.format(&decode_bytes(&[0xE9], 0)), "jp hl");
+        assert_eq!(Pastraiser.format(&decode_bytes(&[0xE9], 0)), "JP (HL)");
+        assert_eq!(Rgbds.format(&decode_bytes(&[0xFF], 0)), "rst $38");
+        assert_eq!(Pastraiser.format(&decode_bytes(&[0xFF], 0)), "RST 38H");
+        assert_eq!(
+            Rgbds.format(&decode_bytes(&[0xF8, 0xFE], 0)),
+            "ld hl, sp-$02"
+        );
+        assert_eq!(
+            Rgbds.format(&decode_bytes(&[0xF8, 0x05], 0)),
+            "ld hl, sp+$05"
+        );
+        assert_eq!(
+            Rgbds.format(&decode_bytes(&[0xE8, 0xFE], 0)),
+            "add sp, -$02"
+        );
+        assert_eq!(Rgbds.format(&decode_bytes(&[0xE2], 0)), "ld [$ff00+c], a");
+        assert_eq!(Rgbds.format(&decode_bytes(&[0xD8], 0)), "ret c");
+        assert_eq!(Rgbds.format(&decode_bytes(&[0x10, 0x00], 0)), "stop");
+        assert_eq!(Rgbds.format(&decode_bytes(&[0xD3], 0)), "db $D3");
+        assert_eq!(decode_bytes(&[0xD3], 0).size(), 1);
+    }
+}
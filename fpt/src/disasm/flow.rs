@@ -0,0 +1,445 @@
+//! Static disassembly of a whole ROM by following the control flow.
+//!
+//! Starting from the entry point, the RST vectors and the interrupt vectors, every
+//! instruction is decoded and the targets of JP, JR, CALL and RST are queued to be
+//! disassembled too. Bytes never reached this way are considered data.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+
+use super::{decode, DecodedInstruction, Mnemonic, Operand, Reg8, Rgbds, Syntax};
+use crate::debugger::symbols::SymbolTable;
+use crate::memory::map;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+
+/// Entry points every cartridge has, with the label they get in the disassembly
+const ENTRY_POINTS: [(u16, &str); 14] = [
+    (0x00, "RST_00"),
+    (0x08, "RST_08"),
+    (0x10, "RST_10"),
+    (0x18, "RST_18"),
+    (0x20, "RST_20"),
+    (0x28, "RST_28"),
+    (0x30, "RST_30"),
+    (0x38, "RST_38"),
+    (0x40, "VBlankInterrupt"),
+    (0x48, "LCDCInterrupt"),
+    (0x50, "TimerOverflowInterrupt"),
+    (0x58, "SerialTransferCompleteInterrupt"),
+    (0x60, "JoypadTransitionInterrupt"),
+    (0x100, "Boot"),
+];
+
+/// Bytes per `db` line
+const DATA_LINE_LEN: usize = 16;
+/// Runs of the same byte at least this long are written as a single `ds`
+const FILL_MIN_LEN: usize = 16;
+
+/// An address in a specific ROM bank. Bank 0 lives in 0x0000-0x3FFF and the rest of the
+/// banks in 0x4000-0x7FFF.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct RomAddress {
+    pub bank: u16,
+    pub address: u16,
+}
+
+impl RomAddress {
+    pub fn new(bank: u16, address: u16) -> Self {
+        Self { bank, address }
+    }
+
+    /// Where `address` is in the ROM, given the bank switched into 0x4000-0x7FFF.
+    /// Addresses outside of ROM have no ROM location.
+    pub fn resolve(address: u16, switched_bank: u16) -> Option<RomAddress> {
+        if map::ROM_BANK0.contains(&(address as usize)) {
+            Some(RomAddress::new(0, address))
+        } else if map::ROM_BANK1.contains(&(address as usize)) {
+            Some(RomAddress::new(switched_bank, address))
+        } else {
+            None
+        }
+    }
+
+    /// Offset of this address in the ROM file
+    pub fn offset(&self) -> usize {
+        self.bank as usize * ROM_BANK_SIZE + self.address as usize % ROM_BANK_SIZE
+    }
+}
+
+pub struct RomDisassembly<'a> {
+    rom: &'a [u8],
+    code: BTreeMap<RomAddress, DecodedInstruction>,
+    /// Where the jumps and calls that left their own bank ended up
+    far_targets: HashMap<RomAddress, RomAddress>,
+    labels: BTreeMap<RomAddress, String>,
+    /// Symbols outside of ROM, written as constants
+    constants: BTreeMap<u16, String>,
+}
+
+impl<'a> RomDisassembly<'a> {
+    pub fn new(rom: &'a [u8]) -> Self {
+        let mut disassembly = Self {
+            rom,
+            code: BTreeMap::new(),
+            far_targets: HashMap::new(),
+            labels: BTreeMap::new(),
+            constants: BTreeMap::new(),
+        };
+        disassembly.follow_flow();
+        disassembly
+    }
+
+    pub fn banks(&self) -> u16 {
+        self.rom.len().div_ceil(ROM_BANK_SIZE) as u16
+    }
+
+    pub fn code(&self) -> &BTreeMap<RomAddress, DecodedInstruction> {
+        &self.code
+    }
+
+    pub fn labels(&self) -> &BTreeMap<RomAddress, String> {
+        &self.labels
+    }
+
+    /// Names labels after a symbol file instead of their location. Symbols outside of
+    /// ROM become constants, except local ones which can't be.
+    pub fn name_symbols(&mut self, symbols: &SymbolTable) {
+        for symbol in symbols.iter() {
+            match RomAddress::resolve(symbol.address, symbol.bank) {
+                Some(at) if at.bank == symbol.bank && at.offset() < self.rom.len() => {
+                    self.labels.insert(at, symbol.name.clone());
+                }
+                Some(_) => {}
+                None if !symbol.name.contains('.') => {
+                    self.constants
+                        .entry(symbol.address)
+                        .or_insert_with(|| symbol.name.clone());
+                }
+                None => {}
+            }
+        }
+    }
+
+    fn read(&self, bank: u16, address: u16) -> u8 {
+        let offset = RomAddress::new(bank, address).offset();
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    /// Decodes the instruction at `at`, if all of it fits in the bank
+    fn decode_at(&self, at: RomAddress) -> Option<DecodedInstruction> {
+        if at.offset() >= self.rom.len() {
+            return None;
+        }
+        let inst = decode(at.address, |address| self.read(at.bank, address));
+        let end = at.address as usize % ROM_BANK_SIZE + inst.size() as usize;
+        (end <= ROM_BANK_SIZE).then_some(inst)
+    }
+
+    fn follow_flow(&mut self) {
+        // Each path to explore carries the bank it thinks is switched in
+        let mut queue: VecDeque<(RomAddress, u16)> = VecDeque::new();
+        for (address, label) in ENTRY_POINTS {
+            let at = RomAddress::new(0, address);
+            self.labels.insert(at, label.to_string());
+            queue.push_back((at, 1));
+        }
+
+        while let Some((mut at, mut switched_bank)) = queue.pop_front() {
+            // Value of A if it was just loaded with an immediate, to catch bank switches
+            let mut a = None;
+            while !self.code.contains_key(&at) {
+                let Some(inst) = self.decode_at(at) else {
+                    break;
+                };
+                if inst.mnemonic == Mnemonic::Db {
+                    break;
+                }
+
+                match (inst.mnemonic, inst.operands.as_slice()) {
+                    (Mnemonic::Ld, [Operand::Reg8(Reg8::A), Operand::Imm8(n)]) => {
+                        a = Some(*n);
+                    }
+                    (Mnemonic::Ld, [Operand::Mem(address), Operand::Reg8(Reg8::A)])
+                        if (0x2000..0x4000).contains(address) =>
+                    {
+                        if let Some(bank) = a {
+                            // Bank numbers past the end wrap around, and bank 0 reads as bank 1
+                            switched_bank = (bank as u16 % self.banks().max(2)).max(1);
+                        }
+                    }
+                    (_, [Operand::Reg8(Reg8::A), ..]) => a = None,
+                    _ => {}
+                }
+
+                if let Some(target) = inst.target() {
+                    let bank = if at.bank == 0 { switched_bank } else { at.bank };
+                    if let Some(target) = RomAddress::resolve(target, bank) {
+                        let kind = match inst.mnemonic {
+                            Mnemonic::Call | Mnemonic::Rst => "Call",
+                            _ => "Jump",
+                        };
+                        self.labels.entry(target).or_insert_with(|| {
+                            format!("{kind}_{:03X}_{:04X}", target.bank, target.address)
+                        });
+                        if target.bank != at.bank && target.bank != 0 {
+                            self.far_targets.insert(at, target);
+                        }
+                        queue.push_back((target, switched_bank));
+                    }
+                }
+
+                let ends_flow = match inst.mnemonic {
+                    Mnemonic::Jp | Mnemonic::Jr | Mnemonic::Ret => !inst.is_conditional(),
+                    Mnemonic::Reti => true,
+                    _ => false,
+                };
+                let next = RomAddress::new(at.bank, inst.next_address());
+                self.code.insert(at, inst);
+                if ends_flow {
+                    break;
+                }
+                at = next;
+            }
+        }
+    }
+
+    /// Re-assemblable RGBDS source for the whole ROM
+    pub fn to_rgbds(&self) -> String {
+        let layouts: Vec<Vec<(RomAddress, usize)>> =
+            (0..self.banks()).map(|bank| self.layout(bank)).collect();
+        // A label can only be written if nothing before it ran over its address
+        let placed: HashSet<RomAddress> = layouts.iter().flatten().map(|(at, _)| *at).collect();
+
+        let mut asm = String::new();
+        for (address, name) in &self.constants {
+            writeln!(asm, "DEF {name} EQU ${address:04X}").unwrap();
+        }
+        if !self.constants.is_empty() {
+            writeln!(asm).unwrap();
+        }
+        for (bank, layout) in layouts.iter().enumerate() {
+            self.write_bank(&mut asm, bank as u16, layout, &placed)
+                .unwrap();
+        }
+        asm
+    }
+
+    /// Splits a bank into lines: each is either an instruction or a run of data bytes
+    fn layout(&self, bank: u16) -> Vec<(RomAddress, usize)> {
+        let start = if bank == 0 { 0x0000 } else { 0x4000 };
+        let end = start + ROM_BANK_SIZE.min(self.rom.len() - bank as usize * ROM_BANK_SIZE);
+        let mut lines = Vec::new();
+        let mut address = start;
+        while address < end {
+            let at = RomAddress::new(bank, address as u16);
+            let len = match self.code.get(&at) {
+                Some(inst) => inst.size() as usize,
+                None => self.data_len(bank, address, end),
+            };
+            lines.push((at, len));
+            address += len;
+        }
+        lines
+    }
+
+    fn write_bank(
+        &self,
+        asm: &mut String,
+        bank: u16,
+        layout: &[(RomAddress, usize)],
+        placed: &HashSet<RomAddress>,
+    ) -> std::fmt::Result {
+        if bank == 0 {
+            writeln!(asm, "SECTION \"ROM Bank $000\", ROM0[$0000]")?;
+        } else {
+            writeln!(
+                asm,
+                "\nSECTION \"ROM Bank ${bank:03X}\", ROMX[$4000], BANK[${bank:X}]"
+            )?;
+        }
+
+        for &(at, len) in layout {
+            if let Some(label) = self.labels.get(&at) {
+                writeln!(asm, "\n{label}:")?;
+            }
+            match self.code.get(&at) {
+                Some(inst) if !needs_db(inst) => {
+                    let switched_bank = self
+                        .far_targets
+                        .get(&at)
+                        .map_or(bank.max(1), |target| target.bank);
+                    let symbols = |address: u16| {
+                        RomAddress::resolve(address, switched_bank)
+                            .filter(|target| placed.contains(target))
+                            .and_then(|target| self.labels.get(&target).cloned())
+                            .or_else(|| self.constants.get(&address).cloned())
+                    };
+                    writeln!(asm, "    {}", Rgbds.format_with_symbols(inst, &symbols))?;
+                }
+                _ => {
+                    let bytes: Vec<u8> = (at.address..at.address + len as u16)
+                        .map(|address| self.read(bank, address))
+                        .collect();
+                    if len >= FILL_MIN_LEN && bytes.iter().all(|b| *b == bytes[0]) {
+                        writeln!(asm, "    ds {len}, ${:02X}", bytes[0])?;
+                    } else {
+                        let bytes: Vec<String> =
+                            bytes.iter().map(|b| format!("${:02X}", b)).collect();
+                        writeln!(asm, "    db {}", bytes.join(", "))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Length of the data line starting at `address`: a run of the same byte or up to
+    /// [`DATA_LINE_LEN`] bytes, never crossing into code or a label
+    fn data_len(&self, bank: u16, address: usize, end: usize) -> usize {
+        let is_data = |address: usize| {
+            let at = RomAddress::new(bank, address as u16);
+            address < end && !self.code.contains_key(&at) && !self.labels.contains_key(&at)
+        };
+        let first = self.read(bank, address as u16);
+        let mut fill = 1;
+        while is_data(address + fill) && self.read(bank, (address + fill) as u16) == first {
+            fill += 1;
+        }
+        if fill >= FILL_MIN_LEN {
+            return fill;
+        }
+        let mut len = 1;
+        while len < DATA_LINE_LEN && is_data(address + len) {
+            len += 1;
+        }
+        len
+    }
+}
+
+/// Instructions RGBASM would encode differently than they are in the ROM
+fn needs_db(inst: &DecodedInstruction) -> bool {
+    inst.mnemonic == Mnemonic::Stop && inst.bytes[1] != 0x00
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom_with(banks: usize, code: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut rom = vec![0xFF; banks * ROM_BANK_SIZE];
+        for (offset, bytes) in code {
+            rom[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+        }
+        rom
+    }
+
+    #[test]
+    fn test_follows_jumps_and_calls() {
+        let rom = rom_with(
+            2,
+            &[
+                (0x100, &[0x00, 0xC3, 0x50, 0x01]),       // nop; jp $0150
+                (0x150, &[0xCD, 0x00, 0x02, 0x18, 0xFB]), // call $0200; jr $0150
+                (0x200, &[0xC9]),                         // ret
+            ],
+        );
+        let disasm = RomDisassembly::new(&rom);
+
+        assert!(disasm.code().contains_key(&RomAddress::new(0, 0x150)));
+        assert!(disasm.code().contains_key(&RomAddress::new(0, 0x153)));
+        assert!(disasm.code().contains_key(&RomAddress::new(0, 0x200)));
+        // The cartridge header and what's after the RET are never executed
+        assert!(!disasm.code().contains_key(&RomAddress::new(0, 0x104)));
+        assert!(!disasm.code().contains_key(&RomAddress::new(0, 0x201)));
+        assert!(!disasm.code().contains_key(&RomAddress::new(1, 0x4000)));
+
+        let asm = disasm.to_rgbds();
+        assert!(asm.contains("\nBoot:\n    nop\n    jp Jump_000_0150\n"));
+        assert!(asm.contains("\nJump_000_0150:\n    call Call_000_0200\n    jr Jump_000_0150\n"));
+        assert!(asm.contains("\nCall_000_0200:\n    ret\n    ds 15871, $FF\n"));
+        assert!(
+            asm.contains("SECTION \"ROM Bank $001\", ROMX[$4000], BANK[$1]\n    ds 16384, $FF\n")
+        );
+    }
+
+    #[test]
+    fn test_follows_bank_switches() {
+        let rom = rom_with(
+            4,
+            &[
+                // ld a, $02; ld [$2000], a; call $4000; jr @
+                (
+                    0x100,
+                    &[0x3E, 0x02, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40, 0x18, 0xFE],
+                ),
+                (2 * ROM_BANK_SIZE, &[0xC9]),
+            ],
+        );
+        let disasm = RomDisassembly::new(&rom);
+
+        assert!(disasm.code().contains_key(&RomAddress::new(2, 0x4000)));
+        assert!(!disasm.code().contains_key(&RomAddress::new(1, 0x4000)));
+        assert!(disasm.to_rgbds().contains("    call Call_002_4000\n"));
+    }
+
+    #[test]
+    fn test_bank_switch_wraps() {
+        let rom = rom_with(
+            4,
+            &[
+                // ld a, $04; ld [$2000], a; call $4000; jr @
+                (
+                    0x100,
+                    &[0x3E, 0x04, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40, 0x18, 0xFE],
+                ),
+                (ROM_BANK_SIZE, &[0xC9]),
+            ],
+        );
+        let disasm = RomDisassembly::new(&rom);
+
+        // Bank 4 of 4 wraps to bank 0, which switches in bank 1
+        assert!(disasm.code().contains_key(&RomAddress::new(1, 0x4000)));
+    }
+
+    #[test]
+    fn test_data_in_the_middle_of_code() {
+        let rom = rom_with(
+            2,
+            &[
+                (0x100, &[0x18, 0x03]),       // jr $0105
+                (0x102, &[0x12, 0x34, 0x56]), // data
+                (0x105, &[0x76, 0x18, 0xFD]), // halt; jr $0105
+            ],
+        );
+        let asm = RomDisassembly::new(&rom).to_rgbds();
+        assert!(asm.contains(
+            "\nBoot:\n    jr Jump_000_0105\n    db $12, $34, $56\n\nJump_000_0105:\n    halt\n"
+        ));
+    }
+
+    #[test]
+    fn test_names_symbols() {
+        let rom = rom_with(
+            2,
+            &[
+                (0x100, &[0xCD, 0x50, 0x01]),       // call $0150
+                (0x103, &[0xF0, 0x44, 0x18, 0xFC]), // ldh a, [$ff44]; jr $0103
+                (0x150, &[0xEA, 0x00, 0xC0, 0xC9]), // ld [$c000], a; ret
+            ],
+        );
+        let symbols = SymbolTable::parse(
+            "00:0100 EntryPoint\n00:0103 EntryPoint.loop\n00:0150 SaveLY\n00:c000 wLY\n00:ff44 rLY\n",
+        )
+        .unwrap();
+        let mut disasm = RomDisassembly::new(&rom);
+        disasm.name_symbols(&symbols);
+        let asm = disasm.to_rgbds();
+
+        assert!(asm.starts_with("DEF wLY EQU $C000\nDEF rLY EQU $FF44\n\n"));
+        assert!(asm.contains(
+            "\nEntryPoint:\n    call SaveLY\n\nEntryPoint.loop:\n    ldh a, [rLY]\n    jr EntryPoint.loop\n"
+        ));
+        assert!(asm.contains("\nSaveLY:\n    ld [wLY], a\n    ret\n"));
+    }
+}
@@ -0,0 +1,522 @@
+//! A server for the GDB remote serial protocol, so that gdb and its frontends can debug
+//! games running in the emulator.
+//!
+//! Registers are numbered AF, BC, DE, HL, SP and PC, each sent as 16 bits little endian,
+//! and described to gdb by a target description. Breakpoints and watchpoints are set in
+//! the [`Debugger`](crate::debugger::Debugger), software and hardware breakpoints alike.
+//!
+//! <https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html>
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use crate::debug_interface::{DebugCmd, DebugEvent, WatchKind};
+use crate::Gameboy;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.sm83.cpu">
+    <reg name="af" bitsize="16" type="int"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="int"/>
+    <reg name="hl" bitsize="16" type="int"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTERS: usize = 6;
+
+/// Steps to run between checks for an interrupt from gdb
+const INTERRUPT_POLL_STEPS: usize = 10_000;
+
+/// How a `Z` packet asks to stop
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum PointKind {
+    Breakpoint,
+    Watchpoint(WatchKind),
+}
+
+impl PointKind {
+    fn from_type(z_type: u8) -> Option<PointKind> {
+        match z_type {
+            0 | 1 => Some(PointKind::Breakpoint),
+            2 => Some(PointKind::Watchpoint(WatchKind::Write)),
+            3 => Some(PointKind::Watchpoint(WatchKind::Read)),
+            4 => Some(PointKind::Watchpoint(WatchKind::ReadWrite)),
+            _ => None,
+        }
+    }
+}
+
+/// Why running stopped, as told to gdb
+enum Stop {
+    Trap,
+    Interrupted,
+    Watch(WatchKind, u16),
+}
+
+impl Stop {
+    fn reply(&self) -> String {
+        match self {
+            Stop::Trap => "S05".to_string(),
+            Stop::Interrupted => "S02".to_string(),
+            Stop::Watch(kind, address) => {
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::ReadWrite => "awatch",
+                };
+                format!("T05{name}:{address:04x};")
+            }
+        }
+    }
+}
+
+/// One gdb connection, debugging `gameboy`
+pub struct GdbSession<'a> {
+    gameboy: &'a mut Gameboy,
+    stream: TcpStream,
+    ack: bool,
+    /// Debug point ids by what gdb set them as, one for each `Z` packet
+    points: HashMap<(PointKind, u16, u16), Vec<usize>>,
+}
+
+impl<'a> GdbSession<'a> {
+    pub fn new(gameboy: &'a mut Gameboy, stream: TcpStream) -> Self {
+        Self {
+            gameboy,
+            stream,
+            ack: true,
+            points: HashMap::new(),
+        }
+    }
+
+    /// Answers gdb until it kills or detaches from the target, or disconnects
+    pub fn serve(mut self) -> io::Result<()> {
+        self.gameboy.set_paused(true);
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.as_bytes().first() {
+                Some(b'k') => return Ok(()),
+                Some(b'D') => {
+                    self.write_packet("OK")?;
+                    self.gameboy.set_paused(false);
+                    return Ok(());
+                }
+                Some(b'c') => self.resume(DebugCmd::Continue)?,
+                Some(b's') => self.resume(DebugCmd::Step)?,
+                _ if packet == "QStartNoAckMode" => {
+                    // Acknowledged like any packet before switching over
+                    self.write_packet("OK")?;
+                    self.ack = false;
+                    continue;
+                }
+                _ => self.answer(&packet),
+            };
+            self.write_packet(&reply)?;
+        }
+        Ok(())
+    }
+
+    /// The reply to any packet that doesn't run the game
+    fn answer(&mut self, packet: &str) -> String {
+        let (command, args) = packet.split_at(1);
+        let reply = match command {
+            "?" => Some(Stop::Trap.reply()),
+            "g" => Some(self.read_registers()),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "Z" => self.insert_point(args),
+            "z" => self.remove_point(args),
+            "H" => Some("OK".to_string()),
+            "q" | "Q" => self.query(packet),
+            _ => Some(String::new()),
+        };
+        reply.unwrap_or_else(|| "E01".to_string())
+    }
+
+    fn query(&mut self, packet: &str) -> Option<String> {
+        let reply = if packet.starts_with("qSupported") {
+            "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".to_string()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, length) = args.split_once(',')?;
+            let offset = usize::from_str_radix(offset, 16).ok()?;
+            let length = usize::from_str_radix(length, 16).ok()?;
+            let rest = TARGET_XML.get(offset.min(TARGET_XML.len())..)?;
+            if rest.len() > length {
+                format!("m{}", &rest[..length])
+            } else {
+                format!("l{rest}")
+            }
+        } else {
+            match packet {
+                "qAttached" => "1",
+                "qC" => "QC1",
+                "qfThreadInfo" => "m1",
+                "qsThreadInfo" => "l",
+                _ => "",
+            }
+            .to_string()
+        };
+        Some(reply)
+    }
+
+    fn register_values(&self) -> [u16; REGISTERS] {
+        let cpu = self.gameboy.cpu();
+        [cpu.af(), cpu.bc(), cpu.de(), cpu.hl(), cpu.sp(), cpu.pc()]
+    }
+
+    fn set_register(&mut self, index: usize, value: u16) -> Option<()> {
+        let cpu = self.gameboy.cpu_mut();
+        match index {
+            0 => cpu.set_af(value),
+            1 => cpu.set_bc(value),
+            2 => cpu.set_de(value),
+            3 => cpu.set_hl(value),
+            4 => cpu.set_sp(value),
+            5 => cpu.set_pc(value),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn read_registers(&self) -> String {
+        self.register_values()
+            .iter()
+            .map(|value| hex(&value.to_le_bytes()))
+            .collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> Option<String> {
+        let bytes = unhex(args)?;
+        if bytes.len() != REGISTERS * 2 {
+            return None;
+        }
+        for (index, value) in bytes.array_chunks::<2>().enumerate() {
+            self.set_register(index, u16::from_le_bytes(*value))?;
+        }
+        Some("OK".to_string())
+    }
+
+    fn read_register(&self, args: &str) -> Option<String> {
+        let index = usize::from_str_radix(args, 16).ok()?;
+        let value = self.register_values().get(index)?.to_le_bytes();
+        Some(hex(&value))
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (index, value) = args.split_once('=')?;
+        let index = usize::from_str_radix(index, 16).ok()?;
+        let value: [u8; 2] = unhex(value)?.try_into().ok()?;
+        self.set_register(index, u16::from_le_bytes(value))?;
+        Some("OK".to_string())
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (address, length) = parse_address_length(args)?;
+        let bus = self.gameboy.bus();
+        let end = (address + length).min(0x10000);
+        let bytes: Vec<u8> = (address..end).map(|a| bus.read(a)).collect();
+        Some(hex(&bytes))
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (location, data) = args.split_once(':')?;
+        let (address, length) = parse_address_length(location)?;
+        let bytes = unhex(data)?;
+        if bytes.len() != length || address + length > 0x10000 {
+            return None;
+        }
+        let bus = self.gameboy.bus_mut();
+        for (i, byte) in bytes.into_iter().enumerate() {
+            bus.write(address + i, byte);
+        }
+        Some("OK".to_string())
+    }
+
+    /// Handles `Z type,address,kind`, where the kind of watchpoints is how many bytes they
+    /// watch
+    fn insert_point(&mut self, args: &str) -> Option<String> {
+        let (kind, address, length) = parse_point(args)?;
+        let command = match kind {
+            PointKind::Breakpoint => DebugCmd::Breakpoint(address.into(), None),
+            PointKind::Watchpoint(watch) => DebugCmd::Watchpoint {
+                kind: watch,
+                start: address.into(),
+                end: match length {
+                    0 => return None,
+                    1 => None,
+                    _ => Some(address.checked_add(length)?.into()),
+                },
+                condition: None,
+            },
+        };
+        let id = match self.gameboy.debug_cmd(&command)? {
+            DebugEvent::RegisterBreakpoint(id, _) | DebugEvent::RegisterWatchpoint(id, _) => id,
+            _ => return None,
+        };
+        self.points
+            .entry((kind, address, length))
+            .or_default()
+            .push(id);
+        Some("OK".to_string())
+    }
+
+    fn remove_point(&mut self, args: &str) -> Option<String> {
+        let point = parse_point(args)?;
+        let id = self.points.get_mut(&point)?.pop()?;
+        self.gameboy.debug_cmd(&DebugCmd::Delete(id));
+        Some("OK".to_string())
+    }
+
+    /// Runs until a debug point or the end of the step, or until gdb interrupts
+    fn resume(&mut self, command: DebugCmd) -> io::Result<String> {
+        self.gameboy.get_debug_events().clear();
+        self.gameboy.debug_cmd(&command);
+        let mut steps = 0;
+        while !self.gameboy.paused() {
+            self.gameboy.step();
+            steps += 1;
+            if steps % INTERRUPT_POLL_STEPS == 0 && self.interrupted()? {
+                self.gameboy.set_paused(true);
+                return Ok(Stop::Interrupted.reply());
+            }
+        }
+        let stop = self
+            .gameboy
+            .get_debug_events()
+            .drain(..)
+            .find_map(|event| match event {
+                DebugEvent::Watchpoint(hit) => {
+                    let kind = self.points.iter().find_map(|((kind, ..), ids)| match kind {
+                        PointKind::Watchpoint(watch) if ids.contains(&hit.id) => Some(*watch),
+                        _ => None,
+                    });
+                    Some(Stop::Watch(
+                        kind.unwrap_or(WatchKind::ReadWrite),
+                        hit.address.address,
+                    ))
+                }
+                _ => None,
+            });
+        Ok(stop.unwrap_or(Stop::Trap).reply())
+    }
+
+    /// Whether gdb sent a ^C while the game was running
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let read = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match read {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the next `$packet#checksum`, or nothing once gdb disconnected
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0];
+        loop {
+            if self.stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+            // Acks, and interrupts while already stopped
+        }
+        let mut data = Vec::new();
+        loop {
+            if self.stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                b'#' => break,
+                b'}' => {
+                    self.stream.read_exact(&mut byte)?;
+                    data.push(byte[0] ^ 0x20);
+                }
+                b => data.push(b),
+            }
+        }
+        let mut checksum = [0; 2];
+        self.stream.read_exact(&mut checksum)?;
+        if self.ack {
+            self.stream.write_all(b"+")?;
+        }
+        Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let mut escaped = Vec::with_capacity(data.len());
+        for &b in data.as_bytes() {
+            if matches!(b, b'$' | b'#' | b'}' | b'*') {
+                escaped.extend([b'}', b ^ 0x20]);
+            } else {
+                escaped.push(b);
+            }
+        }
+        let checksum = escaped.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        self.stream.write_all(b"$")?;
+        self.stream.write_all(&escaped)?;
+        write!(self.stream, "#{checksum:02x}")?;
+        self.stream.flush()
+    }
+}
+
+fn parse_address_length(args: &str) -> Option<(usize, usize)> {
+    let (address, length) = args.split_once(',')?;
+    Some((
+        usize::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+/// Parses `type,address,kind` into the kind of point, its address and its kind field
+fn parse_point(args: &str) -> Option<(PointKind, u16, u16)> {
+    let mut fields = args.split(',');
+    let kind = PointKind::from_type(fields.next()?.parse().ok()?)?;
+    let address = u16::from_str_radix(fields.next()?, 16).ok()?;
+    let length = u16::from_str_radix(fields.next()?, 16).ok()?;
+    Some((kind, address, length))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    /// The gdb side of the connection
+    struct Client(TcpStream);
+
+    impl Client {
+        fn send(&mut self, data: &str) -> String {
+            let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            write!(self.0, "${data}#{checksum:02x}").unwrap();
+            let mut ack = [0];
+            self.0.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            self.receive()
+        }
+
+        fn receive(&mut self) -> String {
+            let mut byte = [0];
+            let mut reply = Vec::new();
+            self.0.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'$');
+            loop {
+                self.0.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.0.read_exact(&mut checksum).unwrap();
+            let sum = reply.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            assert_eq!(
+                std::str::from_utf8(&checksum).unwrap(),
+                format!("{sum:02x}")
+            );
+            self.0.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_gdb_session() {
+        let mut rom = vec![0; 0x8000];
+        // NOP; LD A, $42; LD [$C000], A; .loop: JR .loop
+        rom[0x100..0x108].copy_from_slice(&[0x00, 0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x18, 0xFE]);
+        let mut gb = Gameboy::new();
+        gb.load_rom(&rom);
+        gb.cpu_mut().set_pc(0x100);
+        gb.cpu_mut().set_sp(0xFFFE);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut gdb = Client(TcpStream::connect(address).unwrap());
+            assert!(gdb
+                .send("qSupported:swbreak+")
+                .contains("qXfer:features:read+"));
+            assert!(gdb
+                .send("qXfer:features:read:target.xml:0,1000")
+                .starts_with("l<?xml"));
+            assert_eq!(gdb.send("?"), "S05");
+            assert_eq!(gdb.send("vMustReplyEmpty"), "");
+            assert_eq!(gdb.send("g"), "0000000000000000feff0001");
+            assert_eq!(gdb.send("m101,2"), "3e42");
+
+            assert_eq!(gdb.send("Z0,103,1"), "OK");
+            assert_eq!(gdb.send("c"), "S05");
+            assert_eq!(gdb.send("p5"), "0301");
+            assert_eq!(gdb.send("z0,103,1"), "OK");
+            assert_eq!(gdb.send("z0,103,1"), "E01");
+
+            // Watching 0xC001 misses the write, watching 2 bytes from 0xBFFF sees it
+            assert_eq!(gdb.send("Z2,c001,1"), "OK");
+            assert_eq!(gdb.send("Z2,bfff,2"), "OK");
+            assert_eq!(gdb.send("Z2,fffe,2"), "E01");
+            assert_eq!(gdb.send("c"), "T05watch:c000;");
+            assert_eq!(gdb.send("mc000,1"), "42");
+            assert_eq!(gdb.send("z2,bfff,1"), "E01");
+            assert_eq!(gdb.send("z2,bfff,2"), "OK");
+            assert_eq!(gdb.send("z2,c001,1"), "OK");
+
+            assert_eq!(gdb.send("Mc001,2:99aa"), "OK");
+            assert_eq!(gdb.send("mc001,2"), "99aa");
+            assert_eq!(gdb.send("P2=3412"), "OK");
+            assert_eq!(gdb.send("p2"), "3412");
+            assert_eq!(gdb.send("P9=0000"), "E01");
+
+            assert_eq!(gdb.send("P5=0601"), "OK");
+            assert_eq!(gdb.send("s"), "S05");
+            assert_eq!(gdb.send("p5"), "0601");
+
+            // Runs the loop until interrupted
+            write!(gdb.0, "$c#63").unwrap();
+            let mut ack = [0];
+            gdb.0.read_exact(&mut ack).unwrap();
+            gdb.0.write_all(&[0x03]).unwrap();
+            assert_eq!(gdb.receive(), "S02");
+
+            write!(gdb.0, "$k#6b").unwrap();
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        GdbSession::new(&mut gb, stream).serve().unwrap();
+        client.join().unwrap();
+        assert_eq!(gb.bus().read(0xC001), 0x99);
+        assert_eq!(gb.cpu().de(), 0x1234);
+    }
+}
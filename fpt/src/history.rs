@@ -0,0 +1,390 @@
+//! Reverse execution for the debugger. Snapshots of the whole `Gameboy` are taken as it
+//! runs, and any point in between is reached by running again from the snapshot before
+//! it. The emulator being deterministic, the run goes the same way the second time.
+
+use std::collections::VecDeque;
+
+use crate::debug_interface::{DebugEvent, SymbolicAddress};
+use crate::lr35902::CpuState;
+use crate::memory::{Buttons, MemoryState};
+use crate::ppu::{Ppu, DOTS_IN_ONE_FRAME};
+use crate::timer::Timer;
+use crate::Gameboy;
+
+struct Snapshot {
+    /// `Gameboy::ticks` when it was taken
+    ticks: u64,
+    memory: MemoryState,
+    cpu: CpuState,
+    ppu: Ppu,
+    timer: Timer,
+}
+
+/// Snapshots taken every `interval` ticks, and the buttons pressed in between so that
+/// running again from a snapshot gets the same input
+pub struct History {
+    interval: u64,
+    capacity: usize,
+    snapshots: VecDeque<Snapshot>,
+    inputs: VecDeque<(u64, Buttons)>,
+}
+
+impl History {
+    /// Keeps at most `capacity` snapshots, so that going back takes up to
+    /// `interval * capacity` ticks
+    pub fn new(interval: u64, capacity: usize) -> Self {
+        Self {
+            interval,
+            capacity,
+            snapshots: VecDeque::new(),
+            inputs: VecDeque::new(),
+        }
+    }
+
+    /// A snapshot every frame, going back `frames` frames. Each snapshot copies all of
+    /// memory and the PPU, a few hundred KB.
+    pub fn frames(frames: usize) -> Self {
+        Self::new(DOTS_IN_ONE_FRAME as u64, frames)
+    }
+
+    /// How far back in ticks the history goes
+    pub fn start(&self) -> Option<u64> {
+        self.snapshots.front().map(|snapshot| snapshot.ticks)
+    }
+
+    /// Drops everything from `ticks` on, which is about to run differently
+    fn truncate(&mut self, ticks: u64) {
+        self.snapshots.retain(|snapshot| snapshot.ticks < ticks);
+        self.inputs.retain(|(at, _)| *at < ticks);
+    }
+}
+
+impl Default for History {
+    /// A snapshot every frame, going back about ten seconds
+    fn default() -> Self {
+        Self::frames(600)
+    }
+}
+
+impl Gameboy {
+    /// Starts or stops recording history for reverse execution, which is off by default
+    /// as it takes a lot of memory. Only [`Gameboy::step`] is recorded.
+    pub fn set_history(&mut self, history: Option<History>) {
+        self.history = history;
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Takes a snapshot if one is due
+    pub(crate) fn record(&mut self) {
+        let Some(history) = &self.history else {
+            return;
+        };
+        let due = history
+            .snapshots
+            .back()
+            .map_or(true, |last| self.ticks >= last.ticks + history.interval);
+        if !due {
+            return;
+        }
+        let snapshot = self.snapshot();
+        let history = self.history.as_mut().unwrap();
+        history.snapshots.push_back(snapshot);
+        if history.snapshots.len() > history.capacity {
+            history.snapshots.pop_front();
+            let start = history.snapshots.front().unwrap().ticks;
+            while history.inputs.front().is_some_and(|(at, _)| *at < start) {
+                history.inputs.pop_front();
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            ticks: self.ticks,
+            memory: self.bus.save_state(),
+            cpu: self.cpu.save_state(),
+            ppu: self.ppu.clone(),
+            timer: self.timer.clone(),
+        }
+    }
+
+    pub(crate) fn record_input(&mut self, buttons: &Buttons) {
+        if let Some(history) = &mut self.history {
+            history.inputs.push_back((self.ticks, *buttons));
+        }
+    }
+
+    /// Goes back to the previous instruction
+    pub(crate) fn reverse_step(&mut self) -> DebugEvent {
+        let Some(snapshots) = self.history.as_ref().map(|h| h.snapshots.len()) else {
+            return no_history();
+        };
+        let current = if self.at_instruction_start() {
+            self.ticks
+        } else {
+            self.instruction_start
+        };
+
+        for index in (0..snapshots).rev() {
+            if self.snapshot_ticks(index) >= current {
+                continue;
+            }
+            self.restore(index);
+            let mut previous = None;
+            while self.ticks < current {
+                if self.at_instruction_start() {
+                    previous = Some(self.ticks);
+                }
+                self.replay_step();
+            }
+            if let Some(previous) = previous {
+                self.restore(index);
+                self.replay_to(previous);
+                self.stop_at_present();
+                let pc = self.pc_address();
+                self.get_debug_events().push_back(DebugEvent::Stop(pc));
+                return DebugEvent::ReverseStep;
+            }
+        }
+        self.stop_at_history_start()
+    }
+
+    /// Goes back to the previous breakpoint or watchpoint hit
+    pub(crate) fn reverse_continue(&mut self) -> DebugEvent {
+        let Some(snapshots) = self.history.as_ref().map(|h| h.snapshots.len()) else {
+            return no_history();
+        };
+        let end = self.ticks;
+        // Looking for hits changes hit counts, which have to be put back
+        let debugger = self.cpu.debugger().clone();
+
+        for index in (0..snapshots).rev() {
+            if self.snapshot_ticks(index) >= end {
+                continue;
+            }
+            let segment_end = if index + 1 < snapshots {
+                self.snapshot_ticks(index + 1).min(end)
+            } else {
+                end
+            };
+            self.restore(index);
+            self.cpu.debugger_mut().set_muted(false);
+            let mut last_hit = None;
+            while self.ticks < segment_end {
+                let before = self.ticks;
+                self.replay_step();
+                if self.cpu.debugger().paused {
+                    // Breakpoints stop before their instruction and watchpoints after, which
+                    // is where the current hit is if stopped at one
+                    if self.ticks < end {
+                        last_hit = Some(before);
+                    }
+                    let debugger = self.cpu.debugger_mut();
+                    debugger.set_paused(false);
+                    debugger.debug_events().clear();
+                }
+            }
+            *self.cpu.debugger_mut() = debugger.clone();
+            if let Some(hit) = last_hit {
+                self.restore(index);
+                self.replay_to(hit);
+                // Running into the hit again leaves its event to the caller
+                self.cpu.debugger_mut().set_muted(false);
+                self.replay_step();
+                self.stop_at_present();
+                return DebugEvent::ReverseContinue;
+            }
+        }
+        self.stop_at_history_start()
+    }
+
+    fn snapshot_ticks(&self, index: usize) -> u64 {
+        self.history.as_ref().unwrap().snapshots[index].ticks
+    }
+
+    /// Puts the whole `Gameboy` back to snapshot `index`, ready to replay from there with
+    /// the debugger muted
+    fn restore(&mut self, index: usize) {
+        let history = self.history.as_ref().unwrap();
+        let snapshot = &history.snapshots[index];
+        self.bus.load_state(&snapshot.memory);
+        self.cpu.load_state(&snapshot.cpu);
+        self.ppu = snapshot.ppu.clone();
+        self.timer = snapshot.timer.clone();
+        self.ticks = snapshot.ticks;
+        self.instruction_start = snapshot.ticks;
+        let debugger = self.cpu.debugger_mut();
+        debugger.rewind();
+        debugger.set_muted(true);
+    }
+
+    /// Runs one step as it ran the first time, with the same buttons pressed
+    fn replay_step(&mut self) {
+        let history = self.history.as_ref().unwrap();
+        let buttons = history
+            .inputs
+            .iter()
+            .filter(|(at, _)| *at == self.ticks)
+            .last()
+            .map(|(_, buttons)| *buttons);
+        if let Some(buttons) = buttons {
+            self.bus.set_buttons(&buttons);
+        }
+        self.run_step();
+    }
+
+    fn replay_to(&mut self, ticks: u64) {
+        while self.ticks < ticks {
+            self.replay_step();
+        }
+    }
+
+    /// Makes where the replay got to the present, paused
+    fn stop_at_present(&mut self) {
+        // The snapshot of the present is taken again, as the input from here on may change
+        let snapshot = self.snapshot();
+        let history = self.history.as_mut().unwrap();
+        history.truncate(self.ticks);
+        history.snapshots.push_back(snapshot);
+        let debugger = self.cpu.debugger_mut();
+        debugger.set_muted(false);
+        debugger.set_paused(true);
+    }
+
+    fn stop_at_history_start(&mut self) -> DebugEvent {
+        let has_snapshots = self.history.as_ref().unwrap().start().is_some();
+        if !has_snapshots {
+            return no_history();
+        }
+        self.restore(0);
+        self.stop_at_present();
+        DebugEvent::HistoryStart(self.pc_address())
+    }
+
+    fn pc_address(&self) -> SymbolicAddress {
+        self.cpu.debugger().symbolic_address(self.cpu.pc(), None)
+    }
+}
+
+fn no_history() -> DebugEvent {
+    DebugEvent::Error("no history recorded to go back in".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug_interface::{DebugCmd, WatchKind};
+
+    fn run(gb: &mut Gameboy) {
+        for _ in 0..10_000 {
+            if gb.paused() {
+                return;
+            }
+            gb.step();
+        }
+        panic!("didn't stop");
+    }
+
+    fn last_write(gb: &mut Gameboy) -> Option<u8> {
+        gb.get_debug_events()
+            .drain(..)
+            .last()
+            .and_then(|event| match event {
+                DebugEvent::Watchpoint(hit) => Some(hit.new),
+                _ => None,
+            })
+    }
+
+    #[test]
+    fn test_reverse() {
+        let mut rom = vec![0; 0x8000];
+        // ld hl, $C000; .loop: inc a; ld [hl], a; jr .loop
+        rom[0x100..0x107].copy_from_slice(&[0x21, 0x00, 0xC0, 0x3C, 0x77, 0x18, 0xFC]);
+        let mut gb = Gameboy::new();
+        gb.load_rom(&rom);
+        gb.cpu_mut().set_pc(0x100);
+        gb.set_history(Some(History::new(50, 100)));
+        gb.debug_cmd(&DebugCmd::Watchpoint {
+            kind: WatchKind::Write,
+            start: 0xC000.into(),
+            end: None,
+            condition: None,
+        });
+
+        for value in 1..=3 {
+            run(&mut gb);
+            assert_eq!(last_write(&mut gb), Some(value));
+            gb.debug_cmd(&DebugCmd::Continue);
+        }
+        run(&mut gb);
+        assert_eq!(last_write(&mut gb), Some(4));
+
+        assert_eq!(
+            gb.debug_cmd(&DebugCmd::ReverseContinue),
+            Some(DebugEvent::ReverseContinue)
+        );
+        assert_eq!(last_write(&mut gb), Some(3));
+        assert_eq!(gb.bus().read(0xC000), 3);
+        gb.debug_cmd(&DebugCmd::ReverseContinue);
+        assert_eq!(last_write(&mut gb), Some(2));
+
+        // Back to before the write
+        assert_eq!(
+            gb.debug_cmd(&DebugCmd::ReverseStep),
+            Some(DebugEvent::ReverseStep)
+        );
+        assert_eq!(gb.cpu().pc(), 0x104);
+        assert_eq!(gb.cpu().a(), 2);
+        assert_eq!(gb.bus().read(0xC000), 1);
+
+        // Running forward again goes the same way
+        gb.debug_cmd(&DebugCmd::Continue);
+        run(&mut gb);
+        assert_eq!(last_write(&mut gb), Some(2));
+        gb.debug_cmd(&DebugCmd::Continue);
+        run(&mut gb);
+        assert_eq!(last_write(&mut gb), Some(3));
+
+        // The first write is the furthest back there is to go
+        gb.debug_cmd(&DebugCmd::ReverseContinue);
+        gb.debug_cmd(&DebugCmd::ReverseContinue);
+        assert_eq!(last_write(&mut gb), Some(1));
+        assert!(matches!(
+            gb.debug_cmd(&DebugCmd::ReverseContinue),
+            Some(DebugEvent::HistoryStart(_))
+        ));
+        assert_eq!(gb.cpu().pc(), 0x100);
+    }
+
+    #[test]
+    fn test_reverse_continue_to_breakpoint() {
+        let mut rom = vec![0; 0x8000];
+        // .loop: inc a; jr .loop
+        rom[0x100..0x103].copy_from_slice(&[0x3C, 0x18, 0xFD]);
+        let mut gb = Gameboy::new();
+        gb.load_rom(&rom);
+        gb.cpu_mut().set_pc(0x100);
+        gb.set_history(Some(History::new(10, 100)));
+        gb.debug_cmd(&DebugCmd::Breakpoint(0x101.into(), None));
+        for _ in 0..3 {
+            gb.debug_cmd(&DebugCmd::Continue);
+            run(&mut gb);
+        }
+        assert_eq!(gb.cpu().a(), 3);
+
+        gb.get_debug_events().clear();
+        gb.debug_cmd(&DebugCmd::ReverseContinue);
+        assert_eq!(gb.cpu().a(), 2);
+        assert_eq!(gb.cpu().pc(), 0x101);
+        assert!(matches!(
+            gb.get_debug_events().back(),
+            Some(DebugEvent::Breakpoint(1, _))
+        ));
+        gb.debug_cmd(&DebugCmd::Continue);
+        run(&mut gb);
+        assert_eq!(gb.cpu().a(), 3);
+    }
+}
@@ -5,24 +5,43 @@
 use std::collections::VecDeque;
 
 pub use debug_interface::{DebugCmd, DebugEvent, DebugInterface};
+use history::History;
 use lr35902::LR35902;
-use memory::{Bus, Buttons};
-use ppu::{Frame, Ppu, DOTS_IN_ONE_FRAME};
+use memory::{map, Bus, Buttons};
+use model::Model;
+use movie::MovieState;
+use ppu::{Frame, Ppu, RgbFrame, DOTS_IN_ONE_FRAME};
 use timer::Timer;
+use trace::Tracer;
 
 pub mod bw;
+pub mod cheats;
 pub mod debug_interface;
 pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod history;
 pub mod lr35902;
 pub mod memory;
+pub mod model;
+pub mod movie;
 pub mod ppu;
+pub mod sgb;
 pub mod timer;
+pub mod trace;
 
 pub struct Gameboy {
     bus: Bus,
     cpu: LR35902,
     ppu: Ppu,
     timer: Timer,
+    tracer: Option<Tracer>,
+    history: Option<History>,
+    movie: Option<MovieState>,
+    /// Steps run so far, which is how history tells points in time apart
+    ticks: u64,
+    /// `ticks` when the instruction running started
+    instruction_start: u64,
 }
 
 impl Gameboy {
@@ -34,31 +53,64 @@ impl Gameboy {
             cpu: LR35902::new(bus.clone()),
             ppu: Ppu::new(bus.clone()),
             timer: Timer::new(bus),
+            tracer: None,
+            history: None,
+            movie: None,
+            ticks: 0,
+            instruction_start: 0,
         }
     }
 
+    /// Runs the boot ROM of the model, or fakes booting when there's none
     pub fn boot_real(&mut self) {
+        if !self.bus.has_bootrom() {
+            self.boot_fake();
+            return;
+        }
         self.bus_mut().load_bootrom();
         // Bootrom will be unloaded when it finishes - one of the last instructions
         // is writing to the BANK register which will trigger unload_bootrom
     }
 
-    /// Sets CPU and hardware registers to the values found in the DMG0 column in the tables at
-    /// <https://gbdev.io/pandocs/Power_Up_Sequence.html#console-state-after-boot-rom-hand-off>
+    /// Sets CPU and hardware registers to the values the boot ROM of the model leaves, as in
+    /// the tables at
+    /// <https://gbdev.io/pandocs/Power_Up_Sequence.html#console-state-after-boot-rom-hand-off>.
+    /// Registers the tables don't know the value of for a model get the DMG one.
     pub fn boot_fake(&mut self) {
+        let model = self.model();
+        let cgb = self.bus.cgb();
+
         // CPU registers
-        self.cpu.set_af(0x0100);
-        self.cpu.set_bc(0xff13);
-        self.cpu.set_de(0x00c1);
-        self.cpu.set_hl(0x8403);
+        // H and C are only set by the DMG and MGB boot ROMs when the header checksum isn't 0
+        let flags = if self.bus.read(map::HEADER_CHECKSUM) == 0 {
+            0x80
+        } else {
+            0xB0
+        };
+        let [af, bc, de, hl] = match model {
+            Model::Dmg0 => [0x0100, 0xff13, 0x00c1, 0x8403],
+            Model::DmgAbc => [0x0100 | flags, 0x0013, 0x00d8, 0x014d],
+            Model::Mgb => [0xff00 | flags, 0x0013, 0x00d8, 0x014d],
+            Model::Sgb => [0x0100, 0x0014, 0x0000, 0xc060],
+            Model::Sgb2 => [0xff00, 0x0014, 0x0000, 0xc060],
+            // A = 0x11 is how games know they run on a CGB
+            Model::Cgb if cgb => [0x1180, 0x0000, 0xff56, 0x000d],
+            Model::Cgb => [0x1180, 0x0000, 0x0008, 0x007c],
+        };
+        self.cpu.set_af(af);
+        self.cpu.set_bc(bc);
+        self.cpu.set_de(de);
+        self.cpu.set_hl(hl);
         self.cpu.set_sp(0xfffe);
         self.cpu.set_pc(0x100); // This skips executing the bootrom
 
         // HW registers
         self.bus.write(0xFF00, 0xCF); // P1
         self.bus.write(0xFF01, 0x00); // SB
-        self.bus.write(0xFF02, 0x7E); // SC
-        self.bus.write(0xFF04, 0x18); // DIV
+        self.bus
+            .write(0xFF02, if model == Model::Cgb { 0x7F } else { 0x7E }); // SC
+        self.bus
+            .write(0xFF04, if model == Model::Dmg0 { 0x18 } else { 0xAB }); // DIV
         self.bus.write(0xFF05, 0x00); // TIMA
         self.bus.write(0xFF06, 0x00); // TMA
         self.bus.write(0xFF07, 0xF8); // TAC
@@ -83,25 +135,57 @@ impl Gameboy {
         self.bus.write(0xFF23, 0xBF); // NR44
         self.bus.write(0xFF24, 0x77); // NR50
         self.bus.write(0xFF25, 0xF3); // NR51
-        self.bus.write(0xFF26, 0xF1); // NR52
+        self.bus
+            .write(0xFF26, if model.sgb() { 0xF0 } else { 0xF1 }); // NR52
         self.bus.write(0xFF40, 0x91); // LCDC
-        self.bus.write(0xFF41, 0x81); // STAT
+        self.bus
+            .write(0xFF41, if model == Model::Dmg0 { 0x81 } else { 0x85 }); // STAT
         self.bus.write(0xFF42, 0x00); // SCY
         self.bus.write(0xFF43, 0x00); // SCX
-        self.bus.write(0xFF44, 0x91); // LY
+        self.bus
+            .write(0xFF44, if model == Model::Dmg0 { 0x91 } else { 0x00 }); // LY
         self.bus.write(0xFF45, 0x00); // LYC
-        self.bus.write(0xFF46, 0xFF); // DMA
+        self.bus
+            .write(0xFF46, if model == Model::Cgb { 0x00 } else { 0xFF }); // DMA
         self.bus.write(0xFF47, 0xFC); // BGP
         self.bus.write(0xFF48, 0x00); // OBP0
         self.bus.write(0xFF49, 0x00); // OBP1
         self.bus.write(0xFF4A, 0x00); // WY
         self.bus.write(0xFF4B, 0x00); // WX
+
+        if cgb {
+            self.bus.write(0xFF4D, 0x00); // KEY1
+            self.bus.write(0xFF4F, 0x00); // VBK
+            self.bus.write(0xFF70, 0x00); // SVBK
+
+            // The boot ROM leaves every BG color white
+            self.bus.write(0xFF68, 0x80); // BCPS
+            for _ in 0..32 {
+                self.bus.write(0xFF69, 0xFF); // BCPD
+                self.bus.write(0xFF69, 0x7F);
+            }
+        }
     }
 
     pub fn load_rom(&mut self, rom: &[u8]) {
         self.bus.load_rom(rom);
     }
 
+    /// Runs games as `model` does, which should be chosen before booting
+    pub fn set_model(&mut self, model: Model) {
+        self.bus.set_model(model);
+    }
+
+    /// The model chosen, or else the one the loaded game is best played on
+    pub fn model(&self) -> Model {
+        self.bus.model()
+    }
+
+    /// Replaces the boot ROM that [`Gameboy::boot_real`] runs with one of the model's
+    pub fn set_bootrom(&mut self, bootrom: &[u8]) -> Result<(), String> {
+        self.bus.set_bootrom(bootrom)
+    }
+
     pub fn bus(&self) -> &Bus {
         &self.bus
     }
@@ -130,24 +214,68 @@ impl Gameboy {
         &mut self.timer
     }
 
+    /// Traces every instruction run from now on, or stops tracing. Returns the previous
+    /// tracer, to finish it.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    /// Whether the next step starts running an instruction
+    fn at_instruction_start(&self) -> bool {
+        self.cpu.inst_cycle_count() == 0
+            && !self.cpu.halted()
+            && !self.cpu.stopped()
+            && !self.bus.dma_stalled()
+    }
+
+    fn trace(&mut self) {
+        // Only once per instruction, before it runs
+        if self.tracer.is_some() && self.at_instruction_start() {
+            let tracer = self.tracer.as_mut().unwrap();
+            tracer.trace(&self.cpu, &self.bus);
+        }
+    }
+
     pub fn step(&mut self) -> u8 {
+        self.trace();
+        self.record();
+        self.run_step()
+    }
+
+    fn run_step(&mut self) -> u8 {
+        if self.at_instruction_start() {
+            self.instruction_start = self.ticks;
+        }
         let cycles = self.cpu.step();
-        // TODO: care for double speed mode (need to run half as much dots)
-        self.ppu.step(cycles as u32);
+        self.ppu.step_cpu(cycles as u32);
         self.timer.step(self.cpu.clock_cycles());
+        self.movie_step();
+        // Nothing ran if stopped at a breakpoint
+        if cycles > 0 {
+            self.ticks += 1;
+        }
         cycles
     }
 
     pub fn instruction(&mut self) -> u32 {
+        self.trace();
         let cycles = self.cpu.instruction() as u32;
-        // TODO: care for double speed mode (need to run half as much dots)
-        self.ppu.step(cycles);
+        self.ppu.step_cpu(cycles);
         self.timer.step(self.cpu.clock_cycles());
+        self.movie_step();
         cycles
     }
 
     pub fn debug_cmd(&mut self, cmd: &DebugCmd) -> Option<DebugEvent> {
-        self.cpu_mut().receive_command(cmd)
+        match cmd {
+            DebugCmd::ReverseStep => Some(self.reverse_step()),
+            DebugCmd::ReverseContinue => Some(self.reverse_continue()),
+            _ => self.cpu_mut().receive_command(cmd),
+        }
     }
 
     pub fn paused(&self) -> bool {
@@ -166,12 +294,39 @@ impl Gameboy {
         self.ppu.get_frame()
     }
 
+    pub fn get_rgb_frame(&self) -> &RgbFrame {
+        self.ppu.get_rgb_frame()
+    }
+
+    /// The SGB picture, `SGB_WIDTH` x `SGB_HEIGHT` with the border, if it's a SGB
+    pub fn get_sgb_frame(&self) -> Option<Vec<u32>> {
+        self.bus.sgb_frame()
+    }
+
+    /// T-cycles the CPU runs in a frame, twice as many in double speed mode
     pub fn cycles_in_one_frame(&self) -> u32 {
-        // TODO: care for double speed mode
-        DOTS_IN_ONE_FRAME
+        if self.double_speed() {
+            2 * DOTS_IN_ONE_FRAME
+        } else {
+            DOTS_IN_ONE_FRAME
+        }
+    }
+
+    /// Whether the CPU runs at twice its normal speed (CGB)
+    pub fn double_speed(&self) -> bool {
+        self.bus.double_speed()
     }
 
     pub fn set_buttons(&mut self, buttons: &Buttons) {
+        if !self.movie_input(buttons) {
+            self.press(buttons);
+        }
+    }
+
+    fn press(&mut self, buttons: &Buttons) {
+        if self.bus.buttons() != *buttons {
+            self.record_input(buttons);
+        }
         self.bus_mut().set_buttons(buttons)
     }
 }
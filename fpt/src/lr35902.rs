@@ -1,17 +1,21 @@
 use std::collections::VecDeque;
 use std::fmt;
 
+use call_stack::{CallStack, Frame, FrameKind};
 use instructions::{Instruction, InstructionKind, INSTRUCTIONS};
 
 use super::memory::Bus;
-use crate::debug_interface::{DebugCmd, DebugEvent, DebugInterface};
+use crate::debug_interface::expr::Register;
+use crate::debug_interface::{AccessKind, DebugCmd, DebugEvent, DebugInterface, Registers};
 use crate::debugger::Debugger;
+use crate::disasm::{self, DecodedInstruction};
 use crate::ppu::Mode;
 use crate::{bw, memory};
 
+pub mod call_stack;
 pub mod instructions;
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone)]
 pub struct LR35902 {
     af: u16,
     bc: u16,
@@ -26,8 +30,59 @@ pub struct LR35902 {
     inst_cycle_count: u8,
     branch_taken: bool,
     halted: bool,
+    stopped: Option<Stop>,
     bus: Bus,
     debugger: Debugger,
+    call_stack: CallStack,
+}
+
+/// The CPU's part of a snapshot: everything but the bus and the debugger
+#[derive(Clone)]
+pub struct CpuState {
+    registers: Registers,
+    ime: bool,
+    ime_next_inst: bool,
+    prefix_cb: bool,
+    clock_cycles: u64,
+    inst_cycle_count: u8,
+    branch_taken: bool,
+    halted: bool,
+    stopped: Option<Stop>,
+    call_stack: CallStack,
+}
+
+/// What the CPU waits for after STOP
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Stop {
+    /// Low power mode, until a button is pressed
+    Button,
+    /// A speed switch (CGB), for this many more t-cycles
+    SpeedSwitch(u32),
+}
+
+/// How long a speed switch stops the CPU for: 2050 M-cycles, while DIV doesn't tick
+/// <https://gbdev.io/pandocs/CGB_Registers.html#ff4d--key1-cgb-mode-only-prepare-speed-switch>
+const SPEED_SWITCH_CYCLES: u32 = 2050 * 4;
+
+/// Compares the state of the CPU, leaving out what is only kept for debugging
+impl PartialEq for LR35902 {
+    fn eq(&self, other: &Self) -> bool {
+        self.af == other.af
+            && self.bc == other.bc
+            && self.de == other.de
+            && self.hl == other.hl
+            && self.sp == other.sp
+            && self.pc == other.pc
+            && self.ime == other.ime
+            && self.ime_next_inst == other.ime_next_inst
+            && self.prefix_cb == other.prefix_cb
+            && self.clock_cycles == other.clock_cycles
+            && self.inst_cycle_count == other.inst_cycle_count
+            && self.branch_taken == other.branch_taken
+            && self.halted == other.halted
+            && self.stopped == other.stopped
+            && self.bus == other.bus
+    }
 }
 
 impl Default for LR35902 {
@@ -44,7 +99,21 @@ impl fmt::Debug for LR35902 {
 
 impl DebugInterface for LR35902 {
     fn receive_command(&mut self, cmd: &DebugCmd) -> Option<DebugEvent> {
-        self.debugger.receive_command(cmd)
+        match cmd {
+            // The debugger only gets a copy of the registers
+            DebugCmd::SetRegister(register, value) => {
+                if register.is_8bit() && *value > 0xFF {
+                    return Some(DebugEvent::Error(format!(
+                        "{value:#X} does not fit in {register}"
+                    )));
+                }
+                self.set_register(*register, *value);
+                Some(DebugEvent::SetRegister(*register, *value))
+            }
+            _ => self
+                .debugger
+                .receive_command(cmd, &self.registers(), &self.call_stack),
+        }
     }
 
     fn paused(&self) -> bool {
@@ -52,7 +121,7 @@ impl DebugInterface for LR35902 {
     }
 
     fn set_paused(&mut self, paused: bool) {
-        self.debugger.paused = paused;
+        self.debugger.set_paused(paused);
     }
 
     fn get_debug_events(&mut self) -> &mut VecDeque<DebugEvent> {
@@ -76,9 +145,11 @@ impl LR35902 {
             inst_cycle_count: 0,
             branch_taken: false,
             halted: false,
+            stopped: None,
             bus: bus.clone(),
             // Debugging
             debugger: Debugger::new(bus.clone()),
+            call_stack: CallStack::default(),
         }
     }
 
@@ -187,6 +258,46 @@ impl LR35902 {
         self.sp = sp;
     }
 
+    /// The value of a register by name, as the debugger's expressions refer to them
+    pub fn register(&self, register: Register) -> u16 {
+        match register {
+            Register::A => self.a() as u16,
+            Register::F => self.f() as u16,
+            Register::B => self.b() as u16,
+            Register::C => self.c() as u16,
+            Register::D => self.d() as u16,
+            Register::E => self.e() as u16,
+            Register::H => self.h() as u16,
+            Register::L => self.l() as u16,
+            Register::AF => self.af,
+            Register::BC => self.bc,
+            Register::DE => self.de,
+            Register::HL => self.hl,
+            Register::SP => self.sp,
+            Register::PC => self.pc,
+        }
+    }
+
+    /// Sets a register, truncating `value` to 8 bits for the halves of register pairs
+    pub fn set_register(&mut self, register: Register, value: u16) {
+        match register {
+            Register::A => self.set_a(value as u8),
+            Register::F => self.set_f(value as u8),
+            Register::B => self.set_b(value as u8),
+            Register::C => self.set_c(value as u8),
+            Register::D => self.set_d(value as u8),
+            Register::E => self.set_e(value as u8),
+            Register::H => self.set_h(value as u8),
+            Register::L => self.set_l(value as u8),
+            Register::AF => self.set_af(value),
+            Register::BC => self.set_bc(value),
+            Register::DE => self.set_de(value),
+            Register::HL => self.set_hl(value),
+            Register::SP => self.set_sp(value),
+            Register::PC => self.set_pc(value),
+        }
+    }
+
     // flags
     pub fn z_flag(&self) -> bool {
         bw::test_bit16::<7>(self.af)
@@ -257,6 +368,15 @@ impl LR35902 {
         self.branch_taken = branch_taken;
     }
 
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Whether STOP stopped the CPU, for a speed switch or until a button is pressed
+    pub fn stopped(&self) -> bool {
+        self.stopped.is_some()
+    }
+
     pub fn inst_cycle_count(&self) -> u8 {
         self.inst_cycle_count
     }
@@ -267,8 +387,9 @@ impl LR35902 {
 
     // Memory
     pub fn mem8(&self, index: u16) -> u8 {
-        // TODO: watchpoint trigger read
-        self.bus.read(index as usize)
+        let value = self.bus.read(index as usize);
+        self.debugger.watch_read(index, value, false);
+        value
     }
 
     pub fn mem16(&self, index: u16) -> u16 {
@@ -276,8 +397,19 @@ impl LR35902 {
     }
 
     pub fn set_mem8(&mut self, index: u16, value: u8) {
+        let old = self
+            .debugger
+            .is_watched(index, AccessKind::Write)
+            .then(|| self.bus.read(index as usize));
+        let old_oam = (index == memory::map::DMA as u16 && self.debugger.watches_dma())
+            .then(|| self.bus.copy_range(memory::map::OAM));
         self.bus.write(index as usize, value);
-        // TODO: watchpoint trigger write
+        if let Some(old) = old {
+            self.debugger.watch_write(index, old, value, false);
+        }
+        if let Some(old_oam) = old_oam {
+            self.watch_oam_dma(value, &old_oam);
+        }
         // Write triggers (TODO: better solution)
         if (index == memory::map::BANK as u16) && (value != 0) {
             self.bus.unload_bootrom();
@@ -296,15 +428,32 @@ impl LR35902 {
         self.set_mem8(index, bw::get_byte16::<0>(value));
     }
 
+    /// Lets watchpoints see the reads and writes of the OAM DMA transfer from `source`
+    /// that just replaced `old_oam`
+    fn watch_oam_dma(&self, source: u8, old_oam: &[u8]) {
+        let source = (source as u16) << 8;
+        for (i, old) in old_oam.iter().enumerate() {
+            let oam = memory::map::OAM.start as u16 + i as u16;
+            let value = self.bus.read(oam as usize);
+            self.debugger.watch_read(source + i as u16, value, true);
+            self.debugger.watch_write(oam, *old, value, true);
+        }
+    }
+
     // Decoding
+    /// Reads memory as part of an instruction, which watchpoints don't see
+    fn fetch8(&self, index: u16) -> u8 {
+        self.bus.read(index as usize)
+    }
+
     /// get 8 bit immediate at position pc + 1 + pos
     fn get_d8(&self, pos: u8) -> u8 {
-        self.mem8(self.pc + pos as u16 + 1)
+        self.fetch8(self.pc + pos as u16 + 1)
     }
 
     /// get 8 bit immediate at position pc + 1 + pos
     fn get_r8(&self, pos: u8) -> i8 {
-        self.mem8(self.pc + pos as u16 + 1) as i8
+        self.fetch8(self.pc + pos as u16 + 1) as i8
     }
 
     /// get 16 bit immediate at position pc + 1 + pos
@@ -322,7 +471,7 @@ impl LR35902 {
     }
 
     pub fn decode(&self) -> Instruction {
-        let mut opcode = self.mem8(self.pc()) as u16;
+        let mut opcode = self.fetch8(self.pc()) as u16;
         if self.prefix_cb {
             opcode += 0x100;
         }
@@ -337,7 +486,7 @@ impl LR35902 {
         for i in 1..(n + 1) {
             let last_inst = ret[i - 1];
             let next_pc = last_inst.0 + last_inst.1.size as u16;
-            let mut next_inst_opcode_index = self.mem8(next_pc) as usize;
+            let mut next_inst_opcode_index = self.fetch8(next_pc) as usize;
             if last_inst.1.mnemonic == "PREFIX CB" {
                 next_inst_opcode_index += 0x100;
             }
@@ -560,6 +709,7 @@ impl LR35902 {
     }
 
     fn pop(&mut self) -> u16 {
+        self.call_stack.pop_word(self.pc(), self.sp());
         let r = self.mem16(self.sp());
         self.set_sp(self.sp() + 2);
         r
@@ -584,10 +734,12 @@ impl LR35902 {
     fn call(&mut self, address: u16) {
         // pc + 3 because CALLs have size == 3 bytes
         self.push(self.pc() + 3);
+        self.push_frame(FrameKind::Call, address, self.pc() + 3);
         self.jump(address);
     }
 
     fn ret(&mut self) {
+        self.call_stack.ret(self.pc(), self.sp());
         let address = self.pop();
         self.jump(address);
     }
@@ -595,15 +747,36 @@ impl LR35902 {
     fn rst(&mut self, address: u16) {
         // pc + 1 because RSTs have size == 1 byte
         self.push(self.pc() + 1);
+        self.push_frame(FrameKind::Rst, address, self.pc() + 1);
         self.jump(address);
     }
 
+    /// Keeps track of a return address that was just pushed
+    fn push_frame(&mut self, kind: FrameKind, target: u16, return_address: u16) {
+        self.call_stack.push(Frame {
+            kind,
+            call_site: self.pc(),
+            target,
+            via: None,
+            return_address,
+            sp: self.sp(),
+            bank: self.bus.rom_bank(),
+        });
+    }
+
+    /// Sets SP by hand, which throws away any return address it moves past
+    fn move_sp(&mut self, sp: u16) {
+        self.set_sp(sp);
+        self.call_stack.move_sp(self.pc(), sp);
+    }
+
     fn reti(&mut self) {
         // TODO: The interrupt master enable flag is returned to its pre-interrupt status.
         //  BUT: https://gbdev.io/pandocs/Interrupts.htm claims that RETI is EI followed by RET
         self.set_ime_next_inst();
 
         // RET
+        self.call_stack.ret(self.pc(), self.sp());
         let address = self.pop();
         self.jump(address);
     }
@@ -614,22 +787,71 @@ impl LR35902 {
         self.set_h_flag(true);
     }
 
-    pub fn update_code_listing(&mut self, inst: Instruction) {
-        if self.bus.memory().code_listing()[self.pc() as usize].is_some() {
+    pub fn registers(&self) -> Registers {
+        Registers {
+            af: self.af,
+            bc: self.bc,
+            de: self.de,
+            hl: self.hl,
+            sp: self.sp,
+            pc: self.pc,
+        }
+    }
+
+    pub fn save_state(&self) -> CpuState {
+        CpuState {
+            registers: self.registers(),
+            ime: self.ime,
+            ime_next_inst: self.ime_next_inst,
+            prefix_cb: self.prefix_cb,
+            clock_cycles: self.clock_cycles,
+            inst_cycle_count: self.inst_cycle_count,
+            branch_taken: self.branch_taken,
+            halted: self.halted,
+            stopped: self.stopped,
+            call_stack: self.call_stack.clone(),
+        }
+    }
+
+    pub fn load_state(&mut self, state: &CpuState) {
+        let r = state.registers;
+        (self.af, self.bc, self.de, self.hl, self.sp, self.pc) =
+            (r.af, r.bc, r.de, r.hl, r.sp, r.pc);
+        self.ime = state.ime;
+        self.ime_next_inst = state.ime_next_inst;
+        self.prefix_cb = state.prefix_cb;
+        self.clock_cycles = state.clock_cycles;
+        self.inst_cycle_count = state.inst_cycle_count;
+        self.branch_taken = state.branch_taken;
+        self.halted = state.halted;
+        self.stopped = state.stopped;
+        self.call_stack = state.call_stack.clone();
+    }
+
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    /// Decodes the instruction at `address` without going through the CPU's memory accessors
+    pub fn disassemble(&self, address: u16) -> DecodedInstruction {
+        disasm::decode(address, |a| self.bus.read(a as usize))
+    }
+
+    pub fn update_code_listing(&mut self) {
+        // The whole instruction was already listed at the address of the 0xCB prefix
+        if self.prefix_cb || self.bus.memory().code_listing()[self.pc() as usize].is_some() {
             return;
         }
-        let result: Vec<String> = (1..inst.size)
-            .map(|i| format!("{:#02X}", self.mem8(self.pc() + i as u16)))
-            .collect();
-        let str = format!(
-            "{:#06X}: {} ({:#02X}{}{})",
-            self.pc(),
-            inst.mnemonic,
-            inst.opcode,
-            if result.is_empty() { "" } else { " " },
-            result.join(" ")
-        );
-        self.bus.memory_mut().set_code_listing_at(self.pc(), str);
+        let inst = self.disassemble(self.pc());
+        self.bus.memory_mut().set_code_listing_at(self.pc(), inst);
     }
 
     // Run instructions
@@ -640,6 +862,16 @@ impl LR35902 {
     /// each subsystem could be like coroutines for easier state tracking.
     /// But, for now, it's easier to run multiple cycles in each step().
     pub fn step(&mut self) -> u8 {
+        if self.stopped.is_some() {
+            self.wait_stopped();
+            return 1;
+        }
+        if self.bus.dma_stalled() {
+            // The timer keeps running
+            self.bus.dma_stall();
+            self.set_clock_cycles(self.clock_cycles() + 1);
+            return 1;
+        }
         let inst = self.decode();
         if !self.halted {
             // Only actually mutate CPU state on the last t-cycle of the instruction
@@ -648,29 +880,31 @@ impl LR35902 {
                 return 1;
             }
 
-            self.update_code_listing(inst);
-            if self.debugger.match_breakpoint(self.pc()) {
-                return 0;
-            }
-            if self.debugger.match_instrpoint(inst.opcode) {
+            self.update_code_listing();
+            if self.debugger.match_points(&self.registers(), inst.opcode) {
                 return 0;
             }
 
+            // CB instructions run once pc is already past their prefix
+            let pc = self.pc() - self.prefix_cb as u16;
             self.execute(inst);
 
             if !self.mutated_pc() {
                 self.set_pc(self.pc() + inst.size as u16);
             }
+            self.debugger.match_watchpoints(&self.registers(), pc);
         }
+        let pc = self.pc();
         let intr_service_routine_cycles = if self.ime { self.run_interrupts() } else { 0 };
+        if intr_service_routine_cycles != 0 {
+            self.debugger.match_watchpoints(&self.registers(), pc);
+        }
         if intr_service_routine_cycles != 0 && self.halted {
             self.halted = false;
         }
         if !self.halted {
-            if self.debugger.step {
-                self.set_paused(true);
-                self.debugger.step = false;
-            }
+            self.debugger
+                .end_instruction(self.pc(), self.call_stack.depth());
 
             let inst_cycles = if inst.kind == InstructionKind::Jump && !self.mutated_pc() {
                 inst.cycles_not_taken
@@ -686,6 +920,18 @@ impl LR35902 {
         1 + intr_service_routine_cycles
     }
 
+    /// Spends a t-cycle stopped, and starts again once the speed switch is done or a
+    /// button is pressed
+    fn wait_stopped(&mut self) {
+        self.stopped = match self.stopped {
+            Some(Stop::SpeedSwitch(cycles)) if cycles > 1 => Some(Stop::SpeedSwitch(cycles - 1)),
+            Some(Stop::Button) if self.bus.buttons() == memory::Buttons::default() => {
+                Some(Stop::Button)
+            }
+            _ => None,
+        };
+    }
+
     /// To run an interrupt, we first run the interrupt service routine (ISR).
     /// That on itself takes 5 M-cycles, or 20 T-cycles.
     fn run_interrupts(&mut self) -> u8 {
@@ -714,6 +960,7 @@ impl LR35902 {
         self.bus.set_iflag(bw::set_bit8_dyn(iflag, intr_bit, false));
         self.set_ime(false);
         self.push(self.pc());
+        self.push_frame(FrameKind::Interrupt, isv, self.pc());
         self.set_pc(isv);
         // https://gbdev.io/pandocs/Interrupts.html#interrupt-handling
         20
@@ -815,7 +1062,13 @@ impl LR35902 {
             }
             0x10 => {
                 // STOP 0
-                todo!("0x10 STOP 0")
+                self.stopped = Some(if self.bus.switch_speed() {
+                    Stop::SpeedSwitch(SPEED_SWITCH_CYCLES)
+                } else {
+                    Stop::Button
+                });
+                // Resets DIV
+                self.bus.write(memory::map::DIV, 0);
             }
             0x11 => {
                 // LD DE,d16
@@ -993,7 +1246,7 @@ impl LR35902 {
             }
             0x31 => {
                 // LD SP,d16
-                self.set_sp(self.get_d16(0));
+                self.move_sp(self.get_d16(0));
             }
             0x32 => {
                 // LD (HL-),A
@@ -1003,7 +1256,7 @@ impl LR35902 {
             0x33 => {
                 // INC SP
                 let result = self.inc16(self.sp());
-                self.set_sp(result);
+                self.move_sp(result);
             }
             0x34 => {
                 // INC (HL)
@@ -1044,7 +1297,7 @@ impl LR35902 {
             0x3B => {
                 // DEC SP
                 let result = self.dec16(self.sp());
-                self.set_sp(result);
+                self.move_sp(result);
             }
             0x3C => {
                 // INC A
@@ -1831,10 +2084,11 @@ impl LR35902 {
             0xE8 => {
                 // ADD SP,r8
                 let result = self.add16i(self.sp(), self.get_r8(0));
-                self.set_sp(result);
+                self.move_sp(result);
             }
             0xE9 => {
                 // JP (HL)
+                self.call_stack.jump_hl(self.pc(), self.sp(), self.hl());
                 self.jump(self.hl());
             }
             0xEA => {
@@ -1903,7 +2157,7 @@ impl LR35902 {
             }
             0xF9 => {
                 // LD SP,HL
-                self.set_sp(self.hl());
+                self.move_sp(self.hl());
             }
             0xFA => {
                 // LD A,(a16)
@@ -0,0 +1,171 @@
+//! Shadow call stack, kept alongside the real one for the debugger.
+
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+impl fmt::Display for FrameKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FrameKind::Call => "call",
+            FrameKind::Rst => "rst",
+            FrameKind::Interrupt => "interrupt",
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// The CALL or RST instruction, or the instruction an interrupt came before
+    pub call_site: u16,
+    pub target: u16,
+    /// The `JP (HL)` that went on from the original target to `target`, as trampolines do
+    pub via: Option<u16>,
+    pub return_address: u16,
+    /// Where the return address was pushed
+    pub sp: u16,
+    /// ROM bank switched in when the frame was pushed
+    pub bank: u16,
+}
+
+/// Something the program did to the stack that threw frames away without returning
+/// from them, so the call stack may not be what it looks like
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Desync {
+    /// SP was moved past return addresses by LD SP, ADD SP, INC SP or DEC SP
+    SpMoved { pc: u16, sp: u16 },
+    /// A return address was taken off the stack by a POP instead of a RET
+    Popped { pc: u16, return_address: u16 },
+    /// A RET or RETI returned from deeper than the innermost frame
+    Skipped { pc: u16, frames: usize },
+    /// A `JP (HL)` jumped away with SP above the return address of the innermost frame,
+    /// so that frame was thrown away
+    Jumped { pc: u16, target: u16 },
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Desync::SpMoved { pc, sp } => write!(f, "SP moved to {sp:#06X} at {pc:#06X}"),
+            Desync::Popped { pc, return_address } => write!(
+                f,
+                "return address {return_address:#06X} popped at {pc:#06X}"
+            ),
+            Desync::Skipped { pc, frames } => {
+                write!(f, "return at {pc:#06X} skipped {frames} frame(s)")
+            }
+            Desync::Jumped { pc, target } => {
+                write!(f, "JP (HL) at {pc:#06X} to {target:#06X} left the frame")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    /// The last desync since the stack was empty
+    desync: Option<Desync>,
+}
+
+impl CallStack {
+    pub fn push(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    /// Pops the frame returned from by a RET or RETI, with `sp` pointing at the return
+    /// address about to be popped. Frames pushed deeper than `sp` were thrown away by
+    /// the program and are dropped too. Nothing is popped for a return address pushed by
+    /// hand, like in a `push hl; ret` jump.
+    pub fn ret(&mut self, pc: u16, sp: u16) -> Option<Frame> {
+        let dropped = self.drop_below(sp);
+        if dropped > 0 {
+            self.record(Desync::Skipped {
+                pc,
+                frames: dropped,
+            });
+        }
+        if self.frames.last()?.sp == sp {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
+    /// Follows a POP at `pc`, with `sp` pointing at the word about to be popped
+    pub fn pop_word(&mut self, pc: u16, sp: u16) {
+        if self.frames.last().is_some_and(|frame| frame.sp == sp) {
+            let frame = self.pop().unwrap();
+            self.record(Desync::Popped {
+                pc,
+                return_address: frame.return_address,
+            });
+        }
+    }
+
+    /// Follows an instruction at `pc` setting SP by hand
+    pub fn move_sp(&mut self, pc: u16, sp: u16) {
+        if self.drop_below(sp) > 0 {
+            self.record(Desync::SpMoved { pc, sp });
+        }
+    }
+
+    /// Follows a `JP (HL)` at `pc`, which calls on to `target` in trampolines, with `sp`
+    /// pointing at the top of the stack
+    pub fn jump_hl(&mut self, pc: u16, sp: u16, target: u16) {
+        match self.frames.last_mut() {
+            Some(frame) if frame.sp == sp => {
+                frame.via = Some(pc);
+                frame.target = target;
+            }
+            // Below the frame, like in a `push de; jp hl` call, the frame is still there
+            Some(frame) if frame.sp > sp => {}
+            Some(_) => {
+                self.drop_below(sp);
+                self.record(Desync::Jumped { pc, target });
+            }
+            None => {}
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Frames from the outermost to the innermost one
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn desync(&self) -> Option<Desync> {
+        self.desync
+    }
+
+    fn record(&mut self, desync: Desync) {
+        self.desync = Some(desync);
+    }
+
+    fn pop(&mut self) -> Option<Frame> {
+        let frame = self.frames.pop();
+        if self.frames.is_empty() {
+            // Whatever is pushed from here on is tracked from scratch
+            self.desync = None;
+        }
+        frame
+    }
+
+    /// Drops the frames whose return address is below `sp`, no longer on the stack
+    fn drop_below(&mut self, sp: u16) -> usize {
+        let depth = self.frames.len();
+        while self.frames.last().is_some_and(|frame| frame.sp < sp) {
+            self.frames.pop();
+        }
+        depth - self.frames.len()
+    }
+}
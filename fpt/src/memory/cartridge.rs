@@ -1,3 +1,5 @@
+use std::cell::RefCell;
+
 use crate::memory::map;
 use crate::memory::{Address, MemoryRange};
 
@@ -47,12 +49,21 @@ pub trait Cartridge {
 
     fn write(&mut self, address: Address, value: u8);
 
+    /// Bank currently switched into 0x4000-0x7FFF
+    fn rom_bank(&self) -> u16 {
+        1
+    }
+
+    /// A copy of this cartridge in its current state, for snapshots
+    fn box_clone(&self) -> Box<RefCell<dyn Cartridge>>;
+
     fn read_range(&self, memory_range: MemoryRange) -> Vec<u8> {
         memory_range.map(|address| self.read(address)).collect()
     }
 
     fn get_title(&self) -> String {
-        String::from_utf8(self.read_range(map::TITLE)).unwrap()
+        // Not always ASCII, as newer cartridges use the end of it for other fields
+        String::from_utf8_lossy(&self.read_range(map::TITLE)).into_owned()
     }
 
     fn get_manufacturer_code(&self) -> String {
@@ -63,6 +74,10 @@ pub trait Cartridge {
         String::from_utf8(self.read_range(map::NEW_LICENSEE_CODE)).unwrap()
     }
 
+    fn get_cgb_flag(&self) -> u8 {
+        self.read(map::CGB_FLAG)
+    }
+
     fn get_sgb_flag(&self) -> u8 {
         self.read(map::SGB_FLAG)
     }
@@ -88,6 +103,7 @@ pub trait Cartridge {
     }
 }
 
+#[derive(Clone)]
 pub struct EmptyCartridge {}
 
 impl EmptyCartridge {
@@ -101,4 +117,8 @@ impl Cartridge for EmptyCartridge {
         0xFF
     }
     fn write(&mut self, _address: Address, _value: u8) {}
+
+    fn box_clone(&self) -> Box<RefCell<dyn Cartridge>> {
+        Box::new(RefCell::new(self.clone()))
+    }
 }
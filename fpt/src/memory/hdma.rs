@@ -0,0 +1,102 @@
+//! VRAM DMA (CGB), which copies blocks of 16 bytes to VRAM either all at once or one
+//! each HBlank <https://gbdev.io/pandocs/CGB_Registers.html#lcd-vram-dma-transfers>
+
+use super::{map, Bus};
+use crate::bw;
+
+/// Bytes copied at a time
+const BLOCK_SIZE: u16 = 16;
+
+/// T-cycles of the CPU a block stops it for at normal speed: 8 M-cycles
+const BLOCK_CYCLES: u32 = 8 * 4;
+
+/// A VRAM DMA still copying
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Hdma {
+    source: u16,
+    destination: u16,
+    /// Blocks left to copy
+    blocks: u8,
+}
+
+impl Bus {
+    /// Starts a VRAM DMA, or cancels the HBlank DMA running if bit 7 is clear
+    pub(super) fn write_hdma5(&mut self, value: u8) {
+        let hblank = bw::test_bit8::<7>(value);
+        let running = self.memory_mut().hdma.take();
+        if let (Some(hdma), false) = (running, hblank) {
+            // Reads as stopped, with the blocks it had left
+            self.memory_mut().mem[map::HDMA5] = 0x80 | (hdma.blocks - 1);
+            return;
+        }
+
+        let memory = self.memory();
+        let mut hdma = Hdma {
+            // The lower 4 bits are ignored, and the destination is always in VRAM
+            source: u16::from_be_bytes([memory.mem[map::HDMA1], memory.mem[map::HDMA2]]) & 0xFFF0,
+            destination: 0x8000
+                | u16::from_be_bytes([memory.mem[map::HDMA3], memory.mem[map::HDMA4]]) & 0x1FF0,
+            blocks: (value & 0x7F) + 1,
+        };
+        drop(memory);
+
+        if hblank {
+            self.memory_mut().mem[map::HDMA5] = value & 0x7F;
+            self.memory_mut().hdma = Some(hdma);
+            // Started during HBlank, the first block is copied right away
+            if self.stat() & 0b11 == 0 {
+                self.hblank_dma();
+            }
+        } else {
+            // General purpose DMA copies everything at once
+            while hdma.blocks > 0 {
+                self.copy_hdma_block(&mut hdma);
+            }
+            self.memory_mut().mem[map::HDMA5] = 0xFF;
+        }
+    }
+
+    /// Copies the next block of the HBlank DMA running, if any, as HBlank starts
+    pub fn hblank_dma(&mut self) {
+        let Some(mut hdma) = self.memory_mut().hdma.take() else {
+            return;
+        };
+        self.copy_hdma_block(&mut hdma);
+        let mut memory = self.memory_mut();
+        if hdma.blocks == 0 {
+            memory.mem[map::HDMA5] = 0xFF;
+        } else {
+            memory.mem[map::HDMA5] = hdma.blocks - 1;
+            memory.hdma = Some(hdma);
+        }
+    }
+
+    /// Copies a block to the VRAM bank switched in, stopping the CPU while it does. The
+    /// transfer ends early once it reaches the end of VRAM.
+    fn copy_hdma_block(&mut self, hdma: &mut Hdma) {
+        for i in 0..BLOCK_SIZE {
+            let value = self.read(hdma.source.wrapping_add(i) as usize);
+            self.memory_mut().mem[(hdma.destination + i) as usize] = value;
+        }
+        hdma.source = hdma.source.wrapping_add(BLOCK_SIZE);
+        hdma.destination += BLOCK_SIZE;
+        hdma.blocks -= 1;
+        if hdma.destination as usize >= map::VRAM.end {
+            hdma.blocks = 0;
+        }
+
+        // It takes as long at both speeds, so twice as many cycles in double speed mode
+        let cycles = BLOCK_CYCLES * (1 + self.double_speed() as u32);
+        self.memory_mut().dma_stall += cycles;
+    }
+
+    /// Whether a VRAM DMA stops the CPU
+    pub fn dma_stalled(&self) -> bool {
+        self.memory().dma_stall > 0
+    }
+
+    /// Spends a t-cycle of the CPU stopped by a VRAM DMA
+    pub fn dma_stall(&mut self) {
+        self.memory_mut().dma_stall -= 1;
+    }
+}
@@ -10,6 +10,8 @@ use super::{Address, MemoryRange};
 
 /// This is where the bootrom lives
 pub const BOOTROM: MemoryRange = 0x0000..0x0100;
+/// Where the rest of the CGB bootrom lives, past the cartridge header
+pub const CGB_BOOTROM: MemoryRange = 0x0200..0x0900;
 
 /// The Cartridge Header
 pub const ROM_DATA: MemoryRange = 0x0100..0x0150;
@@ -28,6 +30,8 @@ pub const EXT_WRAM: MemoryRange = 0xA000..0xC000;
 
 /// Unit Working RAM (8 KB)
 pub const WRAM: MemoryRange = 0xC000..0xE000;
+/// Second half of WRAM (4 KB) - In CGB mode, switchable bank 1-7
+pub const WRAM_BANK1: MemoryRange = 0xD000..0xE000;
 
 /// Not usable (Mirror of C000~DDFF (ECHO RAM)) <https://gbdev.io/pandocs/Memory_Map.html#echo-ram>
 pub const NOT_USABLE1: MemoryRange = 0xE000..0xFE00;
@@ -150,7 +154,7 @@ pub const BANK: Address = 0xFF50;
 //-------------------------------------------------------------------------
 
 /// Prepare speed switch (CGB)
-pub const KEY1: Address = 0xFF4C;
+pub const KEY1: Address = 0xFF4D;
 /// VRAM bank (CGB)
 pub const VBK: Address = 0xFF4F;
 /// VRAM DMA source high (CGB)
@@ -175,7 +179,8 @@ pub const OCPS: Address = 0xFF6A;
 pub const OCPD: Address = 0xFF6B;
 /// Object priority mode (CGB)
 pub const OPRI: Address = 0xFF6C;
-/// WRAM bank (CGB) pub const SVBK: Address = 0xFF70;
+/// WRAM bank (CGB)
+pub const SVBK: Address = 0xFF70;
 /// Audio digital outputs 1 & 2 (CGB)
 pub const PCM12: Address = 0xFF76;
 /// Audio digital outputs 3 & 4 (CGB)
@@ -211,3 +216,5 @@ pub const RAM_SIZE: Address = 0x149;
 pub const OLD_LICENSEE_CODE: Address = 0x14b;
 /// Game version
 pub const VERSION_NUMBER: Address = 0x14c;
+/// Checksum of the header, which the boot ROM checks
+pub const HEADER_CHECKSUM: Address = 0x14d;
@@ -1,9 +1,14 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
 use super::cartridge::Cartridge;
 use super::cartridge::{convert_ram_size, convert_rom_size, get_ram_size, get_rom_size};
 use super::{map, Address, MemoryRange};
 
+#[derive(Clone)]
 pub struct Mbc3Cartridge {
-    rom_banks: Vec<[u8; 0x4000]>,
+    /// Shared by snapshots, as it never changes
+    rom_banks: Rc<Vec<[u8; 0x4000]>>,
     ram_banks: Vec<[u8; 0x2000]>,
     ext_ram_enabled: bool,
     rom_bank_number: usize,
@@ -28,19 +33,21 @@ impl Mbc3Cartridge {
         );
 
         let ram_banks = vec![[0; RAM_BANK_SIZE]; ram_size as usize];
-        let rom_banks = (0..rom_size)
-            .map(|i| {
-                cartridge[ROM_BANK_SIZE * i..(ROM_BANK_SIZE * (i + 1))]
-                    .try_into()
-                    .unwrap()
-            })
-            .collect();
+        let rom_banks = Rc::new(
+            (0..rom_size)
+                .map(|i| {
+                    cartridge[ROM_BANK_SIZE * i..(ROM_BANK_SIZE * (i + 1))]
+                        .try_into()
+                        .unwrap()
+                })
+                .collect(),
+        );
 
         Mbc3Cartridge {
             rom_banks,
             ram_banks,
             ext_ram_enabled: false,
-            rom_bank_number: 0,
+            rom_bank_number: 1,
             ram_bank_number: 0,
         }
     }
@@ -60,6 +67,15 @@ impl Cartridge for Mbc3Cartridge {
             panic!()
         }
     }
+
+    fn rom_bank(&self) -> u16 {
+        self.rom_bank_number as u16
+    }
+
+    fn box_clone(&self) -> Box<RefCell<dyn Cartridge>> {
+        Box::new(RefCell::new(self.clone()))
+    }
+
     fn write(&mut self, address: Address, value: u8) {
         if RAM_ENABLE.contains(&address) {
             self.ext_ram_enabled = value & 0xF == 0xA;
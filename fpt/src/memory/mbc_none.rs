@@ -1,11 +1,16 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
 use super::cartridge::Cartridge;
 use super::{map, Address};
 
 /// Cartridge with no banking and no external ram
 ///
 /// <https://gbdev.io/pandocs/nombc.html>
+#[derive(Clone)]
 pub struct NoMbcCartridge {
-    memory: [u8; 0x8000],
+    /// Shared by snapshots, as it never changes
+    memory: Rc<[u8; 0x8000]>,
 }
 
 impl NoMbcCartridge {
@@ -15,7 +20,7 @@ impl NoMbcCartridge {
             "Expected cartridge size of 0x8000"
         );
         NoMbcCartridge {
-            memory: cartridge.try_into().unwrap(),
+            memory: Rc::new(cartridge.try_into().unwrap()),
         }
     }
 }
@@ -35,4 +40,8 @@ impl Cartridge for NoMbcCartridge {
             "Cartridge must only be accessed for cartridge specific memory segments"
         );
     }
+
+    fn box_clone(&self) -> Box<RefCell<dyn Cartridge>> {
+        Box::new(RefCell::new(self.clone()))
+    }
 }
@@ -1,18 +1,28 @@
 mod cartridge;
+mod hdma;
 pub mod map;
 mod mbc3;
 mod mbc_builder;
 mod mbc_none;
+pub mod search;
 
 use std::cell::{Ref, RefCell, RefMut};
+use std::io;
 use std::ops::Range;
+use std::path::PathBuf;
 use std::rc::Rc;
 
 use cartridge::Cartridge;
+use hdma::Hdma;
 use mbc_builder::{create_empty_mbc, create_mbc};
-use rand::prelude::*;
+use search::{MemorySearch, SearchWidth};
 
 use crate::bw;
+use crate::cheats::Cheats;
+use crate::disasm::DecodedInstruction;
+use crate::model::Model;
+use crate::ppu::Frame;
+use crate::sgb::Sgb;
 
 pub type Address = usize;
 pub type MemoryRange = Range<Address>;
@@ -21,12 +31,40 @@ pub struct Memory {
     mem: Vec<u8>,
     pub bootrom_loaded: bool,
     pub cartridge: Box<RefCell<dyn Cartridge>>,
-    bootrom: &'static [u8; 256],
-    code_listing: Vec<Option<String>>,
+    /// The boot ROM given for the model, if any
+    bootrom: Option<Vec<u8>>,
+    /// The model chosen, if any, or else the one the cartridge header asks for
+    model: Option<Model>,
+    code_listing: Vec<Option<DecodedInstruction>>,
     pub buttons: Buttons,
+    /// What the CPU reads from LY instead of the real one, as Gameboy Doctor needs
+    pub ly_stub: Option<u8>,
+    pub cheats: Cheats,
+    /// Whether the cartridge runs in Game Boy Color mode, as its header's CGB_FLAG says
+    cgb: bool,
+    /// The VRAM bank that VBK doesn't switch in (CGB), as `mem` has the one it does
+    vram_bank: Vec<u8>,
+    /// WRAM banks 1-7 (CGB), but the one SVBK switches in is in `mem` instead
+    wram_banks: Vec<u8>,
+    /// 8 BG palettes of 4 colors (CGB), each 15 bits little endian, as BCPD writes them
+    bg_palettes: [u8; 64],
+    /// 8 OBJ palettes of 4 colors (CGB), as OCPD writes them
+    obj_palettes: [u8; 64],
+    /// The HBlank DMA copying to VRAM (CGB), if any
+    hdma: Option<Hdma>,
+    /// T-cycles a VRAM DMA still stops the CPU for (CGB)
+    dma_stall: u32,
+    /// The Super Game Boy, for games that use it
+    sgb: Option<Sgb>,
 }
 
-#[derive(Clone, Copy, Default, Debug)]
+/// The boot ROM of DMG models when none is given
+const DMG_BOOTROM: &[u8; 256] = include_bytes!("../../dmg.bin");
+
+/// Size of a WRAM bank
+const WRAM_BANK_SIZE: usize = 0x1000;
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
 pub struct Buttons {
     pub a: bool,
     pub b: bool,
@@ -38,6 +76,26 @@ pub struct Buttons {
     pub left: bool,
 }
 
+impl Buttons {
+    /// Presses or releases a button by name: `a`, `b`, `start`, `select`, `up`, `down`,
+    /// `left` or `right`
+    pub fn set(&mut self, name: &str, pressed: bool) -> Result<(), String> {
+        let button = match name {
+            "a" => &mut self.a,
+            "b" => &mut self.b,
+            "start" => &mut self.start,
+            "select" => &mut self.select,
+            "up" => &mut self.up,
+            "down" => &mut self.down,
+            "left" => &mut self.left,
+            "right" => &mut self.right,
+            _ => return Err(format!("no button {name}")),
+        };
+        *button = pressed;
+        Ok(())
+    }
+}
+
 impl PartialEq for Memory {
     fn eq(&self, other: &Self) -> bool {
         self.slice(map::WRAM) == other.slice(map::WRAM)
@@ -52,17 +110,33 @@ impl Default for Memory {
 
 impl Memory {
     pub fn new() -> Self {
-        const ARRAY_REPEAT_VALUE: Option<String> = None;
+        const ARRAY_REPEAT_VALUE: Option<DecodedInstruction> = None;
         Self {
             mem: vec![0; 65536],
             bootrom_loaded: false,
             cartridge: create_empty_mbc(),
-            bootrom: include_bytes!("../../dmg.bin"),
+            bootrom: None,
+            model: None,
             code_listing: vec![ARRAY_REPEAT_VALUE; 0xffff + 1],
             buttons: Buttons::default(),
+            ly_stub: None,
+            cheats: Cheats::default(),
+            cgb: false,
+            vram_bank: vec![0; map::VRAM.len()],
+            wram_banks: vec![0; 7 * WRAM_BANK_SIZE],
+            bg_palettes: [0; 64],
+            obj_palettes: [0; 64],
+            hdma: None,
+            dma_stall: 0,
+            sgb: None,
         }
     }
 
+    /// The boot ROM given, or else the DMG one
+    fn bootrom(&self) -> &[u8] {
+        self.bootrom.as_deref().unwrap_or(DMG_BOOTROM)
+    }
+
     fn array_ref<const N: usize>(&self, from: Address) -> &[u8; N] {
         self.mem[from..from + N].try_into().unwrap() // guaranteed to have size N
     }
@@ -75,13 +149,120 @@ impl Memory {
         &mut self.mem[range]
     }
 
-    pub fn code_listing(&self) -> &[Option<String>] {
+    pub fn code_listing(&self) -> &[Option<DecodedInstruction>] {
         &self.code_listing
     }
 
-    pub fn set_code_listing_at(&mut self, pc: u16, v: String) {
+    pub fn set_code_listing_at(&mut self, pc: u16, v: DecodedInstruction) {
         self.code_listing[pc as usize] = Some(v);
     }
+
+    fn vram_bank(&self) -> usize {
+        (self.mem[map::VBK] & 1) as usize
+    }
+
+    fn wram_bank(&self) -> usize {
+        // Bank 0 is always at 0xC000, so asking for it gets bank 1
+        ((self.mem[map::SVBK] & 0b111) as usize).max(1)
+    }
+
+    fn switch_vram_bank(&mut self, bank: usize) {
+        if bank != self.vram_bank() {
+            self.mem[map::VRAM].swap_with_slice(&mut self.vram_bank);
+        }
+    }
+
+    fn switch_wram_bank(&mut self, bank: usize) {
+        let current = self.wram_bank();
+        if bank != current {
+            let slot = |bank: usize| (bank - 1) * WRAM_BANK_SIZE..bank * WRAM_BANK_SIZE;
+            // Puts the current bank away, then takes the new one out
+            self.mem[map::WRAM_BANK1].swap_with_slice(&mut self.wram_banks[slot(current)]);
+            self.mem[map::WRAM_BANK1].swap_with_slice(&mut self.wram_banks[slot(bank)]);
+        }
+    }
+
+    /// Writes a CGB register that does more than hold its value, returning false for the
+    /// ones that don't
+    fn write_cgb_register(&mut self, address: Address, value: u8) -> bool {
+        match address {
+            map::KEY1 => {
+                // Only the switch can be armed, STOP switches the speed
+                self.mem[map::KEY1] = self.mem[map::KEY1] & 0x80 | value & 1 | 0b0111_1110;
+            }
+            map::VBK => {
+                self.switch_vram_bank((value & 1) as usize);
+                self.mem[map::VBK] = value | 0b1111_1110;
+            }
+            map::SVBK => {
+                self.switch_wram_bank(((value & 0b111) as usize).max(1));
+                self.mem[map::SVBK] = value | 0b1111_1000;
+            }
+            map::BCPS => {
+                self.mem[map::BCPS] = value;
+                write_palette(&mut self.mem, &mut self.bg_palettes, map::BCPS, None);
+            }
+            map::BCPD => {
+                write_palette(&mut self.mem, &mut self.bg_palettes, map::BCPS, Some(value))
+            }
+            map::OCPS => {
+                self.mem[map::OCPS] = value;
+                write_palette(&mut self.mem, &mut self.obj_palettes, map::OCPS, None);
+            }
+            map::OCPD => write_palette(
+                &mut self.mem,
+                &mut self.obj_palettes,
+                map::OCPS,
+                Some(value),
+            ),
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Writes `data` to the palette byte the specification register `spec` (BCPS or OCPS)
+/// points at, moving on to the next byte when its bit 7 says so. The data register after
+/// `spec` always reads the byte it points at.
+fn write_palette(mem: &mut [u8], palettes: &mut [u8; 64], spec: Address, data: Option<u8>) {
+    if let Some(value) = data {
+        let index = mem[spec] & 0x3F;
+        palettes[index as usize] = value;
+        if bw::test_bit8::<7>(mem[spec]) {
+            mem[spec] = mem[spec] & 0x80 | (index + 1) & 0x3F;
+        }
+    }
+    mem[spec + 1] = palettes[(mem[spec] & 0x3F) as usize];
+}
+
+/// Whether a cartridge's header asks for CGB features, whether or not the game also runs
+/// on DMG
+fn cgb_game(cartridge: &dyn Cartridge) -> bool {
+    bw::test_bit8::<7>(cartridge.get_cgb_flag())
+}
+
+/// Whether a cartridge's header asks for SGB features, as the SGB only listens to games
+/// that do
+fn sgb_game(cartridge: &dyn Cartridge) -> bool {
+    cartridge.get_sgb_flag() == 0x03 && cartridge.get_old_licensee_code() == 0x33
+}
+
+/// What a snapshot keeps of memory: everything but the code listing and the cheats, which
+/// aren't part of the game's state
+pub struct MemoryState {
+    mem: Vec<u8>,
+    bootrom_loaded: bool,
+    cartridge: Box<RefCell<dyn Cartridge>>,
+    buttons: Buttons,
+    ly_stub: Option<u8>,
+    cgb: bool,
+    vram_bank: Vec<u8>,
+    wram_banks: Vec<u8>,
+    bg_palettes: [u8; 64],
+    obj_palettes: [u8; 64],
+    hdma: Option<Hdma>,
+    dma_stall: u32,
+    sgb: Option<Sgb>,
 }
 
 #[derive(Clone, PartialEq)]
@@ -109,25 +290,222 @@ impl Bus {
         self.memory_mut().bootrom_loaded = false;
     }
 
+    /// Replaces the boot ROM with one of the model's, which must be of its size
+    pub fn set_bootrom(&mut self, bootrom: &[u8]) -> Result<(), String> {
+        let model = self.model();
+        if bootrom.len() != model.bootrom_size() {
+            return Err(format!(
+                "the {model} boot ROM is {} bytes, not {}",
+                model.bootrom_size(),
+                bootrom.len()
+            ));
+        }
+        self.memory_mut().bootrom = Some(bootrom.to_vec());
+        Ok(())
+    }
+
+    /// Whether there's a boot ROM for the model: the one given, or the DMG one for DMG models
+    pub fn has_bootrom(&self) -> bool {
+        self.memory().bootrom.is_some() || self.model().dmg()
+    }
+
     pub fn load_rom(&mut self, rom: &[u8]) {
-        self.memory_mut().cartridge =
+        let cartridge =
             create_mbc(rom).expect("Given rom cannot be interpreted as a valid cartridge type");
+        self.memory_mut().cartridge = cartridge;
+        self.apply_model();
+    }
+
+    /// Runs games as `model` does instead of as their cartridge header asks, dropping the
+    /// boot ROM given for another model
+    pub fn set_model(&mut self, model: Model) {
+        if self.model() != model {
+            self.memory_mut().bootrom = None;
+        }
+        self.memory_mut().model = Some(model);
+        self.apply_model();
+    }
+
+    /// The model chosen, or else a CGB for games that use CGB features, a SGB for those
+    /// that use SGB ones, and a DMG0 for the others
+    pub fn model(&self) -> Model {
+        let memory = self.memory();
+        let cartridge = memory.cartridge.borrow();
+        memory.model.unwrap_or(if cgb_game(&*cartridge) {
+            Model::Cgb
+        } else if sgb_game(&*cartridge) {
+            Model::Sgb
+        } else {
+            Model::Dmg0
+        })
+    }
+
+    /// Turns CGB mode and the SGB on or off for the loaded game on the model
+    fn apply_model(&mut self) {
+        let model = self.model();
+        let mut memory = self.memory_mut();
+        let cartridge = memory.cartridge.borrow();
+        // The SGB plays CGB games as DMG ones
+        let (cgb, sgb) = (
+            model == Model::Cgb && cgb_game(&*cartridge),
+            model.sgb() && sgb_game(&*cartridge),
+        );
+        drop(cartridge);
+        memory.cgb = cgb;
+        memory.sgb = sgb.then(Sgb::new);
+        if cgb {
+            // No VRAM DMA is running
+            memory.mem[map::HDMA5] = 0xFF;
+        }
+    }
+
+    /// Whether the Gameboy is a Game Boy Color running a game in CGB mode
+    pub fn cgb(&self) -> bool {
+        self.memory().cgb
+    }
+
+    /// Whether the Gameboy is a Super Game Boy running a game that uses it
+    pub fn sgb(&self) -> bool {
+        self.memory().sgb.is_some()
+    }
+
+    /// Lets the SGB, if any, get the frame just drawn
+    pub fn sgb_vblank(&mut self, frame: &Frame) {
+        if let Some(sgb) = self.memory_mut().sgb.as_mut() {
+            sgb.vblank(frame);
+        }
+    }
+
+    /// The SGB picture, with the border, if it's a SGB
+    pub fn sgb_frame(&self) -> Option<Vec<u32>> {
+        self.memory().sgb.as_ref().map(Sgb::render)
+    }
+
+    /// Sets the buttons of the second (1) to fourth (3) SGB controller, if it's a SGB
+    pub fn set_player_buttons(&mut self, player: usize, buttons: &Buttons) -> Result<(), String> {
+        match self.memory_mut().sgb.as_mut() {
+            Some(sgb) => sgb.set_player_buttons(player, buttons),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the CPU runs at twice its normal speed (CGB)
+    pub fn double_speed(&self) -> bool {
+        let memory = self.memory();
+        memory.cgb && bw::test_bit8::<7>(memory.mem[map::KEY1])
+    }
+
+    /// Switches speed if KEY1 is armed for it, as STOP does, and returns whether it did
+    pub fn switch_speed(&mut self) -> bool {
+        let mut memory = self.memory_mut();
+        let key1 = memory.mem[map::KEY1];
+        if !memory.cgb || !bw::test_bit8::<0>(key1) {
+            return false;
+        }
+        memory.mem[map::KEY1] = (key1 ^ 0x80) & !1;
+        true
+    }
+
+    pub fn save_state(&self) -> MemoryState {
+        let memory = self.memory();
+        let cartridge = memory.cartridge.borrow().box_clone();
+        MemoryState {
+            mem: memory.mem.clone(),
+            bootrom_loaded: memory.bootrom_loaded,
+            cartridge,
+            buttons: memory.buttons,
+            ly_stub: memory.ly_stub,
+            cgb: memory.cgb,
+            vram_bank: memory.vram_bank.clone(),
+            wram_banks: memory.wram_banks.clone(),
+            bg_palettes: memory.bg_palettes,
+            obj_palettes: memory.obj_palettes,
+            hdma: memory.hdma,
+            dma_stall: memory.dma_stall,
+            sgb: memory.sgb.clone(),
+        }
+    }
+
+    pub fn load_state(&mut self, state: &MemoryState) {
+        let mut memory = self.memory_mut();
+        memory.mem.clone_from(&state.mem);
+        memory.bootrom_loaded = state.bootrom_loaded;
+        memory.cartridge = state.cartridge.borrow().box_clone();
+        memory.buttons = state.buttons;
+        memory.ly_stub = state.ly_stub;
+        memory.cgb = state.cgb;
+        memory.vram_bank.clone_from(&state.vram_bank);
+        memory.wram_banks.clone_from(&state.wram_banks);
+        memory.bg_palettes = state.bg_palettes;
+        memory.obj_palettes = state.obj_palettes;
+        memory.hdma = state.hdma;
+        memory.dma_stall = state.dma_stall;
+        memory.sgb.clone_from(&state.sgb);
+    }
+
+    /// Snapshots RAM to find an address by how its value changes
+    pub fn memsearch(&self, width: SearchWidth) -> MemorySearch {
+        MemorySearch::new(self, width)
+    }
+
+    pub fn title(&self) -> String {
+        self.memory().cartridge.borrow().get_title()
+    }
+
+    /// Does the RAM writes of the GameShark cheats, at VBlank
+    pub fn apply_ram_cheats(&mut self) {
+        let writes = self.memory().cheats.ram_writes();
+        for (address, value) in writes {
+            self.write(address as usize, value);
+        }
+    }
+
+    /// Where the cheats of the loaded game are saved
+    pub fn cheats_path(&self) -> PathBuf {
+        Cheats::path(&self.title())
+    }
+
+    /// Replaces the cheats with the ones saved for the loaded game, and returns how many
+    /// there are
+    pub fn load_cheats(&mut self) -> Result<usize, String> {
+        let cheats = Cheats::load(&self.cheats_path())?;
+        let count = cheats.list().len();
+        self.memory_mut().cheats = cheats;
+        Ok(count)
+    }
+
+    pub fn save_cheats(&self) -> io::Result<PathBuf> {
+        let path = self.cheats_path();
+        self.memory().cheats.save(&path)?;
+        Ok(path)
+    }
+
+    pub fn rom_bank(&self) -> u16 {
+        self.memory().cartridge.borrow().rom_bank()
+    }
+
+    /// Whether the boot ROM is mapped at `address`, around the cartridge header for CGB ones
+    fn bootrom_mapped(&self, address: Address) -> bool {
+        let memory = self.memory();
+        memory.bootrom_loaded
+            && (map::BOOTROM.contains(&address)
+                || map::CGB_BOOTROM.contains(&address) && address < memory.bootrom().len())
     }
 
     pub fn read(&self, address: Address) -> u8 {
-        // TODO
-        let mut rng = rand::thread_rng();
-        if address == 0xff04 {
-            let r: u8 = rng.gen();
-            return r;
+        if address == map::LY {
+            if let Some(ly) = self.memory().ly_stub {
+                return ly;
+            }
         }
 
-        if map::BOOTROM.contains(&address) && self.memory().bootrom_loaded {
-            self.memory().bootrom[address]
-        } else if map::ROM_BANK0.contains(&address)
-            || map::ROM_BANK1.contains(&address)
-            || map::EXT_WRAM.contains(&address)
-        {
+        if self.bootrom_mapped(address) {
+            self.memory().bootrom()[address]
+        } else if map::ROM_BANK0.contains(&address) || map::ROM_BANK1.contains(&address) {
+            let memory = self.memory();
+            let value = memory.cartridge.borrow().read(address);
+            memory.cheats.patch_rom(address as u16, value)
+        } else if map::EXT_WRAM.contains(&address) {
             self.memory().cartridge.borrow().read(address)
         } else if address == map::JOYP {
             self.joyp()
@@ -157,7 +535,19 @@ impl Bus {
                 .borrow_mut()
                 .write(address, value);
         } else if map::IO_REGISTERS.contains(&address) {
+            if self.cgb() && address == map::HDMA5 {
+                self.write_hdma5(value);
+                return;
+            }
+            if self.cgb() && self.memory_mut().write_cgb_register(address, value) {
+                return;
+            }
             self.memory_mut().mem[address as Address] = value;
+            if address == map::JOYP {
+                if let Some(sgb) = self.memory_mut().sgb.as_mut() {
+                    sgb.write_joyp(value);
+                }
+            }
             if address == map::DMA {
                 // dma transfer takes time, we do it instantaneously
                 let oam_data =
@@ -233,8 +623,9 @@ impl Bus {
         self.write(map::SCX, value);
     }
 
+    /// The real LY, even while it is stubbed for the CPU
     pub fn ly(&self) -> u8 {
-        self.read(map::LY)
+        self.memory().mem[map::LY]
     }
 
     pub fn set_ly(&mut self, value: u8) {
@@ -249,13 +640,42 @@ impl Bus {
         self.write(map::LYC, value)
     }
 
+    pub fn div(&self) -> u8 {
+        self.read(map::DIV)
+    }
+
+    pub fn tima(&self) -> u8 {
+        self.read(map::TIMA)
+    }
+
+    /// Runs `reader` with the VRAM bank switched in, as the CPU sees it
     pub fn with_vram<R>(&self, reader: impl FnOnce(&[u8]) -> R) -> R {
         reader(&self.memory().mem[map::VRAM])
     }
 
+    /// Runs `reader` with VRAM bank 0 or 1, whichever is switched in, as the PPU sees it
+    pub fn with_vram_bank<R>(&self, bank: usize, reader: impl FnOnce(&[u8]) -> R) -> R {
+        let memory = self.memory();
+        if !memory.cgb || bank == memory.vram_bank() {
+            reader(&memory.mem[map::VRAM])
+        } else {
+            reader(&memory.vram_bank)
+        }
+    }
+
+    /// The BG and OBJ color palettes (CGB)
+    pub fn palettes(&self) -> ([u8; 64], [u8; 64]) {
+        let memory = self.memory();
+        (memory.bg_palettes, memory.obj_palettes)
+    }
+
     fn joyp(&self) -> u8 {
-        let buttons = self.buttons();
-        let joyp = self.memory().mem[map::JOYP];
+        let memory = self.memory();
+        let buttons = match &memory.sgb {
+            Some(sgb) => sgb.buttons(memory.buttons),
+            None => memory.buttons,
+        };
+        let joyp = memory.mem[map::JOYP];
         let sel_buttons = !bw::test_bit8::<5>(joyp);
         let sel_dpad = !bw::test_bit8::<4>(joyp);
         let b = if sel_dpad && sel_buttons {
@@ -270,6 +690,9 @@ impl Bus {
                 + ((buttons.select as u8) << 2)
                 + ((buttons.b as u8) << 1)
                 + (buttons.a as u8)
+        } else if let Some(sgb) = &memory.sgb {
+            // Reads 0xF for the first controller, 0xE for the second...
+            sgb.player()
         } else {
             0
         };
@@ -285,6 +708,10 @@ impl Bus {
         self.memory_mut().buttons = *buttons;
     }
 
+    pub fn set_ly_stub(&mut self, ly: Option<u8>) {
+        self.memory_mut().ly_stub = ly;
+    }
+
     pub fn ie(&self) -> u8 {
         self.read(map::IE)
     }
@@ -297,3 +724,165 @@ impl Bus {
         self.write(map::IF, value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cgb_bus() -> Bus {
+        let mut rom = vec![0; 0x8000];
+        rom[map::CGB_FLAG] = 0x80;
+        let mut bus = Bus::new();
+        bus.load_rom(&rom);
+        assert!(bus.cgb());
+        bus
+    }
+
+    #[test]
+    fn test_cgb_banks() {
+        let mut bus = cgb_bus();
+
+        for bank in 0..8 {
+            bus.write(map::SVBK, bank);
+            bus.write(0xD000, bank + 10);
+            bus.write(0xC000, bank + 20);
+        }
+        // Bank 0 is bank 1, and bank 0 is always at 0xC000
+        bus.write(map::SVBK, 0);
+        assert_eq!(bus.read(0xD000), 11);
+        bus.write(map::SVBK, 5);
+        assert_eq!(bus.read(0xD000), 15);
+        assert_eq!(bus.read(0xF000), 15);
+        assert_eq!(bus.read(0xC000), 27);
+        assert_eq!(bus.read(map::SVBK), 0xFD);
+
+        bus.write(0x8000, 1);
+        bus.write(map::VBK, 1);
+        bus.write(0x8000, 2);
+        assert_eq!(bus.read(0x8000), 2);
+        assert_eq!(bus.with_vram_bank(0, |vram| vram[0]), 1);
+        assert_eq!(bus.with_vram_bank(1, |vram| vram[0]), 2);
+        bus.write(map::VBK, 0);
+        assert_eq!(bus.read(0x8000), 1);
+    }
+
+    #[test]
+    fn test_general_purpose_dma() {
+        let mut bus = cgb_bus();
+        for i in 0..0x20 {
+            bus.write(0xC000 + i, i as u8);
+        }
+        bus.write(map::VBK, 1);
+        bus.write(map::HDMA1, 0xC0);
+        bus.write(map::HDMA2, 0x0F); // The lower bits are ignored
+        bus.write(map::HDMA3, 0xE0); // As are the upper ones of the destination
+        bus.write(map::HDMA4, 0x10);
+        bus.write(map::HDMA5, 0x01);
+
+        let copied = bus.with_vram_bank(1, |vram| vram[0x10..0x30].to_vec());
+        assert_eq!(copied, (0..0x20).collect::<Vec<u8>>());
+        assert_eq!(bus.read(map::HDMA5), 0xFF);
+        let mut stalled = 0;
+        while bus.dma_stalled() {
+            bus.dma_stall();
+            stalled += 1;
+        }
+        assert_eq!(stalled, 2 * 32);
+    }
+
+    #[test]
+    fn test_dma_end_of_vram() {
+        let mut bus = cgb_bus();
+        for i in 0..0x30 {
+            bus.write(0xC000 + i, 0xA0 + i as u8);
+        }
+        bus.write(map::HDMA1, 0xC0);
+        bus.write(map::HDMA2, 0x00);
+        bus.write(map::HDMA3, 0x1F);
+        bus.write(map::HDMA4, 0xF0);
+        bus.write(map::HDMA5, 0x02);
+
+        // It stops at 0x9FFF instead of going on from 0x8000
+        assert_eq!(bus.read(0x9FFF), 0xAF);
+        assert_eq!(bus.read(0x8000), 0);
+        assert_eq!(bus.read(map::HDMA5), 0xFF);
+    }
+
+    #[test]
+    fn test_hblank_dma() {
+        let mut bus = cgb_bus();
+        bus.set_stat(0b10);
+        for i in 0..0x30 {
+            bus.write(0xD000 + i, 0xA0 + i as u8);
+        }
+        bus.write(map::HDMA1, 0xD0);
+        bus.write(map::HDMA2, 0x00);
+        bus.write(map::HDMA3, 0x00);
+        bus.write(map::HDMA4, 0x00);
+        bus.write(map::HDMA5, 0x82);
+        assert_eq!(bus.read(map::HDMA5), 0x02);
+        assert_eq!(bus.read(0x8000), 0);
+
+        bus.hblank_dma();
+        assert_eq!(bus.read(map::HDMA5), 0x01);
+        assert_eq!(bus.read(0x800F), 0xAF);
+        assert_eq!(bus.read(0x8010), 0);
+
+        // Cancelled, it reads as stopped with a block left to copy
+        bus.write(map::HDMA5, 0x00);
+        assert_eq!(bus.read(map::HDMA5), 0x81);
+        bus.hblank_dma();
+        assert_eq!(bus.read(0x8010), 0);
+    }
+
+    #[test]
+    fn test_models() {
+        let mut bus = cgb_bus();
+        assert_eq!(bus.model(), Model::Cgb);
+        // Other models play CGB games as DMG ones
+        bus.set_model(Model::DmgAbc);
+        assert!(!bus.cgb());
+        assert!(bus.has_bootrom());
+        assert!(bus.set_bootrom(&[0; 0x900]).is_err());
+
+        bus.set_model(Model::Cgb);
+        assert!(bus.cgb());
+        assert!(!bus.has_bootrom());
+        let bootrom: Vec<u8> = (0..0x900).map(|i| (i >> 8) as u8 + 1).collect();
+        bus.set_bootrom(&bootrom).unwrap();
+        bus.load_bootrom();
+        assert_eq!(bus.read(0x00FF), 1);
+        // The cartridge header is there in the middle of it
+        assert_eq!(bus.read(map::CGB_FLAG), 0x80);
+        assert_eq!(bus.read(0x0800), 9);
+        bus.unload_bootrom();
+        assert_eq!(bus.read(0x0800), 0);
+
+        // The CGB boot ROM isn't kept for another model
+        bus.set_model(Model::DmgAbc);
+        bus.load_bootrom();
+        assert_eq!(bus.read(0x0800), 0);
+        assert_eq!(bus.read(0x00FF), DMG_BOOTROM[0xFF]);
+        bus.set_model(Model::Cgb);
+        assert!(!bus.has_bootrom());
+    }
+
+    #[test]
+    fn test_cgb_palettes() {
+        let mut bus = cgb_bus();
+
+        // Auto-increment from the second byte of palette 1
+        bus.write(map::BCPS, 0x80 | 9);
+        for value in [1, 2, 3] {
+            bus.write(map::BCPD, value);
+        }
+        assert_eq!(bus.read(map::BCPS), 0x80 | 12);
+        bus.write(map::BCPS, 10);
+        assert_eq!(bus.read(map::BCPD), 2);
+        bus.write(map::BCPD, 4);
+        assert_eq!(bus.read(map::BCPD), 4);
+        assert_eq!(bus.read(map::BCPS), 10);
+        assert_eq!(bus.palettes().0[9..12], [1, 4, 3]);
+        assert_eq!(bus.palettes().1, [0; 64]);
+    }
+}
@@ -0,0 +1,243 @@
+//! Finds where a game keeps a value, like a lives counter, by how it changes between
+//! snapshots of RAM.
+
+use super::{map, Bus, MemoryRange, WRAM_BANK_SIZE};
+
+/// First half of WRAM, never switched
+const WRAM_BANK0: MemoryRange = map::WRAM.start..map::WRAM_BANK1.start;
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum SearchWidth {
+    #[default]
+    Byte,
+    /// 16 bits, little endian
+    Word,
+}
+
+/// What a candidate's value must have done since the last snapshot to stay one
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SearchFilter {
+    Same,
+    Changed,
+    Increased,
+    Decreased,
+    Equal(u16),
+}
+
+impl SearchFilter {
+    fn keeps(self, previous: u16, value: u16) -> bool {
+        match self {
+            SearchFilter::Same => value == previous,
+            SearchFilter::Changed => value != previous,
+            SearchFilter::Increased => value > previous,
+            SearchFilter::Decreased => value < previous,
+            SearchFilter::Equal(n) => value == n,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Candidate {
+    pub address: u16,
+    /// WRAM bank (CGB) of an address in 0xD000-0xDFFF, whether or not SVBK switched it in
+    pub bank: Option<u8>,
+    /// Value at the snapshot before the last one
+    pub previous: u16,
+    /// Value at the last snapshot
+    pub value: u16,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct MemorySearch {
+    width: SearchWidth,
+    candidates: Vec<Candidate>,
+}
+
+impl MemorySearch {
+    /// Starts with every address of RAM as a candidate
+    pub fn new(bus: &Bus, width: SearchWidth) -> Self {
+        let unit = match width {
+            SearchWidth::Byte => 1,
+            SearchWidth::Word => 2,
+        };
+        let candidates = searched(bus)
+            .into_iter()
+            .flat_map(|(range, bank)| (range.start..=range.end - unit).map(move |a| (a, bank)))
+            .map(|(address, bank)| {
+                let value = read(bus, width, bank, address as u16);
+                Candidate {
+                    address: address as u16,
+                    bank,
+                    previous: value,
+                    value,
+                }
+            })
+            .collect();
+        Self { width, candidates }
+    }
+
+    /// Takes a new snapshot, keeping the candidates whose value changed as `filter` asks
+    pub fn filter(&mut self, bus: &Bus, filter: SearchFilter) {
+        let width = self.width;
+        self.candidates.retain_mut(|candidate| {
+            let value = read(bus, width, candidate.bank, candidate.address);
+            candidate.previous = candidate.value;
+            candidate.value = value;
+            filter.keeps(candidate.previous, value)
+        });
+    }
+
+    pub fn width(&self) -> SearchWidth {
+        self.width
+    }
+
+    pub fn candidates(&self) -> &[Candidate] {
+        &self.candidates
+    }
+
+    /// Reads a candidate's value as it is now, not as the last snapshot saw it
+    pub fn read(&self, bus: &Bus, candidate: &Candidate) -> u16 {
+        read(bus, self.width, candidate.bank, candidate.address)
+    }
+}
+
+/// RAM a game keeps its state in: cartridge RAM if the header says it has some, work RAM
+/// with every bank in CGB mode, and high RAM
+fn searched(bus: &Bus) -> Vec<(MemoryRange, Option<u8>)> {
+    let mut ranges = Vec::new();
+    if bus.memory().cartridge.borrow().get_ram_size() != 0 {
+        ranges.push((map::EXT_WRAM, None));
+    }
+    ranges.push((WRAM_BANK0, None));
+    if bus.cgb() {
+        ranges.extend((1..=7).map(|bank| (map::WRAM_BANK1, Some(bank))));
+    } else {
+        ranges.push((map::WRAM_BANK1, None));
+    }
+    ranges.push((map::HRAM, None));
+    ranges
+}
+
+fn read(bus: &Bus, width: SearchWidth, bank: Option<u8>, address: u16) -> u16 {
+    let low = read_byte(bus, bank, address) as u16;
+    match width {
+        SearchWidth::Byte => low,
+        SearchWidth::Word => low | (read_byte(bus, bank, address + 1) as u16) << 8,
+    }
+}
+
+fn read_byte(bus: &Bus, bank: Option<u8>, address: u16) -> u8 {
+    let memory = bus.memory();
+    match bank {
+        // Banks SVBK didn't switch in are put away
+        Some(bank) if bank as usize != memory.wram_bank() => {
+            let offset = address as usize - map::WRAM_BANK1.start;
+            memory.wram_banks[(bank as usize - 1) * WRAM_BANK_SIZE + offset]
+        }
+        _ => {
+            drop(memory);
+            bus.read(address as usize)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        let mut bus = Bus::new();
+        // MBC3 with 8 KB of RAM
+        let mut rom = vec![0; 0x8000];
+        rom[map::CARTRIDGE_TYPE] = 0x12;
+        rom[map::RAM_SIZE] = 0x02;
+        bus.load_rom(&rom);
+        let search = bus.memsearch(SearchWidth::Byte);
+        assert_eq!(
+            search.candidates().len(),
+            map::EXT_WRAM.len() + map::WRAM.len() + map::HRAM.len()
+        );
+
+        // Without cartridge RAM
+        rom[map::CARTRIDGE_TYPE] = 0;
+        rom[map::RAM_SIZE] = 0;
+        bus.load_rom(&rom);
+        let mut search = bus.memsearch(SearchWidth::Byte);
+        assert_eq!(search.candidates().len(), map::WRAM.len() + map::HRAM.len());
+
+        // Lives go from 3 to 2, while a timer at 0xC100 goes up
+        bus.write(0xC123, 3);
+        bus.write(0xFF90, 3);
+        search.filter(&bus, SearchFilter::Equal(3));
+        assert_eq!(search.candidates().len(), 2);
+        bus.write(0xC123, 2);
+        search.filter(&bus, SearchFilter::Decreased);
+        assert_eq!(
+            search.candidates(),
+            [Candidate {
+                address: 0xC123,
+                bank: None,
+                previous: 3,
+                value: 2,
+            }]
+        );
+        search.filter(&bus, SearchFilter::Same);
+        assert_eq!(search.candidates().len(), 1);
+        search.filter(&bus, SearchFilter::Changed);
+        assert!(search.candidates().is_empty());
+
+        bus.write(0xC200, 0x34);
+        bus.write(0xC201, 0x12);
+        let mut search = bus.memsearch(SearchWidth::Word);
+        bus.write(0xC201, 0x13);
+        search.filter(&bus, SearchFilter::Increased);
+        assert_eq!(
+            search.candidates(),
+            [
+                Candidate {
+                    address: 0xC200,
+                    bank: None,
+                    previous: 0x1234,
+                    value: 0x1334,
+                },
+                Candidate {
+                    address: 0xC201,
+                    bank: None,
+                    previous: 0x0012,
+                    value: 0x0013,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_search_wram_banks() {
+        let mut bus = Bus::new();
+        let mut rom = vec![0; 0x8000];
+        rom[map::CGB_FLAG] = 0x80;
+        bus.load_rom(&rom);
+        assert!(bus.cgb());
+        let mut search = bus.memsearch(SearchWidth::Byte);
+        assert_eq!(
+            search.candidates().len(),
+            WRAM_BANK0.len() + 7 * map::WRAM_BANK1.len() + map::HRAM.len()
+        );
+
+        // Found in bank 3 while bank 5 is switched in
+        bus.write(map::SVBK, 3);
+        bus.write(0xD010, 42);
+        bus.write(map::SVBK, 5);
+        search.filter(&bus, SearchFilter::Equal(42));
+        let found = Candidate {
+            address: 0xD010,
+            bank: Some(3),
+            previous: 0,
+            value: 42,
+        };
+        assert_eq!(search.candidates(), [found]);
+        bus.write(map::SVBK, 3);
+        bus.write(0xD010, 41);
+        assert_eq!(search.read(&bus, &found), 41);
+    }
+}
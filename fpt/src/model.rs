@@ -0,0 +1,126 @@
+//! The Game Boy models, which boot games in different states and run boot ROMs of their own
+//! <https://gbdev.io/pandocs/Power_Up_Sequence.html>
+
+use std::fmt;
+
+/// Size of the boot ROMs of every model but the CGB, mapped at 0x0000-0x00FF
+pub const BOOTROM_SIZE: usize = 0x100;
+
+/// Size of the CGB boot ROM, mapped at 0x0000-0x00FF and, past the cartridge header,
+/// at 0x0200-0x08FF
+pub const CGB_BOOTROM_SIZE: usize = 0x900;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Model {
+    /// The first Game Boys, with an early boot ROM
+    Dmg0,
+    /// Later Game Boys
+    DmgAbc,
+    /// Game Boy Pocket
+    Mgb,
+    /// Super Game Boy
+    Sgb,
+    /// Super Game Boy 2
+    Sgb2,
+    /// Game Boy Color
+    Cgb,
+}
+
+impl Model {
+    pub const ALL: [Model; 6] = [
+        Model::Dmg0,
+        Model::DmgAbc,
+        Model::Mgb,
+        Model::Sgb,
+        Model::Sgb2,
+        Model::Cgb,
+    ];
+
+    /// The model of a name as `name` gives it, like `dmg-abc`
+    pub fn parse(name: &str) -> Result<Model, String> {
+        Model::ALL
+            .into_iter()
+            .find(|model| model.name() == name.to_lowercase())
+            .ok_or_else(|| {
+                let names: Vec<&str> = Model::ALL.iter().map(|model| model.name()).collect();
+                format!("no model {name}, expected one of {}", names.join(", "))
+            })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Model::Dmg0 => "dmg0",
+            Model::DmgAbc => "dmg-abc",
+            Model::Mgb => "mgb",
+            Model::Sgb => "sgb",
+            Model::Sgb2 => "sgb2",
+            Model::Cgb => "cgb",
+        }
+    }
+
+    pub fn bootrom_size(self) -> usize {
+        match self {
+            Model::Cgb => CGB_BOOTROM_SIZE,
+            _ => BOOTROM_SIZE,
+        }
+    }
+
+    /// Whether it's a Game Boy or a Game Boy Pocket, which the DMG boot ROM boots
+    pub fn dmg(self) -> bool {
+        matches!(self, Model::Dmg0 | Model::DmgAbc | Model::Mgb)
+    }
+
+    /// Whether it's a Super Game Boy, which games may send commands to
+    pub fn sgb(self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::map;
+    use crate::Gameboy;
+
+    #[test]
+    fn test_bootrom_size() {
+        assert_eq!(Model::Cgb.bootrom_size(), 2304);
+        for model in Model::ALL.into_iter().filter(|&model| model != Model::Cgb) {
+            assert_eq!(model.bootrom_size(), 256);
+        }
+    }
+
+    #[test]
+    fn test_model_from_header() {
+        let model = |cgb_flag, sgb_flag, licensee| {
+            let mut rom = vec![0; 0x8000];
+            rom[map::CGB_FLAG] = cgb_flag;
+            rom[map::SGB_FLAG] = sgb_flag;
+            rom[map::OLD_LICENSEE_CODE] = licensee;
+            let mut gb = Gameboy::new();
+            gb.load_rom(&rom);
+            gb.model()
+        };
+        assert_eq!(model(0x00, 0x00, 0x00), Model::Dmg0);
+        assert_eq!(model(0x80, 0x00, 0x00), Model::Cgb);
+        assert_eq!(model(0xC0, 0x03, 0x33), Model::Cgb);
+        assert_eq!(model(0x00, 0x03, 0x33), Model::Sgb);
+        // The SGB flag only counts when the old licensee code is 0x33
+        assert_eq!(model(0x00, 0x03, 0x01), Model::Dmg0);
+    }
+
+    #[test]
+    fn test_parse_display() {
+        for model in Model::ALL {
+            assert_eq!(Model::parse(&model.to_string()), Ok(model));
+        }
+        assert_eq!(Model::parse("DMG-ABC"), Ok(Model::DmgAbc));
+        assert!(Model::parse("agb").is_err());
+    }
+}
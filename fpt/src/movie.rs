@@ -0,0 +1,445 @@
+//! Input movies: the buttons pressed at every frame since power on, to play a game again
+//! exactly the same way. Hashes of some frames are kept along, so that playing back can
+//! tell where the emulator first drew something else.
+//!
+//! Input only changes between frames while recording, so that playing back, which sets
+//! the buttons at the same points, gets the same input at the same cycle.
+
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::memory::Buttons;
+use crate::model::Model;
+use crate::ppu::Frame;
+use crate::Gameboy;
+
+/// First line of a movie file
+const MAGIC: &str = "fpt movie 1";
+
+/// Frames between hashes, about one second
+pub const CHECKPOINT_INTERVAL: u32 = 60;
+
+/// How the emulator powered on
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum Boot {
+    /// Runs the boot ROM
+    #[default]
+    Real,
+    /// Skips the boot ROM, see [`Gameboy::boot_fake`]
+    Fake,
+}
+
+impl Boot {
+    pub fn apply(self, gameboy: &mut Gameboy) {
+        match self {
+            Boot::Real => gameboy.boot_real(),
+            Boot::Fake => gameboy.boot_fake(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct MovieFrame {
+    pub buttons: Buttons,
+    /// Hash of the picture at the end of the frame, at checkpoints
+    pub hash: Option<u64>,
+}
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Movie {
+    /// Title of the game, from the cartridge header
+    pub title: String,
+    pub boot: Boot,
+    /// The model it was recorded on, which older movies don't say
+    pub model: Option<Model>,
+    /// Unix time the cartridge clock starts at. MBC3 clocks aren't emulated yet, so this
+    /// is only kept for when they are.
+    pub rtc_seed: u64,
+    pub checkpoint_interval: u32,
+    pub frames: Vec<MovieFrame>,
+}
+
+/// Where playing back first went another way than recording
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Desync {
+    pub frame: usize,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "desync at frame {}: expected hash {:016x}, got {:016x}",
+            self.frame, self.expected, self.actual
+        )
+    }
+}
+
+/// FNV-1a hash of a picture, to tell frames apart
+pub fn frame_hash(frame: &Frame) -> u64 {
+    frame.iter().fold(0xcbf29ce484222325, |hash, &pixel| {
+        (hash ^ pixel as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn buttons_to_string(buttons: &Buttons) -> String {
+    [
+        (buttons.up, 'U'),
+        (buttons.down, 'D'),
+        (buttons.left, 'L'),
+        (buttons.right, 'R'),
+        (buttons.a, 'A'),
+        (buttons.b, 'B'),
+        (buttons.select, 's'),
+        (buttons.start, 'S'),
+    ]
+    .iter()
+    .map(|&(pressed, c)| if pressed { c } else { '.' })
+    .collect()
+}
+
+fn buttons_from_str(text: &str) -> Option<Buttons> {
+    let pressed: Vec<bool> = text.chars().map(|c| c != '.').collect();
+    let [up, down, left, right, a, b, select, start] = pressed[..] else {
+        return None;
+    };
+    Some(Buttons {
+        a,
+        b,
+        start,
+        select,
+        up,
+        right,
+        down,
+        left,
+    })
+}
+
+impl Movie {
+    /// Writes a header, where the boot line also has the model, as in `boot fake cgb`. Then
+    /// writes a line per frame with the buttons pressed, as in `U.L.A..S` for up, left, A
+    /// and start, and the hash at checkpoints.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let boot = match self.boot {
+            Boot::Real => "real",
+            Boot::Fake => "fake",
+        };
+        let model = self
+            .model
+            .map(|model| format!(" {model}"))
+            .unwrap_or_default();
+        let mut text = format!(
+            "{MAGIC}\ntitle {}\nboot {boot}{model}\nrtc {}\ncheckpoint {}\n",
+            self.title, self.rtc_seed, self.checkpoint_interval
+        );
+        for frame in &self.frames {
+            let _ = write!(text, "{}", buttons_to_string(&frame.buttons));
+            if let Some(hash) = frame.hash {
+                let _ = write!(text, " {hash:016x}");
+            }
+            text.push('\n');
+        }
+        fs::write(path, text)
+    }
+
+    pub fn load(path: &Path) -> Result<Movie, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let error = |line: usize, e: &str| format!("{}:{}: {e}", path.display(), line + 1);
+        let mut lines = text.lines().enumerate();
+        if lines.next().map(|(_, l)| l) != Some(MAGIC) {
+            return Err(error(0, &format!("expected `{MAGIC}`")));
+        }
+
+        let mut movie = Movie {
+            checkpoint_interval: CHECKPOINT_INTERVAL,
+            ..Default::default()
+        };
+        for (i, line) in lines.by_ref().take(4) {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            let invalid = || error(i, &format!("invalid {key} {value}"));
+            match key {
+                "title" => movie.title = value.to_string(),
+                "boot" => {
+                    let (boot, model) = match value.split_once(' ') {
+                        Some((boot, model)) => (boot, Some(model)),
+                        None => (value, None),
+                    };
+                    movie.boot = match boot {
+                        "real" => Boot::Real,
+                        "fake" => Boot::Fake,
+                        _ => return Err(invalid()),
+                    };
+                    movie.model = model
+                        .map(Model::parse)
+                        .transpose()
+                        .map_err(|e| error(i, &e))?;
+                }
+                "rtc" => movie.rtc_seed = value.parse().map_err(|_| invalid())?,
+                "checkpoint" => movie.checkpoint_interval = value.parse().map_err(|_| invalid())?,
+                _ => return Err(invalid()),
+            }
+        }
+
+        for (i, line) in lines {
+            let mut fields = line.split(' ');
+            let buttons = buttons_from_str(fields.next().unwrap_or_default())
+                .ok_or_else(|| error(i, "expected 8 buttons, like U.L.A..S"))?;
+            let hash = match fields.next() {
+                Some(hash) => Some(
+                    u64::from_str_radix(hash, 16)
+                        .map_err(|_| error(i, &format!("invalid hash {hash}")))?,
+                ),
+                None => None,
+            };
+            movie.frames.push(MovieFrame { buttons, hash });
+        }
+        Ok(movie)
+    }
+}
+
+enum Mode {
+    /// With the buttons to press at the next frame
+    Recording(Buttons),
+    Playing(Option<Desync>),
+}
+
+/// A movie being recorded or played back
+pub struct MovieState {
+    movie: Movie,
+    mode: Mode,
+    /// Index in the movie of the frame running
+    frame: usize,
+    /// The PPU's frame counter when that frame started
+    frame_counter: u32,
+}
+
+impl MovieState {
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn recording(&self) -> bool {
+        matches!(self.mode, Mode::Recording(_))
+    }
+
+    /// Index of the frame running
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    /// Whether playing back got past the last frame of the movie
+    pub fn finished(&self) -> bool {
+        !self.recording() && self.frame >= self.movie.frames.len()
+    }
+
+    /// The first frame that didn't look like it did while recording
+    pub fn desync(&self) -> Option<Desync> {
+        match self.mode {
+            Mode::Playing(desync) => desync,
+            Mode::Recording(_) => None,
+        }
+    }
+}
+
+impl Gameboy {
+    /// Starts recording a movie, right after booting with `boot` the emulator that just
+    /// loaded a ROM
+    pub fn record_movie(&mut self, boot: Boot, rtc_seed: u64) {
+        let buttons = self.bus.buttons();
+        let movie = Movie {
+            title: self.bus.title().trim_end_matches('\0').to_string(),
+            boot,
+            model: Some(self.model()),
+            rtc_seed,
+            checkpoint_interval: CHECKPOINT_INTERVAL,
+            frames: vec![MovieFrame {
+                buttons,
+                hash: None,
+            }],
+        };
+        self.movie = Some(MovieState {
+            movie,
+            mode: Mode::Recording(buttons),
+            frame: 0,
+            frame_counter: self.ppu.frame_counter(),
+        });
+    }
+
+    /// Starts playing back `movie`, right after booting as it says the emulator that just
+    /// loaded its ROM. The buttons of the front-end are ignored from now on.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), String> {
+        let title = self.bus.title();
+        let title = title.trim_end_matches('\0');
+        if movie.title != title {
+            return Err(format!("the movie is of {}, not of {}", movie.title, title));
+        }
+        if let Some(model) = movie.model.filter(|&model| model != self.model()) {
+            return Err(format!(
+                "the movie was recorded on a {model}, not on a {}",
+                self.model()
+            ));
+        }
+        if let Some(first) = movie.frames.first() {
+            self.press(&first.buttons);
+        }
+        self.movie = Some(MovieState {
+            movie,
+            mode: Mode::Playing(None),
+            frame: 0,
+            frame_counter: self.ppu.frame_counter(),
+        });
+        Ok(())
+    }
+
+    /// Stops recording or playing back, and returns the movie
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        let mut state = self.movie.take()?;
+        if state.recording() {
+            // The last frame didn't finish
+            state.movie.frames.pop();
+        }
+        Some(state.movie)
+    }
+
+    pub fn movie(&self) -> Option<&MovieState> {
+        self.movie.as_ref()
+    }
+
+    /// Whether the movie takes over the input, in which case `buttons` are only pressed,
+    /// if recording, when the next frame starts
+    pub(crate) fn movie_input(&mut self, buttons: &Buttons) -> bool {
+        match &mut self.movie {
+            Some(MovieState {
+                mode: Mode::Recording(next),
+                ..
+            }) => *next = *buttons,
+            Some(MovieState {
+                mode: Mode::Playing(_),
+                ..
+            }) => {}
+            None => return false,
+        }
+        true
+    }
+
+    /// Moves the movie on to the next frame once the PPU starts one
+    pub(crate) fn movie_step(&mut self) {
+        let Some(state) = &mut self.movie else {
+            return;
+        };
+        if state.frame_counter == self.ppu.frame_counter() {
+            return;
+        }
+        state.frame_counter = self.ppu.frame_counter();
+
+        let finished = state.frame;
+        let checkpoint = (finished as u32 + 1) % state.movie.checkpoint_interval.max(1) == 0;
+        state.frame += 1;
+        let buttons = match &mut state.mode {
+            Mode::Recording(next) => {
+                if checkpoint {
+                    state.movie.frames[finished].hash = Some(frame_hash(self.ppu.get_frame()));
+                }
+                state.movie.frames.push(MovieFrame {
+                    buttons: *next,
+                    hash: None,
+                });
+                Some(*next)
+            }
+            Mode::Playing(desync) => {
+                let expected = state.movie.frames.get(finished).and_then(|f| f.hash);
+                if let (None, Some(expected)) = (*desync, expected) {
+                    let actual = frame_hash(self.ppu.get_frame());
+                    if actual != expected {
+                        *desync = Some(Desync {
+                            frame: finished,
+                            expected,
+                            actual,
+                        });
+                    }
+                }
+                state.movie.frames.get(state.frame).map(|f| f.buttons)
+            }
+        };
+        if let Some(buttons) = buttons {
+            self.press(&buttons);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Copies the action buttons to the first tile, which fills the background
+    fn gameboy() -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        // .loop: ld a, $10; ldh [$00], a; ldh a, [$00]; ld [$8000], a; jr .loop
+        rom[0x100..0x10B].copy_from_slice(&[
+            0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0xEA, 0x00, 0x80, 0x18, 0xF5,
+        ]);
+        rom[0x134..0x138].copy_from_slice(b"TEST");
+        let mut gb = Gameboy::new();
+        gb.load_rom(&rom);
+        Boot::Fake.apply(&mut gb);
+        gb
+    }
+
+    fn run_frames(gb: &mut Gameboy, frames: u32) {
+        let until = gb.ppu().frame_counter() + frames;
+        while gb.ppu().frame_counter() < until {
+            gb.step();
+        }
+    }
+
+    #[test]
+    fn test_record_and_play() {
+        let mut gb = gameboy();
+        gb.record_movie(Boot::Fake, 0);
+        gb.movie.as_mut().unwrap().movie.checkpoint_interval = 3;
+        run_frames(&mut gb, 3);
+        gb.set_buttons(&Buttons {
+            a: true,
+            ..Default::default()
+        });
+        // Only pressed once the next frame starts
+        assert_eq!(gb.bus().buttons(), Buttons::default());
+        run_frames(&mut gb, 9);
+        let movie = gb.stop_movie().unwrap();
+        assert_eq!(movie.frames.len(), 12);
+        assert!(!movie.frames[3].buttons.a);
+        assert!(movie.frames[4].buttons.a);
+        assert_eq!(movie.frames.iter().filter(|f| f.hash.is_some()).count(), 4);
+
+        let path = std::env::temp_dir().join(format!("fpt-{}.movie", std::process::id()));
+        movie.save(&path).unwrap();
+        assert_eq!(Movie::load(&path), Ok(movie.clone()));
+        fs::remove_file(&path).unwrap();
+
+        let mut gb = gameboy();
+        gb.play_movie(movie.clone()).unwrap();
+        run_frames(&mut gb, 12);
+        assert!(gb.movie().unwrap().finished());
+        assert_eq!(gb.movie().unwrap().desync(), None);
+
+        // It was recorded on the DMG0 the game is played on by default
+        assert_eq!(movie.model, Some(Model::Dmg0));
+        let mut gb = gameboy();
+        gb.set_model(Model::Mgb);
+        assert!(gb.play_movie(movie.clone()).is_err());
+
+        // Releasing A for a while, which the checkpoint at frame 8 sees
+        let mut edited = movie;
+        for frame in &mut edited.frames[6..9] {
+            frame.buttons.a = false;
+        }
+        let mut gb = gameboy();
+        gb.play_movie(edited).unwrap();
+        run_frames(&mut gb, 12);
+        assert_eq!(gb.movie().unwrap().desync().unwrap().frame, 8);
+    }
+}
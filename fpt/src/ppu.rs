@@ -7,24 +7,39 @@ use crate::{bw, memory::map, memory::Bus};
 mod sprite;
 pub mod tile;
 
-use sprite::Sprite;
+use sprite::{Flags, Sprite};
 
 pub const SPRITE_SIZE: usize = 4;
 
 pub const WIDTH: usize = 160;
 pub const HEIGHT: usize = 144;
 pub type Frame = [u8; WIDTH * HEIGHT]; // TODO: wasteful, each pixel is 2 bits only
+/// A frame in colors, as 0xRRGGBB
+pub type RgbFrame = [u32; WIDTH * HEIGHT];
 
-//#[derive(Clone, PartialEq)]
+/// Colors of the DMG shades in an `RgbFrame`, from the lightest to the darkest
+pub const GREYSCALE: [u32; 4] = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
+
+#[derive(Clone)]
 #[allow(unused)]
 pub struct Ppu {
     bus: Bus,
     frame: Frame,
+    rgb_frame: RgbFrame,
     dots_this_frame: u32,
     frame_counter: u32,
     mode: Mode,
     tilemap: VRamContents,
+    /// VRAM bank 1 (CGB): more tiles, and where bank 0 has tile maps, the attributes of
+    /// their tiles
+    bank1: VRamContents,
     sprites: Vec<Sprite>,
+    /// Whether half a dot is left from the last t-cycle of the CPU in double speed mode
+    half_dot: bool,
+    /// Whether the line is drawn in CGB mode
+    cgb: bool,
+    bg_palettes: [u8; 64],
+    obj_palettes: [u8; 64],
 }
 
 #[repr(u8)]
@@ -64,11 +79,17 @@ impl Ppu {
         Ppu {
             bus,
             frame: [0b00; WIDTH * HEIGHT],
+            rgb_frame: [GREYSCALE[0]; WIDTH * HEIGHT],
             dots_this_frame: 0,
             frame_counter: 0,
             mode: Mode::OamScan,
             tilemap: VRamContents::default(),
+            bank1: VRamContents::default(),
             sprites: Vec::new(),
+            half_dot: false,
+            cgb: false,
+            bg_palettes: [0; 64],
+            obj_palettes: [0; 64],
         }
     }
 
@@ -78,13 +99,30 @@ impl Ppu {
         }
     }
 
+    /// Runs the dots `cycles` t-cycles of the CPU take, which are half as many in double
+    /// speed mode
+    pub fn step_cpu(&mut self, cycles: u32) {
+        if self.bus.double_speed() {
+            let cycles = cycles + self.half_dot as u32;
+            self.half_dot = cycles % 2 == 1;
+            self.step(cycles / 2);
+        } else {
+            self.step(cycles);
+        }
+    }
+
     fn set_mode(&mut self, mode: Mode) {
         self.mode = mode;
     }
 
     fn oam_scan(&mut self) {
         if self.dots_this_frame % 456 == (80 - 1) {
-            self.tilemap = self.bus.with_vram(VRamContents::load);
+            self.tilemap = self.bus.with_vram_bank(0, VRamContents::load);
+            self.cgb = self.bus.cgb();
+            if self.cgb {
+                self.bank1 = self.bus.with_vram_bank(1, VRamContents::load);
+                (self.bg_palettes, self.obj_palettes) = self.bus.palettes();
+            }
             self.sprites = map::OAM
                 .step_by(SPRITE_SIZE)
                 .map(|index| {
@@ -96,7 +134,7 @@ impl Ppu {
         }
     }
 
-    /// Currently only draws the background pixels, not the window or sprites
+    /// Currently only draws the background pixels and sprites, not the window
     #[allow(clippy::format_collect)]
     fn pixel_transfer(&mut self) {
         let lcdc = self.bus.lcdc();
@@ -109,36 +147,95 @@ impl Ppu {
         let xx = ((x as u8 + self.bus.scx()) as u16 % 256u16) as usize;
         let yy = ((self.bus.ly() + self.bus.scy()) as u16 % 256u16) as usize;
         let tile_i = xx / 8 + yy / 8 * 32;
-        let tile_data_address = match bw::test_bit8::<3>(lcdc) {
-            false => self.tilemap.tile_map0[tile_i],
-            true => self.tilemap.tile_map1[tile_i],
+        let (tile_map, attribute_map) = match bw::test_bit8::<3>(lcdc) {
+            false => (&self.tilemap.tile_map0, &self.bank1.tile_map0),
+            true => (&self.tilemap.tile_map1, &self.bank1.tile_map1),
         };
+        let attributes = Flags::from(if self.cgb { attribute_map[tile_i] } else { 0 });
         let tile = self
-            .tilemap
-            .get_tile(tile_data_address as usize, bw::test_bit8::<4>(lcdc));
-        let mut pixel = tile.get_pixel(yy % 8, xx % 8);
-
-        for sprite in self.sprites.iter() {
-            let sprite_x = sprite.x as i32 - 8;
-            let sprite_y = sprite.y as i32 - 16;
-
-            let x = x as i32;
-            let y = y as i32;
-            if x >= (sprite_x) && x < sprite_x + 8 && y >= sprite_y && y < sprite_y + 8 {
-                let tile = self.tilemap.get_tile(sprite.tile_index as usize, true);
-                let tile_x = (x - sprite_x) as usize;
-                let tile_y = (y - sprite_y) as usize;
-                let sprite_pixel = tile.get_pixel(tile_y, tile_x);
-                // Color 0 means transparent
-                if sprite_pixel != 0 {
-                    pixel = sprite_pixel;
+            .vram(attributes.bank)
+            .get_tile(tile_map[tile_i] as usize, bw::test_bit8::<4>(lcdc));
+        let bg_pixel = tile.get_pixel(
+            flip(yy % 8, attributes.y_flip),
+            flip(xx % 8, attributes.x_flip),
+        );
+
+        let (pixel, color) = match self.sprite_pixel(x, y) {
+            Some((sprite, pixel)) if self.sprite_over_bg(lcdc, &attributes, sprite, bg_pixel) => (
+                pixel,
+                self.color(&self.obj_palettes, sprite.flags.cgb_palette, pixel),
+            ),
+            _ => (
+                bg_pixel,
+                self.color(&self.bg_palettes, attributes.cgb_palette, bg_pixel),
+            ),
+        };
+        self.frame[WIDTH * y + x] = pixel;
+        self.rgb_frame[WIDTH * y + x] = color;
+    }
+
+    /// The tiles of VRAM bank 1 if `bank1` and in CGB mode, or else of bank 0
+    fn vram(&self, bank1: bool) -> &VRamContents {
+        if bank1 && self.cgb {
+            &self.bank1
+        } else {
+            &self.tilemap
+        }
+    }
+
+    /// The sprite drawn at `x`, `y`, if any, and the color of its pixel there. Where
+    /// sprites overlap, the first in OAM wins in CGB mode, and the leftmost one on DMG or
+    /// when OPRI asks for it.
+    fn sprite_pixel(&self, x: usize, y: usize) -> Option<(&Sprite, u8)> {
+        let by_x = !self.cgb || bw::test_bit8::<0>(self.bus.read(map::OPRI));
+        self.sprites
+            .iter()
+            .enumerate()
+            .filter_map(|(i, sprite)| {
+                let tile_x = x as i32 - (sprite.x as i32 - 8);
+                let tile_y = y as i32 - (sprite.y as i32 - 16);
+                if !(0..8).contains(&tile_x) || !(0..8).contains(&tile_y) {
+                    return None;
                 }
-            }
+                let tile = self
+                    .vram(sprite.flags.bank)
+                    .get_tile(sprite.tile_index as usize, true);
+                let pixel = tile.get_pixel(
+                    flip(tile_y as usize, sprite.flags.y_flip),
+                    flip(tile_x as usize, sprite.flags.x_flip),
+                );
+                // Color 0 means transparent
+                (pixel != 0).then_some((i, sprite, pixel))
+            })
+            .min_by_key(|(i, sprite, _)| (if by_x { sprite.x } else { 0 }, *i))
+            .map(|(_, sprite, pixel)| (sprite, pixel))
+    }
+
+    /// Whether a sprite pixel is drawn over a BG pixel of color `bg_pixel`, which has
+    /// `attributes` in CGB mode
+    fn sprite_over_bg(&self, lcdc: u8, attributes: &Flags, sprite: &Sprite, bg_pixel: u8) -> bool {
+        if self.cgb && !bw::test_bit8::<0>(lcdc) {
+            // In CGB mode, LCDC.0 clear takes away the priority of BG and window
+            return true;
         }
-        self.frame[WIDTH * y + x] = pixel;
+        // Either priority flag puts BG colors 1-3 over the sprite
+        bg_pixel == 0 || !(attributes.priority || sprite.flags.priority)
+    }
+
+    /// The color of `pixel` with CGB palette `palette` of `palettes`, or its shade on DMG
+    fn color(&self, palettes: &[u8; 64], palette: u8, pixel: u8) -> u32 {
+        if !self.cgb {
+            return GREYSCALE[pixel as usize];
+        }
+        let i = (palette as usize * 4 + pixel as usize) * 2;
+        rgb555(u16::from_le_bytes([palettes[i], palettes[i + 1]]))
     }
 
     fn h_blank(&mut self) {
+        if self.dots_this_frame % 456 == (80 + 160 + 1) as u32 {
+            // HBlank DMA copies a block each time HBlank starts (CGB)
+            self.bus.hblank_dma();
+        }
         if self.dots_this_frame >= (456 * HEIGHT - 1) as u32 {
             self.set_mode(Mode::VBlank);
         } else if self.dots_this_frame % 456 == 455 {
@@ -150,6 +247,8 @@ impl Ppu {
         if self.dots_this_frame == 144 * 456 {
             self.bus
                 .set_iflag(bw::set_bit8::<0>(self.bus.iflag(), true));
+            self.bus.apply_ram_cheats();
+            self.bus.sgb_vblank(&self.frame);
         }
         if self.dots_this_frame == DOTS_IN_ONE_FRAME - 1 {
             self.set_mode(Mode::OamScan);
@@ -204,6 +303,35 @@ impl Ppu {
     pub fn get_frame(&self) -> &Frame {
         &self.frame
     }
+
+    /// The frame in the colors of the CGB palettes, or in `GREYSCALE` on DMG
+    pub fn get_rgb_frame(&self) -> &RgbFrame {
+        &self.rgb_frame
+    }
+
+    /// Frames drawn since power on
+    pub fn frame_counter(&self) -> u32 {
+        self.frame_counter
+    }
+}
+
+/// A color of the CGB and SGB palettes, 5 bits per channel with red in the lowest ones,
+/// as 0xRRGGBB
+pub fn rgb555(color: u16) -> u32 {
+    let channel = |shift: u16| {
+        let c = (color >> shift & 0x1F) as u32;
+        c << 3 | c >> 2
+    };
+    channel(0) << 16 | channel(5) << 8 | channel(10)
+}
+
+/// Row or column `i` of a tile, counting from the other side if `flipped`
+fn flip(i: usize, flipped: bool) -> usize {
+    if flipped {
+        7 - i
+    } else {
+        i
+    }
 }
 
 #[cfg(test)]
@@ -226,4 +354,89 @@ mod tests {
         gb.ppu.step(4560);
         assert_eq!(gb.ppu.mode, Mode::OamScan);
     }
+
+    #[test]
+    fn test_double_speed() {
+        let mut rom = vec![0; 0x8000];
+        rom[map::CGB_FLAG] = 0x80;
+        // ld a, 1; ldh [KEY1], a; stop; .loop: jr .loop
+        rom[0x100..0x108].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x18, 0xFE]);
+        let mut gb: Gameboy = Gameboy::new();
+        gb.load_rom(&rom);
+        gb.boot_fake();
+
+        while !gb.cpu().stopped() {
+            gb.step();
+        }
+        assert!(gb.double_speed());
+        let dots = gb.ppu.dots_this_frame;
+        let mut stopped_for = 0;
+        while gb.cpu().stopped() {
+            stopped_for += gb.step() as u32;
+        }
+        assert_eq!(stopped_for, 2050 * 4);
+        assert_eq!(gb.ppu.dots_this_frame, dots + 2050 * 2);
+
+        let frame = gb.ppu.frame_counter() + 1;
+        while gb.ppu.frame_counter() < frame {
+            gb.step();
+        }
+        let mut cycles = 0;
+        while gb.ppu.frame_counter() == frame {
+            cycles += gb.step() as u32;
+        }
+        assert_eq!(cycles, gb.cycles_in_one_frame());
+        assert_eq!(cycles, 2 * DOTS_IN_ONE_FRAME);
+    }
+
+    #[test]
+    fn test_cgb_rendering() {
+        let mut rom = vec![0; 0x8000];
+        rom[map::CGB_FLAG] = 0xC0;
+        let mut gb: Gameboy = Gameboy::new();
+        gb.load_rom(&rom);
+        gb.boot_fake();
+        let bus = &mut gb.bus;
+        bus.write(map::LCDC, 0x93); // Tile data at 0x8000, sprites on
+
+        // BG palette 2 color 3 is red, OBJ palette 1 color 1 is blue
+        bus.write(map::BCPS, 2 * 8 + 3 * 2);
+        bus.write(map::BCPD, 0x1F);
+        bus.write(map::BCPS, 2 * 8 + 3 * 2 + 1);
+        bus.write(map::BCPD, 0x00);
+        bus.write(map::OCPS, 0x80 | (8 + 2));
+        bus.write(map::OCPD, 0x00);
+        bus.write(map::OCPD, 0x7C);
+
+        // Tile 1 of bank 1 is color 3, with its left column color 1
+        bus.write(map::VBK, 1);
+        for row in 0..8 {
+            bus.write(0x8010 + 2 * row, 0xFF);
+            bus.write(0x8010 + 2 * row + 1, 0x7F);
+        }
+        // The first BG tile is tile 1 of bank 1, with palette 2, flipped horizontally
+        bus.write(0x9800, 0b0010_1010);
+        bus.write(map::VBK, 0);
+        bus.write(0x9800, 1);
+
+        // A sprite with tile 1 of bank 1 and palette 1, 4 pixels right, flipped horizontally
+        // and behind BG colors 1-3
+        bus.write(map::OAM.start, 16);
+        bus.write(map::OAM.start + 1, 12);
+        bus.write(map::OAM.start + 2, 1);
+        bus.write(map::OAM.start + 3, 0b1010_1001);
+
+        gb.ppu.step(456);
+        let line = &gb.ppu.get_rgb_frame()[..12];
+        // The BG is flipped, so its color 1 column is the last, which is white
+        assert_eq!(line[..7], [0xFF0000; 7]);
+        assert_eq!(line[7], 0xFFFFFF);
+        // The sprite shows over BG color 0 only
+        assert_eq!(line[8..11], [0x000000; 3]);
+        assert_eq!(line[11], 0x0000FF);
+        assert_eq!(
+            gb.ppu.get_frame()[..12],
+            [3, 3, 3, 3, 3, 3, 3, 1, 3, 3, 3, 1]
+        );
+    }
 }
@@ -1,6 +1,8 @@
 use crate::bw::*;
 
-#[derive(Debug)]
+/// Attributes of a sprite, which in CGB mode BG map attributes share the layout of,
+/// `dmg_palette` aside
+#[derive(Clone, Debug)]
 #[allow(unused)]
 pub struct Flags {
     pub priority: bool,
@@ -15,8 +17,8 @@ impl Flags {
     pub fn from(memory: u8) -> Flags {
         Flags {
             priority: test_bit8::<7>(memory),
-            x_flip: test_bit8::<6>(memory),
-            y_flip: test_bit8::<5>(memory),
+            y_flip: test_bit8::<6>(memory),
+            x_flip: test_bit8::<5>(memory),
             dmg_palette: (memory >> 4) & 0b11,
             bank: test_bit8::<3>(memory),
             cgb_palette: memory & 0b111,
@@ -24,12 +26,11 @@ impl Flags {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Sprite {
     pub y: u8,
     pub x: u8,
     pub tile_index: u8,
-    #[allow(unused)]
     pub flags: Flags,
 }
 
@@ -1,6 +1,6 @@
 use std::fmt;
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, Write};
 
 use crate::ppu::Frame;
 
@@ -44,6 +44,7 @@ impl fmt::Debug for Tile {
 
 /// Represents the data that lives in VRAM:
 /// 3 * 128 tile blocks and two 32x32 tile maps
+#[derive(Clone)]
 pub struct VRamContents {
     /// Three blocks of 128 tiles shared by the BG/Win tiles and OBJ tiles
     pub tile_data: [Tile; NUM_TILES],
@@ -95,12 +96,12 @@ impl VRamContents {
 }
 
 /// Writes a Gameboy frame to a PGM file
-pub fn write_pgm_screenshot(frame: &Frame, filename: &str) {
+pub fn write_pgm_screenshot(frame: &Frame, filename: &str) -> io::Result<()> {
     // TODO: code dedup
-    let mut file = File::create(filename).unwrap();
+    let mut file = File::create(filename)?;
 
     // Write the header for a 160x144 PGM image with 4 shades of gray
-    write!(file, "P2\n# Game Boy screenshot: {filename}\n160 144\n3\n").unwrap();
+    write!(file, "P2\n# Game Boy screenshot: {filename}\n160 144\n3\n")?;
 
     // Our Game Boy's framebuffer seems to have a direct correspondence to this!
     for line in frame.array_chunks::<160>() {
@@ -111,8 +112,9 @@ pub fn write_pgm_screenshot(frame: &Frame, filename: &str) {
             .collect::<String>()
             + "\n";
 
-        file.write_all(pgm_line.as_bytes()).unwrap();
+        file.write_all(pgm_line.as_bytes())?;
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -184,7 +186,8 @@ mod tests {
             write_pgm_screenshot(
                 gb.get_frame(),
                 &format!("screenshots/test_one_tile_to_vram-ly_{ly:05}.pgm"),
-            );
+            )
+            .unwrap();
             gb.ppu.step(456);
         }
     }
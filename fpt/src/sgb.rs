@@ -0,0 +1,499 @@
+//! Super Game Boy: the commands games send it through JOYP to color the screen, put a
+//! border around it and read up to four controllers <https://gbdev.io/pandocs/SGB_Functions.html>
+
+use crate::memory::Buttons;
+use crate::ppu::{rgb555, Frame, HEIGHT, WIDTH};
+
+/// Size of the SGB picture, the screen within the border
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+
+/// Where the screen is in the SGB picture
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+
+/// The attributes give a palette to each 8x8 block of the screen
+const ATTR_WIDTH: usize = WIDTH / 8;
+const ATTR_HEIGHT: usize = HEIGHT / 8;
+
+/// Size of the data of a VRAM transfer
+const TRANSFER_SIZE: usize = 0x1000;
+
+/// Border tiles are 8x8 with 4 bits per pixel, as on the SNES
+const BORDER_TILE_SIZE: usize = 32;
+/// The border tile map is 32x32 tiles of 2 bytes, of which 28 lines show, followed by
+/// palettes 4-7 of 16 colors
+const BORDER_MAP_SIZE: usize = 32 * 32 * 2;
+const BORDER_LINES: usize = 28;
+const BORDER_PALETTES_SIZE: usize = 4 * 16 * 2;
+
+// Commands, the upper 5 bits of the first byte of a packet
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const MASK_EN: u8 = 0x17;
+
+// MASK_EN values
+const MASK_FREEZE: u8 = 1;
+const MASK_BLACK: u8 = 2;
+const MASK_COLOR0: u8 = 3;
+
+/// What the next frame is sent to the SNES as
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Transfer {
+    /// CHR_TRN, to tiles 0x80-0xFF if `high`, or else 0x00-0x7F
+    BorderTiles { high: bool },
+    /// PCT_TRN
+    BorderMap,
+}
+
+#[derive(Clone)]
+pub struct Sgb {
+    /// The packet being received and how many of its bits are in, since its reset pulse
+    packet: Option<([u8; PACKET_SIZE], usize)>,
+    /// Whether P14 and P15 are back high since the last bit, so the next pulse is a bit
+    ready: bool,
+    /// Packets received of a command that takes more than one
+    packets: Vec<[u8; PACKET_SIZE]>,
+    /// P14 and P15 as JOYP was last written
+    lines: u8,
+    /// 4 palettes of 4 colors, which share color 0
+    palettes: [[u16; 4]; 4],
+    /// Palette of each 8x8 block of the screen
+    attributes: [u8; ATTR_WIDTH * ATTR_HEIGHT],
+    border_tiles: Vec<u8>,
+    /// Border tile map and palettes, as PCT_TRN sends them
+    border_map: Vec<u8>,
+    mask: u8,
+    transfer: Option<Transfer>,
+    /// The screen as the SGB last got it, which MASK_EN can freeze
+    screen: Box<Frame>,
+    /// Controllers MLT_REQ asked for: 1, 2 or 4
+    players: u8,
+    /// Controller JOYP reads, counting from 0
+    player: u8,
+    /// Buttons of the second to fourth controllers
+    player_buttons: [Buttons; 3],
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Self {
+            packet: None,
+            ready: false,
+            packets: Vec::new(),
+            lines: 0b11,
+            // Greyscale, until a game sets its own
+            palettes: [[0x7FFF, 0x56B5, 0x294A, 0x0000]; 4],
+            attributes: [0; ATTR_WIDTH * ATTR_HEIGHT],
+            border_tiles: vec![0; 256 * BORDER_TILE_SIZE],
+            border_map: vec![0; BORDER_MAP_SIZE + BORDER_PALETTES_SIZE],
+            mask: 0,
+            transfer: None,
+            screen: Box::new([0; WIDTH * HEIGHT]),
+            players: 1,
+            player: 0,
+            player_buttons: [Buttons::default(); 3],
+        }
+    }
+
+    /// Follows what is written to JOYP, where pulling P14 or P15 low sends a bit of a
+    /// packet after a reset pulse pulls both, and where putting both back high moves on to
+    /// the next controller
+    pub fn write_joyp(&mut self, value: u8) {
+        let lines = value >> 4 & 0b11;
+        match lines {
+            0b00 => {
+                self.packet = Some(([0; PACKET_SIZE], 0));
+                self.ready = false;
+            }
+            0b11 => {
+                if self.lines != 0b11 && self.packet.is_none() {
+                    self.player = (self.player + 1) % self.players;
+                }
+                self.ready = true;
+            }
+            // P15 low sends a 1, P14 low a 0
+            _ if self.ready => {
+                self.ready = false;
+                self.receive(lines == 0b01);
+            }
+            _ => {}
+        }
+        self.lines = lines;
+    }
+
+    fn receive(&mut self, bit: bool) {
+        let Some((packet, bits)) = self.packet.as_mut() else {
+            return;
+        };
+        // After its 128 bits, a packet ends with a stop bit
+        if *bits < PACKET_SIZE * 8 {
+            packet[*bits / 8] |= (bit as u8) << (*bits % 8);
+            *bits += 1;
+            return;
+        }
+
+        self.packets.push(*packet);
+        self.packet = None;
+        let length = (self.packets[0][0] & 0b111).max(1) as usize;
+        if self.packets.len() >= length {
+            let data = self.packets.concat();
+            self.packets.clear();
+            self.run(&data);
+        }
+    }
+
+    /// Runs a command, from the data of all its packets
+    fn run(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            PAL01 => self.set_palettes(0, 1, data),
+            PAL23 => self.set_palettes(2, 3, data),
+            PAL03 => self.set_palettes(0, 3, data),
+            PAL12 => self.set_palettes(1, 2, data),
+            ATTR_BLK => self.attr_blk(data),
+            ATTR_LIN => self.attr_lin(data),
+            ATTR_DIV => self.attr_div(data),
+            MLT_REQ => {
+                self.players = match data[1] & 0b11 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            CHR_TRN => {
+                self.transfer = Some(Transfer::BorderTiles {
+                    high: data[1] & 1 == 1,
+                })
+            }
+            PCT_TRN => self.transfer = Some(Transfer::BorderMap),
+            MASK_EN => self.mask = data[1] & 0b11,
+            // Not emulated
+            _ => {}
+        }
+    }
+
+    /// PAL01, PAL23, PAL03 and PAL12: color 0 of every palette, then colors 1-3 of
+    /// palettes `first` and `second`
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |i: usize| u16::from_le_bytes([data[1 + 2 * i], data[2 + 2 * i]]);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    /// Palettes inside, on and outside of rectangles
+    fn attr_blk(&mut self, data: &[u8]) {
+        let sets = data[1] as usize;
+        for set in data[2..].chunks_exact(6).take(sets) {
+            let [control, palettes, x1, y1, x2, y2] = set.try_into().unwrap();
+            let (x1, y1, x2, y2) = (x1 as usize, y1 as usize, x2 as usize, y2 as usize);
+            let inside = (control & 0b001 != 0).then_some(palettes & 0b11);
+            let outside = (control & 0b100 != 0).then_some(palettes >> 4 & 0b11);
+            // Without its own palette, the rectangle's edge takes the inside or outside one
+            let edge = match control & 0b010 != 0 {
+                true => Some(palettes >> 2 & 0b11),
+                false => inside.or(outside),
+            };
+            self.set_attributes(|x, y| {
+                if x < x1 || x > x2 || y < y1 || y > y2 {
+                    outside
+                } else if x == x1 || x == x2 || y == y1 || y == y2 {
+                    edge
+                } else {
+                    inside
+                }
+            });
+        }
+    }
+
+    /// Palettes of whole lines or columns
+    fn attr_lin(&mut self, data: &[u8]) {
+        let sets = data[1] as usize;
+        for &set in data[2..].iter().take(sets) {
+            let line = (set & 0x1F) as usize;
+            let palette = set >> 5 & 0b11;
+            let horizontal = set & 0x80 != 0;
+            self.set_attributes(|x, y| {
+                let on_line = if horizontal { y == line } else { x == line };
+                on_line.then_some(palette)
+            });
+        }
+    }
+
+    /// Palettes on either side of a line or column, and on it
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0b11;
+        let before = data[1] >> 2 & 0b11;
+        let on = data[1] >> 4 & 0b11;
+        let horizontal = data[1] & 0x40 != 0;
+        let line = (data[2] & 0x1F) as usize;
+        self.set_attributes(|x, y| {
+            let position = if horizontal { y } else { x };
+            Some(match position.cmp(&line) {
+                std::cmp::Ordering::Less => before,
+                std::cmp::Ordering::Equal => on,
+                std::cmp::Ordering::Greater => after,
+            })
+        });
+    }
+
+    /// Sets the palette of the blocks `palette` gives one for
+    fn set_attributes(&mut self, palette: impl Fn(usize, usize) -> Option<u8>) {
+        for y in 0..ATTR_HEIGHT {
+            for x in 0..ATTR_WIDTH {
+                if let Some(palette) = palette(x, y) {
+                    self.attributes[y * ATTR_WIDTH + x] = palette;
+                }
+            }
+        }
+    }
+
+    /// Gets the frame just drawn, which a transfer sends as data
+    pub fn vblank(&mut self, frame: &Frame) {
+        if let Some(transfer) = self.transfer.take() {
+            let data = transferred(frame);
+            match transfer {
+                Transfer::BorderTiles { high } => {
+                    let start = high as usize * TRANSFER_SIZE;
+                    self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(&data);
+                }
+                Transfer::BorderMap => {
+                    let size = self.border_map.len();
+                    self.border_map.copy_from_slice(&data[..size]);
+                }
+            }
+        }
+        if self.mask != MASK_FREEZE {
+            *self.screen = *frame;
+        }
+    }
+
+    /// Controller JOYP reads, counting from 0
+    pub fn player(&self) -> u8 {
+        self.player
+    }
+
+    /// Buttons of the controller JOYP reads, where the first one has `first`
+    pub fn buttons(&self, first: Buttons) -> Buttons {
+        match self.player {
+            0 => first,
+            player => self.player_buttons[player as usize - 1],
+        }
+    }
+
+    /// Sets the buttons of the second (1) to fourth (3) controller
+    pub fn set_player_buttons(&mut self, player: usize, buttons: &Buttons) -> Result<(), String> {
+        let player_buttons = player
+            .checked_sub(1)
+            .and_then(|i| self.player_buttons.get_mut(i))
+            .ok_or_else(|| format!("no controller {player}, expected 1 to 3"))?;
+        *player_buttons = *buttons;
+        Ok(())
+    }
+
+    /// The SGB picture, as 0xRRGGBB: the screen in the colors of the palettes, within the
+    /// border
+    pub fn render(&self) -> Vec<u32> {
+        let mut picture = vec![rgb555(self.palettes[0][0]); SGB_WIDTH * SGB_HEIGHT];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let color = match self.mask {
+                    MASK_BLACK => 0,
+                    MASK_COLOR0 => self.palettes[0][0],
+                    _ => {
+                        let palette = self.attributes[y / 8 * ATTR_WIDTH + x / 8] as usize;
+                        self.palettes[palette][self.screen[y * WIDTH + x] as usize]
+                    }
+                };
+                picture[(SCREEN_Y + y) * SGB_WIDTH + SCREEN_X + x] = rgb555(color);
+            }
+        }
+
+        for (i, entry) in self.border_map[..32 * BORDER_LINES * 2]
+            .chunks_exact(2)
+            .enumerate()
+        {
+            let entry = u16::from_le_bytes([entry[0], entry[1]]);
+            let start = (entry & 0xFF) as usize * BORDER_TILE_SIZE;
+            let tile = &self.border_tiles[start..start + BORDER_TILE_SIZE];
+            // Palettes 4-7, the border's own
+            let palette = (entry >> 10 & 0b11) as usize;
+            let x_flip = entry & 0x4000 != 0;
+            let y_flip = entry & 0x8000 != 0;
+            for row in 0..8 {
+                for column in 0..8 {
+                    let tile_row = if y_flip { 7 - row } else { row };
+                    let bit = if x_flip { column } else { 7 - column };
+                    let color = [0, 1, 16, 17]
+                        .iter()
+                        .enumerate()
+                        .map(|(plane, offset)| (tile[offset + 2 * tile_row] >> bit & 1) << plane)
+                        .sum::<u8>() as usize;
+                    // Color 0 is transparent
+                    if color == 0 {
+                        continue;
+                    }
+                    let c = BORDER_MAP_SIZE + (palette * 16 + color) * 2;
+                    let (x, y) = (i % 32 * 8 + column, i / 32 * 8 + row);
+                    picture[y * SGB_WIDTH + x] = rgb555(u16::from_le_bytes([
+                        self.border_map[c],
+                        self.border_map[c + 1],
+                    ]));
+                }
+            }
+        }
+        picture
+    }
+}
+
+/// The 4 KB of VRAM a transfer sends, read back from the screen, where games show the
+/// 256 tiles in order, 20 a line
+fn transferred(frame: &Frame) -> Vec<u8> {
+    (0..TRANSFER_SIZE / 16)
+        .flat_map(|tile| {
+            let (x, y) = (tile % ATTR_WIDTH * 8, tile / ATTR_WIDTH * 8);
+            (0..8).flat_map(move |row| {
+                let pixels = &frame[(y + row) * WIDTH + x..][..8];
+                let plane = |bit: u8| {
+                    pixels
+                        .iter()
+                        .fold(0, |byte, pixel| byte << 1 | (pixel >> bit & 1))
+                };
+                [plane(0), plane(1)]
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{map, Bus};
+
+    fn sgb_bus() -> Bus {
+        let mut rom = vec![0; 0x8000];
+        rom[map::SGB_FLAG] = 0x03;
+        rom[map::OLD_LICENSEE_CODE] = 0x33;
+        let mut bus = Bus::new();
+        bus.load_rom(&rom);
+        assert!(bus.sgb());
+        bus
+    }
+
+    /// Sends the packets of a command through JOYP, as games do
+    fn send(bus: &mut Bus, command: &[u8]) {
+        for packet in command.chunks(PACKET_SIZE) {
+            bus.write(map::JOYP, 0x00);
+            bus.write(map::JOYP, 0x30);
+            let bits = (0..PACKET_SIZE * 8).map(|i| packet[i / 8] >> (i % 8) & 1);
+            // Then the stop bit
+            for bit in bits.chain([0]) {
+                bus.write(map::JOYP, if bit == 1 { 0x10 } else { 0x20 });
+                bus.write(map::JOYP, 0x30);
+            }
+        }
+    }
+
+    fn command(header: u8, data: &[u8]) -> Vec<u8> {
+        let length = (header & 0b111) as usize;
+        let mut command = vec![0; length * PACKET_SIZE];
+        command[0] = header;
+        command[1..1 + data.len()].copy_from_slice(data);
+        command
+    }
+
+    #[test]
+    fn test_palettes_and_attributes() {
+        let mut bus = sgb_bus();
+        // Red, then greens for palette 0 and blues for palette 1
+        #[rustfmt::skip]
+        send(&mut bus, &command(PAL01 << 3 | 1, &[
+            0x1F, 0x00,
+            0xE0, 0x03, 0xE0, 0x01, 0xE0, 0x00,
+            0x00, 0x7C, 0x00, 0x3C, 0x00, 0x1C,
+        ]));
+        // Palette 0 left of column 10, and palette 1 on and right of it
+        send(&mut bus, &command(ATTR_DIV << 3 | 1, &[0b01_00_01, 10]));
+
+        bus.sgb_vblank(&[1; WIDTH * HEIGHT]);
+        let picture = bus.sgb_frame().unwrap();
+        let at = |x, y| picture[(SCREEN_Y + y) * SGB_WIDTH + SCREEN_X + x];
+        assert_eq!(picture[0], 0xFF0000);
+        assert_eq!(at(79, 0), 0x00FF00);
+        assert_eq!(at(80, 143), 0x0000FF);
+    }
+
+    #[test]
+    fn test_multiplayer() {
+        let mut bus = sgb_bus();
+        let player = |bus: &Bus| 0xF - (bus.read(map::JOYP) & 0x0F);
+        bus.write(map::JOYP, 0x30);
+        assert_eq!(player(&bus), 0);
+
+        send(&mut bus, &command(MLT_REQ << 3 | 1, &[0x03]));
+        let buttons = Buttons {
+            left: true,
+            ..Default::default()
+        };
+        bus.set_player_buttons(2, &buttons).unwrap();
+        // The first controller's buttons are set as usual, and there are only four
+        assert!(bus.set_player_buttons(0, &buttons).is_err());
+        assert!(bus.set_player_buttons(4, &buttons).is_err());
+        // The stop bit already moved on to the second controller
+        assert_eq!(player(&bus), 1);
+        bus.write(map::JOYP, 0x20);
+        assert_eq!(bus.read(map::JOYP) & 0x0F, 0x0F);
+        bus.write(map::JOYP, 0x30);
+        assert_eq!(player(&bus), 2);
+        bus.write(map::JOYP, 0x20);
+        assert_eq!(bus.read(map::JOYP) & 0x0F, 0b1101);
+        bus.write(map::JOYP, 0x30);
+        bus.write(map::JOYP, 0x10);
+        bus.write(map::JOYP, 0x30);
+        assert_eq!(player(&bus), 0);
+    }
+
+    #[test]
+    fn test_border() {
+        let mut bus = sgb_bus();
+        // The screen shows tile 0 in color 1, which is border tile 0 with color 1 in every
+        // pixel, as its third and fourth bit planes are tile 1
+        let mut frame = [0; WIDTH * HEIGHT];
+        for y in 0..8 {
+            frame[y * WIDTH..y * WIDTH + 8].fill(1);
+        }
+        send(&mut bus, &command(CHR_TRN << 3 | 1, &[0]));
+        bus.sgb_vblank(&frame);
+
+        // The map is all tile 0 with palette 4, and line 1 of screen tile 128, at 0x802,
+        // makes its color 1 0x00FF
+        let mut frame = [0; WIDTH * HEIGHT];
+        frame[(6 * 8 + 1) * WIDTH + 8 * 8..][..8].fill(1);
+        send(&mut bus, &command(PCT_TRN << 3 | 1, &[]));
+        bus.sgb_vblank(&frame);
+
+        let picture = bus.sgb_frame().unwrap();
+        assert!(picture.iter().all(|&pixel| pixel == 0xFF3900));
+    }
+}
@@ -2,6 +2,7 @@ use super::memory::Bus;
 use crate::bw;
 use crate::memory::map;
 
+#[derive(Clone)]
 pub struct Timer {
     sys: u16, // system timer counter
     bus: Bus,
@@ -0,0 +1,211 @@
+//! Logs the CPU state before each instruction, to diff against other emulators.
+
+use std::io::{self, Write};
+
+use crate::debug_interface::expr::Condition;
+use crate::lr35902::LR35902;
+use crate::memory::Bus;
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum TraceFormat {
+    /// The line format of Gameboy Doctor, also used by many reference logs:
+    /// `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`
+    #[default]
+    Doctor,
+    /// Gameboy Doctor lines followed by the LCD, interrupt and timer registers, the ROM
+    /// bank and the clock cycle count
+    Full,
+}
+
+/// The trace line for the instruction `cpu` is about to run
+pub fn trace_line(format: TraceFormat, cpu: &LR35902, bus: &Bus) -> String {
+    let pc = cpu.pc();
+    let pcmem = |offset: u16| bus.read(pc.wrapping_add(offset) as usize);
+    let mut line = format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+        cpu.a(),
+        cpu.f(),
+        cpu.b(),
+        cpu.c(),
+        cpu.d(),
+        cpu.e(),
+        cpu.h(),
+        cpu.l(),
+        cpu.sp(),
+        pc,
+        pcmem(0),
+        pcmem(1),
+        pcmem(2),
+        pcmem(3),
+    );
+    if format == TraceFormat::Full {
+        line += &format!(
+            " LCDC:{:02X} STAT:{:02X} LY:{:02X} IF:{:02X} IE:{:02X} DIV:{:02X} TIMA:{:02X} BANK:{:02X} CYC:{}",
+            bus.lcdc(),
+            bus.stat(),
+            bus.ly(),
+            bus.iflag(),
+            bus.ie(),
+            bus.div(),
+            bus.tima(),
+            bus.rom_bank(),
+            cpu.clock_cycles(),
+        );
+    }
+    line
+}
+
+/// The fields of two trace lines that differ, as `(name, expected, actual)`
+pub fn diff_fields<'a>(expected: &'a str, actual: &'a str) -> Vec<(&'a str, &'a str, &'a str)> {
+    let fields = |line: &'a str| line.split_whitespace().filter_map(|f| f.split_once(':'));
+    fields(expected)
+        .zip(fields(actual))
+        .filter(|(e, a)| e != a)
+        .map(|((name, e), (_, a))| (name, e, a))
+        .collect()
+}
+
+/// Writes a trace line for every instruction run from when `start` holds until `stop` does
+pub struct Tracer {
+    format: TraceFormat,
+    out: Box<dyn Write>,
+    start: Option<Condition>,
+    stop: Option<Condition>,
+    started: bool,
+    done: bool,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(
+        out: Box<dyn Write>,
+        format: TraceFormat,
+        start: Option<Condition>,
+        stop: Option<Condition>,
+    ) -> Self {
+        Self {
+            format,
+            out,
+            started: start.is_none(),
+            start,
+            stop,
+            done: false,
+            error: None,
+        }
+    }
+
+    /// Whether the stop condition was met, or writing the trace failed
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn trace(&mut self, cpu: &LR35902, bus: &Bus) {
+        if self.done {
+            return;
+        }
+        if let Err(e) = self.try_trace(cpu, bus) {
+            self.error = Some(e);
+            self.done = true;
+        }
+    }
+
+    /// Flushes the trace, and returns the first error writing it
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.out.flush()
+    }
+
+    fn try_trace(&mut self, cpu: &LR35902, bus: &Bus) -> io::Result<()> {
+        if !self.started {
+            self.started = holds(self.start.as_ref(), cpu)?;
+            if !self.started {
+                return Ok(());
+            }
+        }
+        if holds(self.stop.as_ref(), cpu)? {
+            self.done = true;
+            return self.out.flush();
+        }
+        writeln!(self.out, "{}", trace_line(self.format, cpu, bus))
+    }
+}
+
+fn holds(condition: Option<&Condition>, cpu: &LR35902) -> io::Result<bool> {
+    match condition {
+        Some(condition) => cpu
+            .debugger()
+            .evaluate(condition, &cpu.registers())
+            .map_err(|e| io::Error::other(format!("{condition}: {e}"))),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::Gameboy;
+
+    /// Lets the test read what the tracer wrote
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut rom = vec![0; 0x8000];
+        // NOP; .loop: INC A; JR .loop
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0x3C, 0x18, 0xFD]);
+        let mut gb = Gameboy::new();
+        gb.load_rom(&rom);
+        gb.cpu_mut().set_pc(0x100);
+        gb.cpu_mut().set_sp(0xFFFE);
+
+        let out = Shared::default();
+        gb.set_tracer(Some(Tracer::new(
+            Box::new(out.clone()),
+            TraceFormat::Doctor,
+            Some(Condition::parse("pc == 0x101").unwrap()),
+            Some(Condition::parse("a == 2").unwrap()),
+        )));
+        while !gb.tracer().unwrap().is_done() {
+            gb.step();
+        }
+        gb.set_tracer(None).unwrap().finish().unwrap();
+
+        let trace = String::from_utf8(out.0.take()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0101 PCMEM:3C,18,FD,00",
+                "A:01 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0102 PCMEM:18,FD,00,00",
+                "A:01 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0101 PCMEM:3C,18,FD,00",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_fields() {
+        let expected = "A:01 F:B0 B:00 C:13 SP:FFFE PC:0100 PCMEM:00,C3,13,02";
+        let actual = "A:01 F:80 B:00 C:13 SP:FFFE PC:0101 PCMEM:00,C3,13,02";
+        assert_eq!(
+            diff_fields(expected, actual),
+            [("F", "B0", "80"), ("PC", "0100", "0101")]
+        );
+        assert!(diff_fields(expected, expected).is_empty());
+    }
+}
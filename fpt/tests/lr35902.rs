@@ -1,3 +1,5 @@
+use fpt::debug_interface::{AccessKind, DebugCmd, DebugEvent, DebugInterface, WatchKind};
+use fpt::lr35902::call_stack::Desync;
 use fpt::lr35902::LR35902;
 use rstest::*;
 
@@ -1974,3 +1976,285 @@ fn test_cp(
         .build();
     assert_eq!(sut, expected);
 }
+
+#[rstest]
+#[case(0x77, WatchKind::Write, Some(AccessKind::Write))] // LD (HL),A
+#[case(0x77, WatchKind::ReadWrite, Some(AccessKind::Write))]
+#[case(0x77, WatchKind::Read, None)]
+#[case(0x7E, WatchKind::Read, Some(AccessKind::Read))] // LD A,(HL)
+#[case(0x7E, WatchKind::Write, None)]
+#[case(0x00, WatchKind::ReadWrite, None)] // NOP
+fn test_watchpoint(
+    #[case] opcode: u8,
+    #[case] kind: WatchKind,
+    #[case] expected: Option<AccessKind>,
+) {
+    let mut sut = LR35902Builder::new()
+        .with_mem8(0xff80, opcode)
+        .with_mem8(0xff91, 0x12)
+        .with_pc(0xff80)
+        .with_a(0x34)
+        .with_hl(0xff91)
+        .build();
+    sut.receive_command(&DebugCmd::Watchpoint {
+        kind,
+        start: 0xff90.into(),
+        end: Some(0xffa0.into()),
+        condition: None,
+    });
+
+    sut.instruction();
+
+    let hit = sut.get_debug_events().iter().find_map(|event| match event {
+        DebugEvent::Watchpoint(hit) => Some(hit.clone()),
+        _ => None,
+    });
+    assert_eq!(hit.as_ref().map(|hit| hit.kind), expected);
+    assert_eq!(sut.paused(), expected.is_some());
+    if let Some(hit) = hit {
+        assert_eq!(hit.address.address, 0xff91);
+        assert_eq!(hit.pc.address, 0xff80);
+        assert_eq!(hit.old, 0x12);
+        assert_eq!(hit.new, if opcode == 0x77 { 0x34 } else { 0x12 });
+        // The instruction that hit the watchpoint still ran to the end
+        assert_eq!(sut.pc(), 0xff81);
+    }
+}
+
+#[rstest]
+#[case(&[DebugCmd::Next], 0xff83, 0)]
+#[case(&[DebugCmd::Step], 0xff90, 1)]
+#[case(&[DebugCmd::Step, DebugCmd::Step, DebugCmd::Finish], 0xff83, 0)]
+#[case(&[DebugCmd::Until(0xff91.into())], 0xff91, 1)]
+fn test_stepping(#[case] commands: &[DebugCmd], #[case] pc: u16, #[case] depth: usize) {
+    let mut sut = LR35902Builder::new()
+        .with_mem8(0xff80, 0xCD) // CALL $FF90
+        .with_mem16(0xff81, 0xff90)
+        .with_mem8(0xff90, 0x00) // NOP
+        .with_mem8(0xff91, 0xC9) // RET
+        .with_pc(0xff80)
+        .with_sp(0xfffe)
+        .build();
+
+    for command in commands {
+        sut.receive_command(command);
+        for _ in 0..100 {
+            if sut.paused() {
+                break;
+            }
+            sut.step();
+        }
+    }
+
+    assert!(sut.paused());
+    assert_eq!(sut.pc(), pc);
+    assert_eq!(sut.call_stack().depth(), depth);
+    assert!(matches!(
+        sut.get_debug_events().back(),
+        Some(DebugEvent::Stop(stop)) if stop.address == pc
+    ));
+}
+
+#[rstest]
+#[case(None, 10, 10)]
+#[case(Some(DebugCmd::Disable(1)), 0, 0)]
+#[case(Some(DebugCmd::Delete(1)), 0, 0)]
+#[case(Some(DebugCmd::Ignore(1, 4)), 6, 10)]
+fn test_debug_points(#[case] command: Option<DebugCmd>, #[case] stops: usize, #[case] hits: u32) {
+    let mut sut = LR35902Builder::new()
+        .with_mem8(0xff80, 0x04) // INC B
+        .with_mem8(0xff81, 0x18) // JR $FF80
+        .with_mem8(0xff82, 0xFD)
+        .with_pc(0xff80)
+        .build();
+    sut.receive_command(&DebugCmd::Breakpoint(0xff80.into(), None));
+    if let Some(command) = command {
+        sut.receive_command(&command);
+    }
+
+    // Each time round the loop is the same breakpoint hit again
+    let mut passes = 0;
+    while sut.b() < 10 {
+        sut.step();
+        if sut.paused() {
+            passes += 1;
+            sut.receive_command(&DebugCmd::Continue);
+        }
+    }
+
+    assert_eq!(passes, stops);
+    let breakpoints = sut
+        .get_debug_events()
+        .iter()
+        .filter(|event| matches!(event, DebugEvent::Breakpoint(1, _)))
+        .count();
+    assert_eq!(breakpoints, stops);
+    let listed = match sut.receive_command(&DebugCmd::ListBreakpoints) {
+        Some(DebugEvent::ListBreakpoints(breakpoints)) => breakpoints,
+        _ => panic!("breakpoints not listed"),
+    };
+    assert_eq!(listed.iter().map(|b| b.point.hits).sum::<u32>(), hits);
+}
+
+#[test]
+fn test_breakpoints_at_one_address() {
+    let mut sut = LR35902Builder::new()
+        .with_mem8(0xff80, 0x77) // LD (HL),A
+        .with_mem8(0xff81, 0x04) // INC B
+        .with_mem8(0xff82, 0x18) // JR $FF80
+        .with_mem8(0xff83, 0xFC)
+        .with_pc(0xff80)
+        .with_hl(0xff91)
+        .build();
+    for command in ["watch 0xff91", "b 0xff81", "b 0xff81 if b == 2"] {
+        sut.receive_command(&DebugCmd::from_string(command).unwrap());
+    }
+    let mut run = |command: DebugCmd| {
+        sut.receive_command(&command);
+        for _ in 0..100 {
+            sut.step();
+            if sut.paused() {
+                break;
+            }
+        }
+        let stops: Vec<usize> = sut
+            .get_debug_events()
+            .drain(..)
+            .filter_map(|event| match event {
+                DebugEvent::Watchpoint(_) => Some(1),
+                DebugEvent::Breakpoint(id, _) => Some(id),
+                _ => None,
+            })
+            .collect();
+        (stops, sut.b())
+    };
+
+    assert_eq!(run(DebugCmd::Continue), (vec![1], 0));
+    run(DebugCmd::Delete(1));
+    // Stopped by the watchpoint right before the breakpoints, which fire when continuing
+    assert_eq!(run(DebugCmd::Continue), (vec![2], 0));
+    assert_eq!(run(DebugCmd::Continue), (vec![2], 1));
+    // Both are checked each time round
+    assert_eq!(run(DebugCmd::Continue), (vec![2, 3], 2));
+
+    let listed = match sut.receive_command(&DebugCmd::ListBreakpoints) {
+        Some(DebugEvent::ListBreakpoints(breakpoints)) => breakpoints,
+        _ => panic!("breakpoints not listed"),
+    };
+    let hits: Vec<u32> = listed.iter().map(|b| b.point.hits).collect();
+    assert_eq!(hits, [3, 3]);
+}
+
+#[rstest]
+#[case(&[0xCD, 0xA0, 0xFF], 2, &[0xffa0, 0xff90], None)] // CALL $FFA0
+#[case(&[0xE9], 1, &[0xffa0], None)] // JP (HL)
+#[case(&[0xC5, 0xE9], 2, &[0xff90], None)] // PUSH BC, JP (HL)
+#[case(&[0xC5, 0xC1, 0xE9], 3, &[0xffa0], None)] // PUSH BC, POP BC, JP (HL)
+#[case(&[0xE1], 1, &[], Some(Desync::Popped { pc: 0xff90, return_address: 0xff83 }))] // POP HL
+#[case(&[0x33, 0x33], 2, &[], Some(Desync::SpMoved { pc: 0xff90, sp: 0xfffd }))] // INC SP
+#[case(&[0x3B, 0x33], 2, &[0xff90], None)] // DEC SP, INC SP
+fn test_backtrace(
+    #[case] code: &[u8],
+    #[case] instructions: usize,
+    #[case] targets: &[u16],
+    #[case] desync: Option<Desync>,
+) {
+    let mut builder = LR35902Builder::new()
+        .with_mem8(0xff80, 0xCD) // CALL $FF90
+        .with_mem16(0xff81, 0xff90)
+        .with_pc(0xff80)
+        .with_sp(0xfffe)
+        .with_hl(0xffa0);
+    for (i, byte) in code.iter().enumerate() {
+        builder = builder.with_mem8(0xff90 + i as u16, *byte);
+    }
+    let mut sut = builder.build();
+
+    for _ in 0..=instructions {
+        sut.instruction();
+    }
+
+    let backtrace = match sut.receive_command(&DebugCmd::Backtrace) {
+        Some(DebugEvent::Backtrace(backtrace)) => backtrace,
+        _ => panic!("no backtrace"),
+    };
+    let frame_targets: Vec<u16> = backtrace.frames.iter().map(|f| f.target.address).collect();
+    assert_eq!(frame_targets, targets);
+    assert_eq!(backtrace.desync, desync);
+    if let Some(frame) = backtrace.frames.last() {
+        assert_eq!(frame.return_address.address, 0xff83);
+    }
+}
+
+#[test]
+fn test_memory_search() {
+    let mut sut = LR35902Builder::new().with_pc(0xff80).build();
+    let mut run = |command: &str| {
+        let command = DebugCmd::from_string(command).unwrap();
+        sut.receive_command(&command).unwrap().to_string()
+    };
+
+    assert_eq!(
+        run("search list"),
+        "Error: no search going on, start one with `search new`\n"
+    );
+    assert!(run("search new").ends_with(" candidates\n"));
+    assert_eq!(run("set mem 0xc010 7"), "Wrote 1 bytes at 0xC010\n");
+    assert_eq!(run("search changed"), "1 candidates\n");
+    assert_eq!(run("search list"), "0xC010: 0x07 (was 0x00)\n");
+    assert_eq!(run("search same"), "1 candidates\n");
+    assert_eq!(
+        DebugCmd::from_string("search new 32").unwrap_err(),
+        "can search 8 or 16 bit values, not 32"
+    );
+}
+
+#[rstest]
+#[case("", "empty command")]
+#[case("frobnicate", "unknown command frobnicate")]
+#[case("b", "missing address")]
+#[case("b 0x10000", "invalid address 0x10000")]
+#[case("b 0x100 when a == 1", "expected `if <condition>`, got when")]
+#[case("d one", "invalid number one")]
+#[case("set reg q 1", "unknown register q")]
+#[case("set mem 0xc000", "missing bytes")]
+#[case("set mem 0xc000 0x100", "invalid number 0x100")]
+#[case("x/4z 0xc000", "unknown format z, expected one of x, d, w, c, i")]
+fn test_parse_errors(#[case] command: &str, #[case] error: &str) {
+    assert_eq!(DebugCmd::from_string(command).unwrap_err(), error);
+}
+
+#[test]
+fn test_modify_state() {
+    let mut sut = LR35902Builder::new().with_pc(0xff80).build();
+    let mut run = |command: &str| {
+        let command = DebugCmd::from_string(command).unwrap();
+        sut.receive_command(&command).unwrap().to_string()
+    };
+
+    assert_eq!(run("set reg a 0x42"), "a = 0x42\n");
+    assert_eq!(run("set reg hl 0xc000"), "hl = 0xC000\n");
+    assert_eq!(run("set reg b 0x100"), "Error: 0x100 does not fit in b\n");
+    assert_eq!(
+        run("regs"),
+        "af: 0x4200  bc: 0x0000  de: 0x0000  hl: 0xC000\n\
+         sp: 0x0000  pc: 0xFF80  flags: ----\n"
+    );
+
+    assert_eq!(
+        run("set mem 0xff80 0x3C 0x18 0xFD"),
+        "Wrote 3 bytes at 0xFF80\n"
+    );
+    assert_eq!(run("x/3x 0xff80"), "0xFF80: 3C 18 FD\n");
+    assert_eq!(run("x/d 0xff80..0xff82"), "0xFF80:  60  24\n");
+    assert_eq!(run("x/w 0xff81"), "0xFF81: FD18\n");
+    assert_eq!(run("x/2i 0xff80").lines().count(), 2);
+    assert_eq!(
+        run("x 0xff82..0xff80"),
+        "Error: 0xFF80 is not after 0xFF82\n"
+    );
+
+    sut.instruction();
+    assert_eq!(sut.a(), 0x43);
+    assert_eq!(sut.pc(), 0xff81);
+}